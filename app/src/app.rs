@@ -3,31 +3,72 @@ use core::borrow::Borrow;
 use embedded_graphics::draw_target::DrawTarget;
 
 use crate::{
-    display::{draw_audio, draw_battery, draw_bg, draw_fps, draw_time},
+    battery::{BatteryEstimator, BatteryHistory},
+    diagnostics::{EventCounts, FrameStats},
+    display::{
+        draw_audio, draw_battery, draw_battery_history, draw_bg, draw_diagnostics,
+        draw_low_battery_popup, draw_settings, draw_time, PLAY_BUTTON_REGION,
+        SCREEN_TIMEOUT_LIST_REGION, SCREEN_TIMEOUT_ROW_HEIGHT, SETTINGS_BUTTON_REGIONS,
+        TIME_FORMAT_TOGGLE_REGION,
+    },
     interface::{
-        AppInput, AppOutput, AppleMediaServiceData, BatteryData, DisplayColor, Gesture,
-        MediaControl, TimeOfDay,
+        AppInput, AppOutput, AppOutputs, AppleMediaServiceData, Diagnostics, DisplayColor, Gesture,
+        MediaControl, PowerProfile, TimeOfDay, Touch,
     },
+    power::{PowerState, LOW_BATTERY_VIBRATION_MS},
+    settings::{Settings, TimeFormat, SCREEN_TIMEOUTS_S},
+    widget::{Button, List, Toggle},
 };
 
 pub struct App {
     active_window: ActiveWindow,
     time: TimeState,
     media: Option<AppleMediaServiceData>,
-    battery: BatteryData,
+    battery: BatteryEstimator,
+    battery_history: BatteryHistory,
+    /// The battery history graph covers most of the screen, so it is only
+    /// redrawn when this is set.
+    battery_history_stale: bool,
+    play_button: Button,
+    /// The low battery popup is shown until this time, or until it is tapped.
+    low_battery_popup_until: Option<u64>,
+    power: PowerState,
+    /// The power profile most recently output to the platform.
+    power_profile: PowerProfile,
+    diagnostics: Option<Diagnostics>,
+    event_counts: EventCounts,
+    frame_stats: FrameStats,
+    settings: Settings,
+    /// In the order of `SETTINGS_BUTTON_REGIONS`.
+    settings_buttons: [Button; 2],
+    /// On for 24 hour time.
+    time_format_toggle: Toggle,
+    /// A row for each of `SCREEN_TIMEOUTS_S`.
+    screen_timeout_list: List,
+    /// Forgetting bonded phones is confirmed by tapping its button again
+    /// before this time.
+    confirm_forget_until: Option<u64>,
 }
 
-#[derive(Clone, Copy)]
-pub(crate) enum ActiveWindow {
+const LOW_BATTERY_POPUP_MS: u64 = 10_000;
+const CONFIRM_FORGET_MS: u64 = 3_000;
+
+/// The windows are cycled through with the button, in this order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ActiveWindow {
     Main,
+    BatteryHistory,
     Debug,
+    Settings,
 }
 
 impl ActiveWindow {
     fn next(&self) -> Self {
         match self {
-            ActiveWindow::Main => ActiveWindow::Debug,
-            ActiveWindow::Debug => ActiveWindow::Main,
+            ActiveWindow::Main => ActiveWindow::BatteryHistory,
+            ActiveWindow::BatteryHistory => ActiveWindow::Debug,
+            ActiveWindow::Debug => ActiveWindow::Settings,
+            ActiveWindow::Settings => ActiveWindow::Main,
         }
     }
 }
@@ -36,8 +77,6 @@ struct TimeState {
     ms_since_boot_when_time_last_specified: u64,
     last_specified_time: TimeOfDay,
     current_ms_since_boot: u64,
-    /// Used only to tell how quickly we are processing updates.
-    previous_ms_since_boot: u64,
 }
 
 impl TimeState {
@@ -86,6 +125,7 @@ impl App {
         E: core::fmt::Debug,
     {
         let active_window = ActiveWindow::Main;
+        let settings = Settings::default();
 
         let s = Self {
             active_window,
@@ -93,15 +133,32 @@ impl App {
                 ms_since_boot_when_time_last_specified: ms_since_boot,
                 last_specified_time: TimeOfDay::default(),
                 current_ms_since_boot: ms_since_boot,
-                previous_ms_since_boot: ms_since_boot,
             },
             media: None,
-            // Placeholder battery data - this will be updated within 1 second by
-            // the battery input polling.
-            battery: BatteryData {
-                charging: false,
-                voltage: 3.5,
-            },
+            // There is no battery data until the first sample arrives, which
+            // should be within 1 second from the battery input polling.
+            battery: BatteryEstimator::new(),
+            battery_history: BatteryHistory::new(),
+            battery_history_stale: true,
+            play_button: Button::new(PLAY_BUTTON_REGION),
+            low_battery_popup_until: None,
+            power: PowerState::new(ms_since_boot, &settings),
+            power_profile: PowerProfile::NORMAL,
+            diagnostics: None,
+            event_counts: EventCounts::default(),
+            frame_stats: FrameStats::new(ms_since_boot),
+            settings,
+            settings_buttons: SETTINGS_BUTTON_REGIONS.map(Button::new),
+            time_format_toggle: Toggle::new(
+                TIME_FORMAT_TOGGLE_REGION,
+                settings.time_format == TimeFormat::TwentyFourHour,
+            ),
+            screen_timeout_list: List::new(
+                SCREEN_TIMEOUT_LIST_REGION,
+                SCREEN_TIMEOUT_ROW_HEIGHT,
+                SCREEN_TIMEOUTS_S.len(),
+            ),
+            confirm_forget_until: None,
         };
 
         // Initialize by drawing the background once - this is a minor
@@ -114,9 +171,17 @@ impl App {
         Ok(s)
     }
 
-    // TODO eventually this will return actions for the platform code to take, for
-    // example turn on backlight
-    //
+    pub fn active_window(&self) -> ActiveWindow {
+        self.active_window
+    }
+
+    /// Should be called once the frame drawn by the last `handle_event` has
+    /// been sent to the display, for the frame times in the diagnostics
+    /// window.
+    pub fn frame_sent(&mut self, ms_since_boot: u64) {
+        self.frame_stats.end_frame(ms_since_boot);
+    }
+
     // TODO why is display special, compared to other "outputs" - it is hard to
     // communicate what we want to do to the display, perhaps we could with function
     // pointers? otherwise should the whole "device" get passed into these functions?
@@ -125,68 +190,162 @@ impl App {
         display: &mut D,
         ms_since_boot: u64,
         event: AppInput,
-    ) -> Result<Option<AppOutput>, D::Error>
+    ) -> Result<AppOutputs, D::Error>
     where
         D: DrawTarget<Color = DisplayColor, Error = E>,
         E: core::fmt::Debug,
     {
-        self.time.previous_ms_since_boot = self.time.current_ms_since_boot;
         self.time.current_ms_since_boot = ms_since_boot;
 
-        let output = match event {
+        self.event_counts.record(&event);
+        self.frame_stats.start_frame(ms_since_boot);
+
+        let mut outputs = AppOutputs::new();
+
+        // An interaction while the screen is off only wakes the screen.
+        let woke_screen = matches!(event, AppInput::Touch(_) | AppInput::ButtonPressed)
+            && self.power.handle_interaction(ms_since_boot);
+
+        match event {
             AppInput::AppleMedia(e) => {
                 self.media = Some(e);
-                None
             }
             AppInput::Battery(e) => {
-                self.battery = e;
-                None
+                let previous = self.battery.level();
+                let level = self.battery.update(e);
+                if previous.is_none_or(|previous| previous.percent != level.percent) {
+                    outputs.push(AppOutput::BatteryLevel(level.percent));
+                }
+                if self.battery_history.record(ms_since_boot, level) {
+                    self.battery_history_stale = true;
+                }
+                if self.power.handle_battery(level) {
+                    self.low_battery_popup_until = Some(ms_since_boot + LOW_BATTERY_POPUP_MS);
+                    outputs.push(AppOutput::Vibrate {
+                        duration_ms: LOW_BATTERY_VIBRATION_MS,
+                    });
+                }
             }
             AppInput::Time(e) => {
                 self.time.last_specified_time = e;
                 self.time.ms_since_boot_when_time_last_specified = ms_since_boot;
-                None
+            }
+            AppInput::Touch(_) | AppInput::ButtonPressed if woke_screen => {}
+            AppInput::Touch(_) if self.low_battery_popup_until.is_some() => {
+                // Any touch dismisses the popup.
+                self.low_battery_popup_until = None;
+                self.clear_screen(display)?;
             }
             AppInput::Touch(touch) => {
-                // We should only do this after pairing, and when the
-                // touch overlaps with a play/pause button. For now
-                // we check if we have media data, as an indication
-                // we might be paired.
-                if self.media.is_some() {
-                    match touch.gesture {
-                        Gesture::SingleClick => {
-                            Some(AppOutput::MediaControl(MediaControl::TogglePlayPause))
-                        }
-                        Gesture::SlideRight => {
-                            Some(AppOutput::MediaControl(MediaControl::NextTrack))
-                        }
-                        Gesture::SlideLeft => {
-                            Some(AppOutput::MediaControl(MediaControl::PreviousTrack))
-                        }
-                        Gesture::SlideUp => Some(AppOutput::MediaControl(MediaControl::VolumeUp)),
-                        Gesture::SlideDown => {
-                            Some(AppOutput::MediaControl(MediaControl::VolumeDown))
-                        }
-                        _ => None,
-                    }
-                } else {
-                    None
+                if let Some(output) = self.handle_touch(touch, ms_since_boot) {
+                    outputs.push(output);
                 }
             }
             AppInput::ButtonPressed => {
                 let new_window = self.active_window.next();
                 self.active_window = new_window;
-                None
+                self.clear_screen(display)?;
             }
             AppInput::Tick => {
                 // this just triggers a re-draw
-                None
             }
+            AppInput::Diagnostics(e) => {
+                self.diagnostics = Some(e);
+            }
+            AppInput::Settings(settings) => self.apply_settings(settings),
         };
 
+        if self
+            .low_battery_popup_until
+            .is_some_and(|until| ms_since_boot >= until)
+        {
+            self.low_battery_popup_until = None;
+            self.clear_screen(display)?;
+        }
+
+        self.power.handle_time(ms_since_boot);
+        let power_profile = self.power.profile();
+        if power_profile != self.power_profile {
+            self.power_profile = power_profile;
+            outputs.push(AppOutput::PowerProfile(power_profile));
+        }
+
         self.draw(display)?;
+        self.battery_history_stale = false;
+
+        Ok(outputs)
+    }
+
+    fn handle_touch(&mut self, touch: Touch, ms_since_boot: u64) -> Option<AppOutput> {
+        // We should only do this after pairing. For now we check if we
+        // have media data, as an indication we might be paired.
+        match (self.active_window, self.media.is_some()) {
+            (ActiveWindow::Main, true) => match touch.gesture {
+                Gesture::SlideRight => Some(AppOutput::MediaControl(MediaControl::NextTrack)),
+                Gesture::SlideLeft => Some(AppOutput::MediaControl(MediaControl::PreviousTrack)),
+                Gesture::SlideUp => Some(AppOutput::MediaControl(MediaControl::VolumeUp)),
+                Gesture::SlideDown => Some(AppOutput::MediaControl(MediaControl::VolumeDown)),
+                _ => self
+                    .play_button
+                    .handle_touch(&touch, ms_since_boot)
+                    .then_some(AppOutput::MediaControl(MediaControl::TogglePlayPause)),
+            },
+            (ActiveWindow::Settings, _) => self.handle_settings_touch(touch, ms_since_boot),
+            _ => None,
+        }
+    }
+
+    fn handle_settings_touch(&mut self, touch: Touch, ms_since_boot: u64) -> Option<AppOutput> {
+        let mut settings = self.settings;
+        let [brightness, forget] = &mut self.settings_buttons;
+        if forget.handle_touch(&touch, ms_since_boot) {
+            if self.confirming_forget(ms_since_boot) {
+                self.confirm_forget_until = None;
+                return Some(AppOutput::ForgetBonds);
+            }
+            self.confirm_forget_until = Some(ms_since_boot + CONFIRM_FORGET_MS);
+            return None;
+        } else if brightness.handle_touch(&touch, ms_since_boot) {
+            settings.cycle_brightness();
+        } else if self
+            .time_format_toggle
+            .handle_touch(&touch, ms_since_boot)
+            .is_some()
+        {
+            settings.toggle_time_format();
+        } else if let Some(row) = self.screen_timeout_list.handle_touch(&touch, ms_since_boot) {
+            settings.screen_timeout_s = SCREEN_TIMEOUTS_S[row];
+        } else {
+            return None;
+        }
 
-        Ok(output)
+        self.apply_settings(settings);
+
+        Some(AppOutput::SaveSettings(settings))
+    }
+
+    fn confirming_forget(&self, ms_since_boot: u64) -> bool {
+        self.confirm_forget_until
+            .is_some_and(|until| ms_since_boot < until)
+    }
+
+    fn apply_settings(&mut self, settings: Settings) {
+        self.settings = settings;
+        self.time_format_toggle
+            .set(settings.time_format == TimeFormat::TwentyFourHour);
+        self.power.apply_settings(&settings);
+    }
+
+    /// Windows only draw over the parts of the screen they use, so anything
+    /// left from a previous window or popup needs clearing.
+    fn clear_screen<D>(&mut self, display: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = DisplayColor>,
+    {
+        draw_bg(display)?;
+        self.battery_history_stale = true;
+
+        Ok(())
     }
 
     fn draw<D, E>(&self, display: &mut D) -> Result<(), E>
@@ -196,24 +355,48 @@ impl App {
     {
         match self.active_window {
             ActiveWindow::Main => {
-                draw_battery(display, &self.battery)?;
-                draw_time(display, self.time.current_time())?;
+                draw_battery(display, self.battery.level())?;
+                draw_time(display, self.time.current_time(), self.settings.time_format)?;
                 if let Some(media_data) = self.media.borrow() {
-                    draw_audio(display, &media_data.artist, &media_data.title)?;
+                    draw_audio(
+                        display,
+                        &media_data.artist,
+                        &media_data.title,
+                        self.play_button.is_pressed(self.time.current_ms_since_boot),
+                    )?;
+                }
+            }
+            ActiveWindow::BatteryHistory => {
+                if self.battery_history_stale {
+                    draw_battery_history(display, &self.battery_history)?;
                 }
             }
             ActiveWindow::Debug => {
-                // nothing for now
-                draw_bg(display)?;
+                draw_diagnostics(
+                    display,
+                    self.time.current_ms_since_boot,
+                    self.diagnostics.as_ref(),
+                    self.battery.last_sample(),
+                    self.frame_stats.last_window(),
+                    &self.event_counts,
+                )?;
+            }
+            ActiveWindow::Settings => {
+                draw_settings(
+                    display,
+                    self.time.current_ms_since_boot,
+                    &self.settings,
+                    &self.settings_buttons,
+                    &self.time_format_toggle,
+                    &self.screen_timeout_list,
+                    self.confirming_forget(self.time.current_ms_since_boot),
+                )?;
             }
         }
 
-        // For now FPS is drawn at the bottom of every window.
-        // TODO handle roll-over
-        // max(1) to avoid divide by zero
-        let fps =
-            1000 / (self.time.current_ms_since_boot - self.time.previous_ms_since_boot).max(1);
-        draw_fps(display, fps as u32)?;
+        if self.low_battery_popup_until.is_some() {
+            draw_low_battery_popup(display, self.battery.level().map_or(0, |l| l.percent))?;
+        }
 
         Ok(())
     }
@@ -223,14 +406,13 @@ impl App {
 mod tests {
     extern crate std;
 
-    use core::str::FromStr;
-
-    use arrayvec::ArrayString;
-    use embedded_graphics::geometry::Size;
+    use proptest::prelude::*;
 
     use crate::{
-        interface::{BatteryData, LCD_H, LCD_W},
-        test_infra::{assert_snapshot, function_name, SimDisplay},
+        gesture::{ControllerGestures, GestureConfig},
+        interface::{Backlight, BleState, DisplayWrites, FirmwareVersion, TouchType},
+        settings::{Brightness, TimeFormat},
+        test_infra::{function_name, Scenario},
     };
 
     use super::*;
@@ -242,48 +424,345 @@ mod tests {
             "mesozoic_app::app::tests::init_and_paired", test_name,
             "this file is referenced from README, so the name cannot change"
         );
-        let mut display = SimDisplay::new(Size::new(LCD_W as u32, LCD_H as u32));
-
-        let ms_since_boot = 0;
-        let mut app = App::init(&mut display, ms_since_boot).unwrap();
-        app.handle_event(
-            &mut display,
-            ms_since_boot,
-            AppInput::Battery(BatteryData {
-                charging: true,
-                voltage: 4.1,
-            }),
-        )
-        .unwrap();
-        app.handle_event(
-            &mut display,
-            ms_since_boot,
-            AppInput::Time(TimeOfDay {
-                hours: 10,
-                minutes: 15,
-                seconds: 01,
-                ..Default::default()
-            }),
-        )
-        .unwrap();
-        app.handle_event(
-            &mut display,
-            ms_since_boot,
-            AppInput::AppleMedia(AppleMediaServiceData {
-                artist: ArrayString::from_str("Rustacean Station").unwrap(),
-                album: ArrayString::from_str("April 28, 2023").unwrap(),
-                title: ArrayString::from_str("Rust Embedded WG").unwrap(),
-            }),
-        )
-        .unwrap();
-        app.handle_event(
-            &mut display,
-            // Using this event to set FPS
-            ms_since_boot + 17,
-            AppInput::Tick,
-        )
-        .unwrap();
-
-        assert_snapshot(test_name, display);
+
+        Scenario::new()
+            .battery(true, 4.1)
+            .time(10, 15, 1)
+            .media("Rustacean Station", "April 28, 2023", "Rust Embedded WG")
+            .snapshot(test_name);
+    }
+
+    /// Redrawing each window on a tick should stay within these, estimated
+    /// for the 8MHz SPI bus. Switching windows clears the screen, which
+    /// alone takes about 115ms.
+    #[test]
+    fn render_budgets() {
+        const MAIN_TICK_US: u32 = 25_000;
+        const BATTERY_HISTORY_TICK_US: u32 = 2_000;
+        const DEBUG_TICK_US: u32 = 100_000;
+        const SETTINGS_TICK_US: u32 = 120_000;
+        const SWITCH_WINDOW_US: u32 = 235_000;
+
+        Scenario::new()
+            .battery(true, 4.1)
+            .time(10, 15, 1)
+            .media("Rustacean Station", "April 28, 2023", "Rust Embedded WG")
+            .advance(17)
+            .expect_render_within(MAIN_TICK_US)
+            .button()
+            .expect_render_within(SWITCH_WINDOW_US)
+            .advance(17)
+            .expect_render_within(BATTERY_HISTORY_TICK_US)
+            .button()
+            .expect_render_within(SWITCH_WINDOW_US)
+            .advance(17)
+            .expect_render_within(DEBUG_TICK_US)
+            .button()
+            .expect_render_within(SWITCH_WINDOW_US)
+            .advance(17)
+            .expect_render_within(SETTINGS_TICK_US)
+            .button()
+            .expect_render_within(SWITCH_WINDOW_US);
+    }
+
+    #[test]
+    fn play_pause_only_on_play_button() {
+        Scenario::new()
+            .media("artist", "album", "title")
+            .tap(10, 200)
+            .expect_no_outputs()
+            .tap(120, 120)
+            .expect_output(AppOutput::MediaControl(MediaControl::TogglePlayPause));
+    }
+
+    #[test]
+    fn two_quick_taps_on_play_button() {
+        // Two quick taps, as the touch controller reports them on the watch.
+        let reports = [
+            (1000, TouchType::Down, Gesture::None),
+            (1040, TouchType::Up, Gesture::SingleClick),
+            (1150, TouchType::Down, Gesture::None),
+            (1190, TouchType::Up, Gesture::SingleClick),
+        ];
+        let mut gestures = ControllerGestures::new(GestureConfig::default());
+        let mut scenario = Scenario::new().media("artist", "album", "title");
+        let mut toggles = std::vec::Vec::new();
+
+        for (ms_since_boot, event_type, gesture) in reports {
+            let touch = Touch {
+                gesture,
+                event_type,
+                x: 120,
+                y: 120,
+            };
+            for touch in gestures.handle_report(&touch, ms_since_boot) {
+                scenario = scenario.at(ms_since_boot).input(AppInput::Touch(touch));
+                if scenario
+                    .outputs()
+                    .contains(&AppOutput::MediaControl(MediaControl::TogglePlayPause))
+                {
+                    toggles.push(ms_since_boot);
+                }
+            }
+        }
+        while let Some(deadline) = gestures.next_deadline() {
+            assert!(gestures.poll(deadline).is_none());
+        }
+
+        // Each tap toggles as soon as it ends.
+        assert_eq!(std::vec![1040, 1190], toggles);
+    }
+
+    #[test]
+    fn low_battery_warning_then_power_saving() {
+        let test_name = function_name!();
+
+        let mut scenario = Scenario::new()
+            .at(100)
+            // About 18%, which is low but not critical.
+            .battery(false, 3.72)
+            .expect_output(AppOutput::Vibrate {
+                duration_ms: LOW_BATTERY_VIBRATION_MS,
+            })
+            .snapshot(test_name)
+            // The warning is only shown once per discharge.
+            .at(200)
+            .battery(false, 3.72)
+            .expect_no_outputs();
+
+        // Dropping below the critical level enters power saving.
+        let mut profiles = std::vec::Vec::new();
+        for i in 0..16 {
+            scenario = scenario.at(300 + i * 100).battery(false, 3.60);
+            profiles.extend(scenario.outputs().iter().filter_map(|output| match output {
+                AppOutput::PowerProfile(profile) => Some(*profile),
+                _ => None,
+            }));
+        }
+        assert_eq!(1, profiles.len());
+        assert!(profiles[0].tick_interval_ms > PowerProfile::NORMAL.tick_interval_ms);
+        assert!(
+            profiles[0].ble_advertising_interval_ms
+                > PowerProfile::NORMAL.ble_advertising_interval_ms
+        );
+
+        // Charging exits power saving.
+        scenario
+            .at(2000)
+            .battery(true, 3.75)
+            .expect_output(AppOutput::PowerProfile(PowerProfile::NORMAL));
+    }
+
+    #[test]
+    fn battery_level_output_when_it_changes() {
+        let scenario = Scenario::new().at(100).battery(false, 4.1);
+        let &[AppOutput::BatteryLevel(full)] = scenario.outputs() else {
+            panic!("expected a battery level, found {:?}", scenario.outputs());
+        };
+
+        let scenario = scenario
+            .at(1100)
+            .battery(false, 4.1)
+            .expect_no_outputs()
+            .at(2100)
+            .battery(false, 3.9);
+        assert!(matches!(
+            scenario.outputs(),
+            [AppOutput::BatteryLevel(percent)] if *percent < full
+        ));
+    }
+
+    #[test]
+    fn screen_timeout() {
+        let scenario = Scenario::new()
+            .media("artist", "album", "title")
+            .advance(14_999)
+            .expect_no_outputs()
+            .advance(1);
+        assert!(matches!(
+            scenario.outputs(),
+            [AppOutput::PowerProfile(PowerProfile {
+                backlight: Backlight::Off,
+                ..
+            })]
+        ));
+
+        // Tapping the play button while the screen is off only wakes it.
+        let scenario = scenario.at(20_000).tap(120, 120);
+        assert!(matches!(
+            scenario.outputs(),
+            [AppOutput::PowerProfile(PowerProfile::NORMAL)]
+        ));
+    }
+
+    #[test]
+    fn settings_window() {
+        let test_name = function_name!();
+        let high_brightness = PowerProfile {
+            backlight: Backlight::High,
+            ..PowerProfile::NORMAL
+        };
+
+        let scenario = Scenario::new().time(13, 5, 0).button().button().button();
+        assert_eq!(ActiveWindow::Settings, scenario.app().active_window());
+
+        scenario
+            .at(100)
+            .tap(120, 26)
+            .expect_output(AppOutput::SaveSettings(Settings {
+                brightness: Brightness::High,
+                ..Settings::default()
+            }))
+            .expect_output(AppOutput::PowerProfile(high_brightness))
+            .at(200)
+            .tap(120, 68)
+            // The list of screen timeouts scrolls to show 30s, in the bottom
+            // row.
+            .at(300)
+            .touch(Gesture::SlideUp, 120, 140)
+            .expect_no_outputs()
+            .at(400)
+            .tap(120, 172)
+            .expect_output(AppOutput::SaveSettings(Settings {
+                brightness: Brightness::High,
+                time_format: TimeFormat::TwelveHour,
+                screen_timeout_s: 30,
+            }))
+            // Taps outside the widgets change nothing.
+            .at(500)
+            .tap(120, 236)
+            .expect_no_outputs()
+            .advance(1_000)
+            .snapshot(test_name);
+    }
+
+    #[test]
+    fn forgetting_bonds_needs_a_second_tap() {
+        let scenario = Scenario::new().button().button().button();
+
+        scenario
+            .at(100)
+            .tap(120, 214)
+            .expect_no_outputs()
+            .at(2_000)
+            .tap(120, 214)
+            .expect_output(AppOutput::ForgetBonds)
+            .at(3_000)
+            .tap(120, 214)
+            .expect_no_outputs()
+            // The second tap has to come soon after the first.
+            .at(6_000)
+            .tap(120, 214)
+            .expect_no_outputs();
+    }
+
+    #[test]
+    fn saved_settings_apply_without_saving() {
+        let settings = Settings {
+            brightness: Brightness::Low,
+            time_format: TimeFormat::TwelveHour,
+            screen_timeout_s: 5,
+        };
+
+        let scenario = Scenario::new()
+            .input(AppInput::Settings(settings))
+            .expect_output(AppOutput::PowerProfile(PowerProfile {
+                backlight: Backlight::Low,
+                ..PowerProfile::NORMAL
+            }));
+        assert!(!scenario
+            .outputs()
+            .iter()
+            .any(|output| matches!(output, AppOutput::SaveSettings(_))));
+
+        let scenario = scenario.advance(5_000);
+        assert!(matches!(
+            scenario.outputs(),
+            [AppOutput::PowerProfile(PowerProfile {
+                backlight: Backlight::Off,
+                ..
+            })]
+        ));
+    }
+
+    fn time_state(last_specified_time: TimeOfDay, specified_at: u64, now: u64) -> TimeState {
+        TimeState {
+            ms_since_boot_when_time_last_specified: specified_at,
+            last_specified_time,
+            current_ms_since_boot: now,
+        }
+    }
+
+    fn seconds_of_day(time: &TimeOfDay) -> u64 {
+        time.hours as u64 * 60 * 60 + time.minutes as u64 * 60 + time.seconds as u64
+    }
+
+    prop_compose! {
+        fn time_of_day()(hours in 0..24u8, minutes in 0..60u8, seconds in 0..60u8) -> TimeOfDay {
+            TimeOfDay { hours, minutes, seconds }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn current_time_adds_elapsed_time(
+            last_specified_time in time_of_day(),
+            specified_at in 0..u64::MAX / 2,
+            // Mostly within a few days, so that carries are likely, but also
+            // far enough to wrap the delta of every unit.
+            elapsed_ms in prop_oneof![0..4 * 24 * 60 * 60 * 1000u64, 0..u64::MAX / 2],
+        ) {
+            let expected = (seconds_of_day(&last_specified_time) + elapsed_ms / 1000) % (24 * 60 * 60);
+
+            let current_time =
+                time_state(last_specified_time, specified_at, specified_at + elapsed_ms).current_time();
+
+            prop_assert!(current_time.hours < 24);
+            prop_assert!(current_time.minutes < 60);
+            prop_assert!(current_time.seconds < 60);
+            prop_assert_eq!(expected, seconds_of_day(&current_time));
+        }
+    }
+
+    #[test]
+    fn current_time_carries_at_the_limits() {
+        // Each unit at its maximum, plus the largest delta of each unit, is
+        // as close as the u8 additions get to overflowing.
+        let latest = TimeOfDay {
+            hours: 23,
+            minutes: 59,
+            seconds: 59,
+        };
+        let elapsed_ms = ((23 * 60 + 59) * 60 + 59) * 1000 + 999;
+        let time = time_state(latest.clone(), 0, elapsed_ms).current_time();
+        assert_eq!((23, 59, 58), (time.hours, time.minutes, time.seconds));
+
+        // Midnight rolls over to the next day.
+        let time = time_state(latest, 5_000, 6_000).current_time();
+        assert_eq!((0, 0, 0), (time.hours, time.minutes, time.seconds));
+    }
+
+    #[test]
+    fn diagnostics_window() {
+        let test_name = function_name!();
+        let mut scenario = Scenario::new()
+            .input(AppInput::Diagnostics(Diagnostics {
+                firmware_version: FirmwareVersion::from("0.1.0").unwrap(),
+                ble: BleState::Connected {
+                    peer_address: [0xC0, 0xFF, 0xEE, 0x12, 0x34, 0x56],
+                },
+                display_writes: DisplayWrites::Dma,
+            }))
+            .battery(false, 3.9)
+            .button()
+            .button();
+        assert_eq!(ActiveWindow::Debug, scenario.app().active_window());
+
+        // Ticks over a full frame stats window, with one slow frame.
+        for ms in (16..=960).step_by(16).chain([1060]) {
+            scenario = scenario.at(ms).tick();
+        }
+
+        scenario.snapshot(test_name);
     }
 }
@@ -0,0 +1,442 @@
+//! Battery percentage estimation from raw voltage samples.
+//!
+//! The SAADC readings are noisy, and the voltage of a LiPo cell reads higher
+//! while charging and sags under load, so converting each sample straight to
+//! a percentage makes the number jump around. `BatteryEstimator` smooths the
+//! samples with a moving average before looking up the discharge curve, and
+//! only lets the reported percentage move in the direction the charging state
+//! says it should.
+
+use crate::interface::BatteryData;
+
+/// Approximate LiPo discharge curve as (voltage, percent), ordered from full
+/// to empty. Values between points are linearly interpolated.
+const DISCHARGE_CURVE: [(f32, u8); 21] = [
+    (4.20, 100),
+    (4.15, 95),
+    (4.11, 90),
+    (4.08, 85),
+    (4.02, 80),
+    (3.98, 75),
+    (3.95, 70),
+    (3.91, 65),
+    (3.87, 60),
+    (3.85, 55),
+    (3.84, 50),
+    (3.82, 45),
+    (3.80, 40),
+    (3.79, 35),
+    (3.77, 30),
+    (3.75, 25),
+    (3.73, 20),
+    (3.71, 15),
+    (3.69, 10),
+    (3.61, 5),
+    (3.27, 0),
+];
+
+/// The charger raises the measured cell voltage by roughly this much, so it is
+/// subtracted before looking up the discharge curve while charging.
+pub const CHARGING_VOLTAGE_OFFSET: f32 = 0.12;
+
+/// Number of samples in the moving average. Battery data arrives about once
+/// per second.
+const FILTER_WINDOW: usize = 16;
+
+/// Converts a resting cell voltage to a percentage using the discharge curve.
+pub fn voltage_to_percent(voltage: f32) -> u8 {
+    let (full_voltage, _) = DISCHARGE_CURVE[0];
+    let (empty_voltage, _) = DISCHARGE_CURVE[DISCHARGE_CURVE.len() - 1];
+
+    if voltage >= full_voltage {
+        return 100;
+    }
+    if voltage <= empty_voltage {
+        return 0;
+    }
+
+    for window in DISCHARGE_CURVE.windows(2) {
+        let (high_voltage, high_percent) = window[0];
+        let (low_voltage, low_percent) = window[1];
+
+        if voltage >= low_voltage {
+            let fraction = (voltage - low_voltage) / (high_voltage - low_voltage);
+            let percent = low_percent as f32 + fraction * (high_percent - low_percent) as f32;
+
+            // Round to nearest, without relying on float methods unavailable in core.
+            return (percent + 0.5) as u8;
+        }
+    }
+
+    0
+}
+
+/// The inverse of `voltage_to_percent`, for simulating a battery. `percent`
+/// is fractional so a slow discharge changes the voltage smoothly.
+pub fn percent_to_voltage(percent: f32) -> f32 {
+    for window in DISCHARGE_CURVE.windows(2) {
+        let (high_voltage, high_percent) = window[0];
+        let (low_voltage, low_percent) = window[1];
+
+        if percent >= low_percent as f32 {
+            let fraction = (percent - low_percent as f32) / (high_percent - low_percent) as f32;
+
+            return low_voltage + fraction.min(1.) * (high_voltage - low_voltage);
+        }
+    }
+
+    DISCHARGE_CURVE[DISCHARGE_CURVE.len() - 1].0
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BatteryLevel {
+    pub percent: u8,
+    pub charging: bool,
+}
+
+pub struct BatteryEstimator {
+    samples: [f32; FILTER_WINDOW],
+    /// Number of valid entries in `samples`, up to `FILTER_WINDOW`.
+    num_samples: usize,
+    /// Index in `samples` the next sample is written to.
+    next_sample: usize,
+    charging: bool,
+    level: Option<BatteryLevel>,
+    last_sample: Option<BatteryData>,
+}
+
+impl Default for BatteryEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BatteryEstimator {
+    pub const fn new() -> Self {
+        Self {
+            samples: [0.; FILTER_WINDOW],
+            num_samples: 0,
+            next_sample: 0,
+            charging: false,
+            level: None,
+            last_sample: None,
+        }
+    }
+
+    /// The current estimate, or `None` if no samples have been received.
+    pub fn level(&self) -> Option<BatteryLevel> {
+        self.level
+    }
+
+    /// The most recent raw sample.
+    pub fn last_sample(&self) -> Option<&BatteryData> {
+        self.last_sample.as_ref()
+    }
+
+    pub fn update(&mut self, data: BatteryData) -> BatteryLevel {
+        if data.charging != self.charging {
+            // The measured voltage steps when the charger is connected or
+            // disconnected, so samples from before the change would drag the
+            // average in the wrong direction.
+            self.num_samples = 0;
+            self.next_sample = 0;
+            self.charging = data.charging;
+        }
+
+        self.samples[self.next_sample] = data.voltage;
+        self.next_sample = (self.next_sample + 1) % FILTER_WINDOW;
+        self.num_samples = (self.num_samples + 1).min(FILTER_WINDOW);
+        self.last_sample = Some(data);
+
+        let average_voltage =
+            self.samples[..self.num_samples].iter().sum::<f32>() / self.num_samples as f32;
+        let estimate = voltage_to_percent(match self.charging {
+            true => average_voltage - CHARGING_VOLTAGE_OFFSET,
+            false => average_voltage,
+        });
+
+        // Hysteresis: the reported percentage only rises while charging and
+        // only falls while discharging. Noise in the other direction is
+        // ignored.
+        let percent = match self.level {
+            Some(previous) if self.charging => estimate.max(previous.percent),
+            Some(previous) if !previous.charging => estimate.min(previous.percent),
+            // The first estimate after booting, or after unplugging the
+            // charger, is taken as-is.
+            _ => estimate,
+        };
+
+        let level = BatteryLevel {
+            percent,
+            charging: self.charging,
+        };
+        self.level = Some(level);
+
+        level
+    }
+}
+
+/// Time between battery history samples.
+pub const BATTERY_HISTORY_INTERVAL_MS: u64 = 10 * 60 * 1000;
+/// Number of battery history samples kept, covering the last 48 hours.
+pub const BATTERY_HISTORY_LEN: usize = 48 * 60 * 60 * 1000 / BATTERY_HISTORY_INTERVAL_MS as usize;
+
+/// Ring buffer of battery levels sampled at a fixed interval, so the effect of
+/// firmware changes on battery life can be seen on the watch itself.
+///
+/// Each sample is packed into a single byte to keep the RAM cost low.
+pub struct BatteryHistory {
+    samples: [u8; BATTERY_HISTORY_LEN],
+    /// Number of valid entries in `samples`, up to `BATTERY_HISTORY_LEN`.
+    len: usize,
+    /// Index in `samples` the next sample is written to.
+    next: usize,
+    last_sample_ms_since_boot: Option<u64>,
+}
+
+impl Default for BatteryHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BatteryHistory {
+    const CHARGING_BIT: u8 = 0x80;
+
+    pub const fn new() -> Self {
+        Self {
+            samples: [0; BATTERY_HISTORY_LEN],
+            len: 0,
+            next: 0,
+            last_sample_ms_since_boot: None,
+        }
+    }
+
+    /// Records the level if at least `BATTERY_HISTORY_INTERVAL_MS` has passed
+    /// since the previous sample (or there is no previous sample).
+    ///
+    /// Returns true if a sample was recorded.
+    pub fn record(&mut self, ms_since_boot: u64, level: BatteryLevel) -> bool {
+        if let Some(last) = self.last_sample_ms_since_boot {
+            if ms_since_boot.saturating_sub(last) < BATTERY_HISTORY_INTERVAL_MS {
+                return false;
+            }
+        }
+
+        self.last_sample_ms_since_boot = Some(ms_since_boot);
+        self.push(level);
+
+        true
+    }
+
+    /// Records the level regardless of when the previous sample was taken.
+    pub fn push(&mut self, level: BatteryLevel) {
+        self.samples[self.next] = level.percent.min(100)
+            | match level.charging {
+                true => Self::CHARGING_BIT,
+                false => 0,
+            };
+        self.next = (self.next + 1) % BATTERY_HISTORY_LEN;
+        self.len = (self.len + 1).min(BATTERY_HISTORY_LEN);
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Iterates over the samples, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = BatteryLevel> + '_ {
+        let oldest = (self.next + BATTERY_HISTORY_LEN - self.len) % BATTERY_HISTORY_LEN;
+
+        (0..self.len).map(move |i| {
+            let sample = self.samples[(oldest + i) % BATTERY_HISTORY_LEN];
+
+            BatteryLevel {
+                percent: sample & !Self::CHARGING_BIT,
+                charging: sample & Self::CHARGING_BIT != 0,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// Raw samples as they arrive from the SAADC, which has a resolution of
+    /// about 1.6mV and jitters by a few tens of mV between readings.
+    ///
+    /// This trace discharges slowly from about 3.95V, with a dip caused by
+    /// load (for example the vibration motor) part way through.
+    const DISCHARGING_TRACE: [f32; 40] = [
+        3.953, 3.961, 3.937, 3.958, 3.945, 3.929, 3.951, 3.942, 3.960, 3.934, //
+        3.948, 3.939, 3.921, 3.947, 3.932, 3.944, 3.918, 3.940, 3.929, 3.936, //
+        3.851, 3.842, 3.860, 3.913, 3.930, 3.921, 3.935, 3.917, 3.926, 3.909, //
+        3.929, 3.914, 3.922, 3.906, 3.919, 3.911, 3.925, 3.903, 3.916, 3.908, //
+    ];
+
+    /// Plugging in the charger partway through a discharge.
+    const PLUGGED_IN_TRACE: [(bool, f32); 20] = [
+        (false, 3.802),
+        (false, 3.811),
+        (false, 3.795),
+        (false, 3.806),
+        (false, 3.799),
+        (true, 3.931),
+        (true, 3.942),
+        (true, 3.925),
+        (true, 3.938),
+        (true, 3.929),
+        (true, 3.944),
+        (true, 3.921),
+        (true, 3.936),
+        (true, 3.948),
+        (true, 3.927),
+        (true, 3.940),
+        (true, 3.951),
+        (true, 3.934),
+        (true, 3.946),
+        (true, 3.957),
+    ];
+
+    #[test]
+    fn discharge_curve_lookup() {
+        assert_eq!(100, voltage_to_percent(4.3));
+        assert_eq!(100, voltage_to_percent(4.2));
+        assert_eq!(50, voltage_to_percent(3.84));
+        assert_eq!(0, voltage_to_percent(3.27));
+        assert_eq!(0, voltage_to_percent(2.5));
+        // Halfway between two points on the curve.
+        assert_eq!(78, voltage_to_percent(4.0));
+
+        // Monotonic over the whole range.
+        let mut previous = 0;
+        for millivolts in 3000..4300 {
+            let percent = voltage_to_percent(millivolts as f32 / 1000.);
+            assert!(percent >= previous, "{millivolts}mV");
+            previous = percent;
+        }
+    }
+
+    #[test]
+    fn percent_to_voltage_inverts_lookup() {
+        assert_eq!(4.2, percent_to_voltage(120.));
+        assert_eq!(3.27, percent_to_voltage(-5.));
+        for percent in 0..=100 {
+            assert_eq!(
+                percent,
+                voltage_to_percent(percent_to_voltage(percent as f32))
+            );
+        }
+    }
+
+    #[test]
+    fn discharging_percentage_is_stable() {
+        let mut estimator = BatteryEstimator::new();
+        let mut previous = None;
+
+        for voltage in DISCHARGING_TRACE {
+            let level = estimator.update(BatteryData {
+                charging: false,
+                voltage,
+            });
+
+            assert!(!level.charging);
+            if let Some(previous) = previous {
+                assert!(level.percent <= previous, "percent rose while discharging");
+                assert!(previous - level.percent <= 2, "percent jumped");
+            }
+            previous = Some(level.percent);
+        }
+
+        // The raw trace swings over a range of roughly 20%.
+        let raw_min = DISCHARGING_TRACE
+            .iter()
+            .map(|v| voltage_to_percent(*v))
+            .min()
+            .unwrap();
+        assert!(raw_min < 60);
+        assert!(previous.unwrap() >= 64);
+    }
+
+    #[test]
+    fn plugging_in_does_not_jump() {
+        let mut estimator = BatteryEstimator::new();
+        let mut levels = PLUGGED_IN_TRACE.iter().map(|(charging, voltage)| {
+            estimator.update(BatteryData {
+                charging: *charging,
+                voltage: *voltage,
+            })
+        });
+
+        let before_charging = levels.nth(4).unwrap();
+        assert!(!before_charging.charging);
+
+        let mut previous = before_charging.percent;
+        for level in levels {
+            assert!(level.charging);
+            assert!(level.percent >= previous, "percent fell while charging");
+            previous = level.percent;
+        }
+
+        // The raw charging voltage would read as about 70%, the offset keeps
+        // the estimate close to where it was before plugging in.
+        assert!(previous - before_charging.percent <= 10);
+    }
+
+    #[test]
+    fn history_samples_at_interval() {
+        let mut history = BatteryHistory::new();
+        let level = |percent| BatteryLevel {
+            percent,
+            charging: false,
+        };
+
+        assert!(history.record(5, level(90)));
+        assert!(!history.record(5 + BATTERY_HISTORY_INTERVAL_MS - 1, level(89)));
+        assert!(history.record(5 + BATTERY_HISTORY_INTERVAL_MS, level(88)));
+
+        assert_eq!(
+            Vec::from([level(90), level(88)]),
+            history.iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn history_keeps_last_48_hours() {
+        assert_eq!(288, BATTERY_HISTORY_LEN);
+
+        let mut history = BatteryHistory::new();
+        for i in 0..BATTERY_HISTORY_LEN + 10 {
+            history.push(BatteryLevel {
+                percent: (i % 101) as u8,
+                charging: i % 2 == 0,
+            });
+        }
+
+        assert_eq!(BATTERY_HISTORY_LEN, history.len());
+        let samples = history.iter().collect::<Vec<_>>();
+        assert_eq!(
+            BatteryLevel {
+                percent: 10,
+                charging: true
+            },
+            samples[0]
+        );
+        assert_eq!(
+            BatteryLevel {
+                percent: (297 % 101) as u8,
+                charging: false
+            },
+            samples[BATTERY_HISTORY_LEN - 1]
+        );
+    }
+}
@@ -0,0 +1,170 @@
+//! Statistics collected by the app for the diagnostics (debug) window.
+
+use crate::interface::AppInput;
+
+/// Number of inputs of each kind handled since boot.
+#[derive(Default)]
+pub(crate) struct EventCounts {
+    pub(crate) apple_media: u32,
+    pub(crate) battery: u32,
+    pub(crate) time: u32,
+    pub(crate) touch: u32,
+    pub(crate) button_pressed: u32,
+    pub(crate) tick: u32,
+    pub(crate) diagnostics: u32,
+    pub(crate) settings: u32,
+}
+
+impl EventCounts {
+    pub(crate) fn record(&mut self, event: &AppInput) {
+        let count = match event {
+            AppInput::AppleMedia(_) => &mut self.apple_media,
+            AppInput::Battery(_) => &mut self.battery,
+            AppInput::Time(_) => &mut self.time,
+            AppInput::Touch(_) => &mut self.touch,
+            AppInput::ButtonPressed => &mut self.button_pressed,
+            AppInput::Tick => &mut self.tick,
+            AppInput::Diagnostics(_) => &mut self.diagnostics,
+            AppInput::Settings(_) => &mut self.settings,
+        };
+
+        *count = count.wrapping_add(1);
+    }
+}
+
+/// Minimum, average, and maximum time taken to draw and send a frame, over
+/// one window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct FrameTimes {
+    pub(crate) min_ms: u64,
+    pub(crate) avg_ms: u64,
+    pub(crate) max_ms: u64,
+}
+
+/// Collects frame times over consecutive windows of `WINDOW_MS`, so the
+/// numbers displayed are stable enough to read.
+pub(crate) struct FrameStats {
+    window_start_ms_since_boot: u64,
+    /// When the event being drawn started to be handled.
+    frame_start_ms_since_boot: Option<u64>,
+    min_ms: u64,
+    max_ms: u64,
+    total_ms: u64,
+    frames: u64,
+    /// Results from the most recently completed window.
+    last_window: Option<FrameTimes>,
+}
+
+impl FrameStats {
+    const WINDOW_MS: u64 = 1000;
+
+    pub(crate) fn new(ms_since_boot: u64) -> Self {
+        Self {
+            window_start_ms_since_boot: ms_since_boot,
+            frame_start_ms_since_boot: None,
+            min_ms: u64::MAX,
+            max_ms: 0,
+            total_ms: 0,
+            frames: 0,
+            last_window: None,
+        }
+    }
+
+    pub(crate) fn start_frame(&mut self, ms_since_boot: u64) {
+        self.frame_start_ms_since_boot = Some(ms_since_boot);
+    }
+
+    /// Records the frame started by the last `start_frame`, if any. Time
+    /// spent waiting for events isn't counted.
+    pub(crate) fn end_frame(&mut self, ms_since_boot: u64) {
+        let Some(frame_start) = self.frame_start_ms_since_boot.take() else {
+            return;
+        };
+        // TODO handle roll-over
+        let frame_ms = ms_since_boot.saturating_sub(frame_start);
+        self.min_ms = self.min_ms.min(frame_ms);
+        self.max_ms = self.max_ms.max(frame_ms);
+        self.total_ms += frame_ms;
+        self.frames += 1;
+
+        if ms_since_boot.saturating_sub(self.window_start_ms_since_boot) >= Self::WINDOW_MS
+            && self.frames > 0
+        {
+            self.last_window = Some(FrameTimes {
+                min_ms: self.min_ms,
+                avg_ms: self.total_ms / self.frames,
+                max_ms: self.max_ms,
+            });
+            self.window_start_ms_since_boot = ms_since_boot;
+            self.min_ms = u64::MAX;
+            self.max_ms = 0;
+            self.total_ms = 0;
+            self.frames = 0;
+        }
+    }
+
+    pub(crate) fn last_window(&self) -> Option<FrameTimes> {
+        self.last_window
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::settings::Settings;
+
+    use super::*;
+
+    #[test]
+    fn event_counts() {
+        let mut counts = EventCounts::default();
+        counts.record(&AppInput::Tick);
+        counts.record(&AppInput::Tick);
+        counts.record(&AppInput::Settings(Settings::default()));
+
+        assert_eq!(2, counts.tick);
+        assert_eq!(1, counts.settings);
+        assert_eq!(0, counts.touch);
+    }
+
+    fn frame(stats: &mut FrameStats, start_ms: u64, frame_ms: u64) {
+        stats.start_frame(start_ms);
+        stats.end_frame(start_ms + frame_ms);
+    }
+
+    #[test]
+    fn frame_stats_over_window() {
+        let mut stats = FrameStats::new(0);
+
+        frame(&mut stats, 0, 10);
+        frame(&mut stats, 100, 30);
+        // The time between frames isn't counted, even when it's long.
+        frame(&mut stats, 600, 20);
+        assert_eq!(None, stats.last_window());
+
+        frame(&mut stats, 990, 20);
+        assert_eq!(
+            Some(FrameTimes {
+                min_ms: 10,
+                avg_ms: 20,
+                max_ms: 30,
+            }),
+            stats.last_window()
+        );
+
+        // The previous window is kept until the next one completes.
+        frame(&mut stats, 1500, 100);
+        assert_eq!(30, stats.last_window().unwrap().max_ms);
+
+        // A frame which was never started isn't counted.
+        stats.end_frame(1800);
+        frame(&mut stats, 2000, 50);
+        assert_eq!(
+            Some(FrameTimes {
+                min_ms: 50,
+                avg_ms: 75,
+                max_ms: 100,
+            }),
+            stats.last_window()
+        );
+    }
+}
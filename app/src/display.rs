@@ -7,11 +7,22 @@ use embedded_graphics::{
     mono_font::ascii,
     pixelcolor::WebColors,
     prelude::RgbColor,
-    primitives::{Primitive, PrimitiveStyleBuilder, StrokeAlignment, Triangle},
+    primitives::{
+        Line, Primitive, PrimitiveStyle, PrimitiveStyleBuilder, Rectangle, StrokeAlignment,
+        Triangle,
+    },
     Drawable,
 };
 
-use crate::interface::{BatteryData, DisplayColor, TimeOfDay, LCD_H, LCD_W};
+use crate::{
+    battery::{BatteryHistory, BatteryLevel, BATTERY_HISTORY_LEN},
+    diagnostics::{EventCounts, FrameTimes},
+    interface::{
+        BatteryData, BleState, Diagnostics, DisplayColor, DisplayWrites, TimeOfDay, LCD_H, LCD_W,
+    },
+    settings::{Brightness, Settings, TimeFormat, SCREEN_TIMEOUTS_S},
+    widget::{Button, List, Toggle},
+};
 
 pub(crate) fn draw_bg<D>(display: &mut D) -> Result<(), D::Error>
 where
@@ -30,7 +41,16 @@ where
     Ok(())
 }
 
-pub(crate) fn draw_audio<D>(display: &mut D, artist: &str, title: &str) -> Result<(), D::Error>
+/// Region of the screen covered by the play button drawn in `draw_audio`.
+pub(crate) const PLAY_BUTTON_REGION: Rectangle =
+    Rectangle::new(Point::new(80, 80), Size::new(80, 80));
+
+pub(crate) fn draw_audio<D>(
+    display: &mut D,
+    artist: &str,
+    title: &str,
+    play_pressed: bool,
+) -> Result<(), D::Error>
 where
     D: DrawTarget<Color = DisplayColor>,
 {
@@ -58,7 +78,7 @@ where
         }
 
         let remaining_horizontal_space = (LCD_W - (text.len() * char_width as usize) as u16) as u32;
-        let is_odd = remaining_horizontal_space % 2 != 0;
+        let is_odd = !remaining_horizontal_space.is_multiple_of(2);
         let left_padding = (remaining_horizontal_space / 2) + if is_odd { 1 } else { 0 };
         let right_padding = remaining_horizontal_space / 2;
 
@@ -97,7 +117,10 @@ where
     .into_styled(
         PrimitiveStyleBuilder::new()
             .stroke_width(2)
-            .fill_color(DisplayColor::new(85, 255, 85))
+            .fill_color(match play_pressed {
+                true => DisplayColor::WHITE,
+                false => DisplayColor::new(85, 255, 85),
+            })
             .stroke_color(DisplayColor::CSS_GRAY)
             .build(),
     )
@@ -106,13 +129,21 @@ where
     Ok(())
 }
 
-pub(crate) fn draw_battery<D>(display: &mut D, battery_data: &BatteryData) -> Result<(), D::Error>
+/// Draws a battery icon with a fill level, and the percentage to its left, in
+/// the top right corner of the screen.
+///
+/// Before the first battery sample arrives the icon is drawn empty.
+pub(crate) fn draw_battery<D>(display: &mut D, level: Option<BatteryLevel>) -> Result<(), D::Error>
 where
     D: DrawTarget<Color = DisplayColor>,
 {
-    let color = match battery_data.charging {
-        true => DisplayColor::new(85, 255, 85),
-        false => DisplayColor::new(255, 85, 85),
+    let percent = level.map(|l| l.percent).unwrap_or(0);
+    let color = match level {
+        Some(BatteryLevel { charging: true, .. }) => DisplayColor::new(85, 255, 85),
+        Some(BatteryLevel {
+            percent: 0..=20, ..
+        }) => DisplayColor::new(255, 85, 85),
+        _ => DisplayColor::WHITE,
     };
     let font = ascii::FONT_5X7;
 
@@ -120,19 +151,52 @@ where
     // allocated enough characters to fit this string.
     const NUM_CHARS: usize = 4;
     let mut s = ArrayString::<NUM_CHARS>::new();
-    write!(&mut s, "{:1.1}v", battery_data.voltage,).unwrap();
+    match level {
+        Some(level) => write!(&mut s, "{:>3}%", level.percent).unwrap(),
+        None => write!(&mut s, " --%").unwrap(),
+    }
 
     let outline_stoke = 2;
-    let width = font.character_size.width * NUM_CHARS as u32 + 2 * outline_stoke;
+    let nub_width = 2;
+    let body_size = Size::new(22, font.character_size.height + 2 * outline_stoke);
+    let body_top_left = Point::new(LCD_W as i32 - nub_width - body_size.width as i32, 0);
+
+    embedded_graphics::primitives::Rectangle::new(body_top_left, body_size)
+        .into_styled(
+            PrimitiveStyleBuilder::new()
+                .stroke_width(outline_stoke)
+                .stroke_alignment(StrokeAlignment::Inside)
+                .stroke_color(color)
+                .build(),
+        )
+        .draw(display)?;
+    embedded_graphics::primitives::Rectangle::new(
+        Point::new(LCD_W as i32 - nub_width, body_size.height as i32 / 4),
+        Size::new(nub_width as u32, body_size.height / 2),
+    )
+    .into_styled(PrimitiveStyleBuilder::new().fill_color(color).build())
+    .draw(display)?;
+
+    // The fill is drawn inside the outline with a one pixel gap. The empty
+    // part is drawn black to clear any previous, higher, fill level.
+    let fill_top_left =
+        body_top_left + Point::new(outline_stoke as i32 + 1, outline_stoke as i32 + 1);
+    let fill_max_width = body_size.width - 2 * (outline_stoke + 1);
+    let fill_height = body_size.height - 2 * (outline_stoke + 1);
+    let fill_width = fill_max_width * percent.min(100) as u32 / 100;
+    embedded_graphics::primitives::Rectangle::new(
+        fill_top_left,
+        Size::new(fill_width, fill_height),
+    )
+    .into_styled(PrimitiveStyleBuilder::new().fill_color(color).build())
+    .draw(display)?;
     embedded_graphics::primitives::Rectangle::new(
-        Point::new(LCD_W as i32 - width as i32, 0),
-        Size::new(width as u32, font.character_size.height + 2 * outline_stoke),
+        fill_top_left + Point::new(fill_width as i32, 0),
+        Size::new(fill_max_width - fill_width, fill_height),
     )
     .into_styled(
         PrimitiveStyleBuilder::new()
-            .stroke_width(outline_stoke)
-            .stroke_alignment(StrokeAlignment::Inside)
-            .stroke_color(color)
+            .fill_color(DisplayColor::BLACK)
             .build(),
     )
     .draw(display)?;
@@ -147,10 +211,11 @@ where
         .baseline(embedded_graphics::text::Baseline::Top)
         .build();
 
+    let text_width = font.character_size.width * NUM_CHARS as u32;
     embedded_graphics::text::Text::with_text_style(
         s.as_str(),
         Point::new(
-            LCD_W as i32 - width as i32 + outline_stoke as i32,
+            body_top_left.x - text_width as i32 - 2,
             outline_stoke as i32,
         ),
         character_style,
@@ -161,7 +226,7 @@ where
     Ok(())
 }
 
-pub(crate) fn draw_time<D, E>(display: &mut D, time: TimeOfDay) -> Result<(), E>
+pub(crate) fn draw_time<D, E>(display: &mut D, time: TimeOfDay, format: TimeFormat) -> Result<(), E>
 where
     D: DrawTarget<Color = DisplayColor, Error = E>,
     E: core::fmt::Debug,
@@ -178,13 +243,29 @@ where
 
     // The unwrap on the write! is safe because we can tell statically that we've
     // allocated enough characters to fit this string.
-    const TIME_NUM_CHARS: usize = 8;
+    const TIME_NUM_CHARS: usize = 11;
     let mut time_string = ArrayString::<TIME_NUM_CHARS>::new();
-    write!(
-        &mut time_string,
-        "{:02}:{:02}:{:02}",
-        time.hours, time.minutes, time.seconds
-    )
+    match format {
+        TimeFormat::TwentyFourHour => write!(
+            &mut time_string,
+            "{:02}:{:02}:{:02}",
+            time.hours, time.minutes, time.seconds
+        ),
+        TimeFormat::TwelveHour => write!(
+            &mut time_string,
+            "{:02}:{:02}:{:02} {}",
+            match time.hours % 12 {
+                0 => 12,
+                hours => hours,
+            },
+            time.minutes,
+            time.seconds,
+            match time.hours < 12 {
+                true => "AM",
+                false => "PM",
+            }
+        ),
+    }
     .unwrap();
 
     let text_x_pos = 0;
@@ -201,11 +282,24 @@ where
     Ok(())
 }
 
-pub(crate) fn draw_fps<D, E>(display: &mut D, fps: u32) -> Result<(), E>
+/// Plots the battery history as a line graph, with periods spent charging
+/// highlighted behind the line.
+///
+/// The newest sample is at the right edge, and the full width of the graph
+/// covers the full history length, so the time axis doesn't depend on how
+/// many samples have been recorded.
+pub(crate) fn draw_battery_history<D>(
+    display: &mut D,
+    history: &BatteryHistory,
+) -> Result<(), D::Error>
 where
-    D: DrawTarget<Color = DisplayColor, Error = E>,
-    E: core::fmt::Debug,
+    D: DrawTarget<Color = DisplayColor>,
 {
+    let plot = Rectangle::new(Point::new(10, 40), Size::new(220, 160));
+    let grid_color = DisplayColor::CSS_DIM_GRAY;
+    let charging_color = DisplayColor::CSS_DARK_GREEN;
+    let line_color = DisplayColor::WHITE;
+
     // TODO factor these styles out so they aren't defined in multiple places
     let character_style = embedded_graphics::mono_font::MonoTextStyleBuilder::new()
         .font(&ascii::FONT_7X14)
@@ -215,27 +309,133 @@ where
     let text_style = embedded_graphics::text::TextStyleBuilder::new()
         .baseline(embedded_graphics::text::Baseline::Top)
         .build();
+    embedded_graphics::text::Text::with_text_style(
+        "Battery, last 48h",
+        Point::new(plot.top_left.x, 20),
+        character_style,
+        text_style,
+    )
+    .draw(display)?;
+
+    plot.into_styled(
+        PrimitiveStyleBuilder::new()
+            .fill_color(DisplayColor::BLACK)
+            .build(),
+    )
+    .draw(display)?;
+
+    let max_x = plot.size.width as i32 - 1;
+    let max_y = plot.size.height as i32 - 1;
+    let x_of = |i: usize| {
+        let samples_from_end = (BATTERY_HISTORY_LEN - history.len() + i) as i32;
+        plot.top_left.x + samples_from_end * max_x / (BATTERY_HISTORY_LEN as i32 - 1)
+    };
+    let y_of = |percent: u8| plot.top_left.y + max_y - percent as i32 * max_y / 100;
+
+    // Charging periods, drawn first so everything else is on top.
+    let mut previous_x = None;
+    for (i, level) in history.iter().enumerate() {
+        let x = x_of(i);
+        if level.charging {
+            let from_x = previous_x.unwrap_or(x);
+            Rectangle::new(
+                Point::new(from_x, plot.top_left.y),
+                Size::new((x - from_x + 1) as u32, plot.size.height),
+            )
+            .into_styled(
+                PrimitiveStyleBuilder::new()
+                    .fill_color(charging_color)
+                    .build(),
+            )
+            .draw(display)?;
+        }
+        previous_x = Some(x);
+    }
+
+    // Grid lines at every 25%, and every 12 hours.
+    let grid_style = PrimitiveStyle::with_stroke(grid_color, 1);
+    for percent in [0, 25, 50, 75, 100] {
+        let y = y_of(percent);
+        Line::new(
+            Point::new(plot.top_left.x, y),
+            Point::new(plot.top_left.x + max_x, y),
+        )
+        .into_styled(grid_style)
+        .draw(display)?;
+    }
+    for hours in [0, 12, 24, 36, 48] {
+        let x = plot.top_left.x + hours * max_x / 48;
+        Line::new(
+            Point::new(x, plot.top_left.y),
+            Point::new(x, plot.top_left.y + max_y),
+        )
+        .into_styled(grid_style)
+        .draw(display)?;
+    }
+
+    let line_style = PrimitiveStyle::with_stroke(line_color, 1);
+    let mut previous_point = None;
+    for (i, level) in history.iter().enumerate() {
+        let point = Point::new(x_of(i), y_of(level.percent));
+        Line::new(previous_point.unwrap_or(point), point)
+            .into_styled(line_style)
+            .draw(display)?;
+        previous_point = Some(point);
+    }
+
+    Ok(())
+}
+
+pub(crate) fn draw_low_battery_popup<D>(display: &mut D, percent: u8) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = DisplayColor>,
+{
+    let popup = Rectangle::new(Point::new(30, 70), Size::new(180, 100));
+    let color = DisplayColor::new(255, 85, 85);
+
+    popup
+        .into_styled(
+            PrimitiveStyleBuilder::new()
+                .fill_color(DisplayColor::BLACK)
+                .stroke_width(3)
+                .stroke_alignment(StrokeAlignment::Inside)
+                .stroke_color(color)
+                .build(),
+        )
+        .draw(display)?;
+
+    // TODO factor these styles out so they aren't defined in multiple places
+    let text_style = embedded_graphics::text::TextStyleBuilder::new()
+        .baseline(embedded_graphics::text::Baseline::Middle)
+        .alignment(embedded_graphics::text::Alignment::Center)
+        .build();
+
+    embedded_graphics::text::Text::with_text_style(
+        "Low battery",
+        Point::new(popup.center().x, popup.top_left.y + 35),
+        embedded_graphics::mono_font::MonoTextStyleBuilder::new()
+            .font(&ascii::FONT_9X15_BOLD)
+            .text_color(color)
+            .background_color(DisplayColor::BLACK)
+            .build(),
+        text_style,
+    )
+    .draw(display)?;
 
     // The unwrap on the write! is safe because we can tell statically that we've
     // allocated enough characters to fit this string.
-    const NUM_CHARS: usize = 8;
+    const NUM_CHARS: usize = 14;
     let mut s = ArrayString::<NUM_CHARS>::new();
-    write!(
-        &mut s,
-        "FPS: {:03}",
-        // Limit the displayed fps value to max we can fit in three characters
-        fps.min(999),
-    )
-    .unwrap();
-
-    let char_height = 14;
-    let text_x_pos = 0;
-    let text_y_pos = 240 - char_height;
+    write!(&mut s, "{}% remaining", percent.min(100)).unwrap();
 
     embedded_graphics::text::Text::with_text_style(
         s.as_str(),
-        Point::new(text_x_pos, text_y_pos),
-        character_style,
+        Point::new(popup.center().x, popup.top_left.y + 65),
+        embedded_graphics::mono_font::MonoTextStyleBuilder::new()
+            .font(&ascii::FONT_7X14)
+            .text_color(DisplayColor::WHITE)
+            .background_color(DisplayColor::BLACK)
+            .build(),
         text_style,
     )
     .draw(display)?;
@@ -243,6 +443,219 @@ where
     Ok(())
 }
 
+/// Regions of the buttons in the settings window, brightness at the top and
+/// forgetting bonded phones at the bottom.
+pub(crate) const SETTINGS_BUTTON_REGIONS: [Rectangle; 2] = [
+    Rectangle::new(Point::new(10, 8), Size::new(220, 36)),
+    Rectangle::new(Point::new(10, 196), Size::new(220, 36)),
+];
+
+/// Region of the time format toggle, under the brightness button.
+pub(crate) const TIME_FORMAT_TOGGLE_REGION: Rectangle =
+    Rectangle::new(Point::new(10, 50), Size::new(220, 36));
+
+/// Region of the list of screen timeouts, which shows three of them at a
+/// time.
+pub(crate) const SCREEN_TIMEOUT_LIST_REGION: Rectangle =
+    Rectangle::new(Point::new(10, 92), Size::new(220, 96));
+pub(crate) const SCREEN_TIMEOUT_ROW_HEIGHT: u32 = 32;
+
+/// Draws the settings window, where tapping a setting's button, toggle or
+/// list row changes it. `confirm_forget` asks for a second tap before
+/// forgetting bonded phones.
+pub(crate) fn draw_settings<D>(
+    display: &mut D,
+    ms_since_boot: u64,
+    settings: &Settings,
+    buttons: &[Button; 2],
+    time_format: &Toggle,
+    screen_timeouts: &List,
+    confirm_forget: bool,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = DisplayColor>,
+{
+    let [brightness, forget] = buttons;
+
+    brightness.draw(
+        display,
+        ms_since_boot,
+        match settings.brightness {
+            Brightness::Low => "Brightness: low",
+            Brightness::Medium => "Brightness: medium",
+            Brightness::High => "Brightness: high",
+        },
+    )?;
+    time_format.draw(display, ms_since_boot, "24 hour time")?;
+
+    // The unwraps on the write! are safe because the timeouts have at most
+    // three digits.
+    let labels = SCREEN_TIMEOUTS_S.map(|timeout_s| {
+        let mut label = ArrayString::<20>::new();
+        match timeout_s == settings.screen_timeout_s {
+            true => write!(&mut label, "> Screen off: {timeout_s}s").unwrap(),
+            false => write!(&mut label, "Screen off: {timeout_s}s").unwrap(),
+        }
+        label
+    });
+    screen_timeouts.draw(
+        display,
+        ms_since_boot,
+        &labels.each_ref().map(|l| l.as_str()),
+    )?;
+
+    forget.draw(
+        display,
+        ms_since_boot,
+        match confirm_forget {
+            false => "Forget phone",
+            true => "Tap again to forget",
+        },
+    )?;
+
+    Ok(())
+}
+
+/// Draws the diagnostics window, one value per line.
+///
+/// Each line is padded to the full width of the screen, so the window can be
+/// redrawn without clearing it first.
+pub(crate) fn draw_diagnostics<D>(
+    display: &mut D,
+    uptime_ms: u64,
+    diagnostics: Option<&Diagnostics>,
+    battery: Option<&BatteryData>,
+    frame_times: Option<FrameTimes>,
+    counts: &EventCounts,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = DisplayColor>,
+{
+    // TODO factor these styles out so they aren't defined in multiple places
+    let character_style = embedded_graphics::mono_font::MonoTextStyleBuilder::new()
+        .font(&ascii::FONT_6X10)
+        .text_color(DisplayColor::WHITE)
+        .background_color(DisplayColor::BLACK)
+        .build();
+    let text_style = embedded_graphics::text::TextStyleBuilder::new()
+        .baseline(embedded_graphics::text::Baseline::Top)
+        .build();
+
+    const LINE_CHARS: usize = LCD_W as usize / 6;
+    const LINE_HEIGHT: i32 = 12;
+    let mut row = 0;
+    let mut draw_line = |args: core::fmt::Arguments| {
+        // The unwrap on the write! is safe because every line below is
+        // shorter than LINE_CHARS.
+        let mut line = ArrayString::<LINE_CHARS>::new();
+        write!(&mut line, "{args}").unwrap();
+        while !line.is_full() {
+            line.push(' ');
+        }
+
+        let result = embedded_graphics::text::Text::with_text_style(
+            line.as_str(),
+            Point::new(0, 4 + row * LINE_HEIGHT),
+            character_style,
+            text_style,
+        )
+        .draw(display);
+        row += 1;
+
+        result.map(|_| ())
+    };
+
+    let seconds = uptime_ms / 1000;
+    draw_line(format_args!(
+        "Uptime    {}d {:02}:{:02}:{:02}",
+        seconds / 60 / 60 / 24,
+        seconds / 60 / 60 % 24,
+        seconds / 60 % 60,
+        seconds % 60
+    ))?;
+    draw_line(format_args!(
+        "Firmware  {}",
+        diagnostics.map_or("unknown", |d| d.firmware_version.as_str())
+    ))?;
+
+    match battery {
+        Some(battery) => {
+            draw_line(format_args!("Battery   {:.3}v", battery.voltage))?;
+            draw_line(format_args!(
+                "Charging  {}",
+                match battery.charging {
+                    true => "yes",
+                    false => "no",
+                }
+            ))?;
+        }
+        None => {
+            draw_line(format_args!("Battery   unknown"))?;
+            draw_line(format_args!("Charging  unknown"))?;
+        }
+    }
+
+    match diagnostics.map(|d| d.ble) {
+        None => {
+            draw_line(format_args!("BLE       unknown"))?;
+            draw_line(format_args!(""))?;
+        }
+        Some(BleState::Unavailable) => {
+            draw_line(format_args!("BLE       unavailable"))?;
+            draw_line(format_args!(""))?;
+        }
+        Some(BleState::Advertising) => {
+            draw_line(format_args!("BLE       advertising"))?;
+            draw_line(format_args!(""))?;
+        }
+        Some(BleState::Connected { peer_address: a }) => {
+            draw_line(format_args!("BLE       connected"))?;
+            draw_line(format_args!(
+                "Peer      {:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
+                a[0], a[1], a[2], a[3], a[4], a[5]
+            ))?;
+        }
+    }
+
+    match frame_times {
+        Some(f) => draw_line(format_args!(
+            "Frame ms  {}/{}/{} min/avg/max",
+            f.min_ms.min(9999),
+            f.avg_ms.min(9999),
+            f.max_ms.min(9999)
+        ))?,
+        None => draw_line(format_args!("Frame ms  measuring"))?,
+    }
+    // The frame rate the display could keep up with, if every frame took the
+    // average time to draw and send.
+    match frame_times {
+        Some(f) => draw_line(format_args!("Max FPS   {}", 1000 / f.avg_ms.max(1)))?,
+        None => draw_line(format_args!("Max FPS   measuring"))?,
+    }
+    draw_line(format_args!(
+        "Display   {}",
+        match diagnostics.map(|d| d.display_writes) {
+            None | Some(DisplayWrites::Unknown) => "unknown",
+            Some(DisplayWrites::Simulator) => "simulator",
+            Some(DisplayWrites::Blocking) => "blocking writes",
+            Some(DisplayWrites::Dma) => "DMA writes",
+        }
+    ))?;
+
+    draw_line(format_args!(""))?;
+    draw_line(format_args!("Events"))?;
+    draw_line(format_args!("  Tick      {}", counts.tick))?;
+    draw_line(format_args!("  Touch     {}", counts.touch))?;
+    draw_line(format_args!("  Button    {}", counts.button_pressed))?;
+    draw_line(format_args!("  Battery   {}", counts.battery))?;
+    draw_line(format_args!("  Time      {}", counts.time))?;
+    draw_line(format_args!("  Media     {}", counts.apple_media))?;
+    draw_line(format_args!("  Diag      {}", counts.diagnostics))?;
+    draw_line(format_args!("  Settings  {}", counts.settings))?;
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     extern crate std;
@@ -261,8 +674,32 @@ mod tests {
 
         // First draw long strings, then shorter ones, to show we properly clear
         // out the old text.
-        draw_audio(&mut display, "long artist", "long title").unwrap();
-        draw_audio(&mut display, "artist", "title").unwrap();
+        draw_audio(&mut display, "long artist", "long title", false).unwrap();
+        draw_audio(&mut display, "artist", "title", false).unwrap();
+
+        assert_snapshot(test_name, display);
+    }
+
+    #[test]
+    fn battery_history() {
+        let test_name = function_name!();
+        let mut display = SimDisplay::new(Size::new(LCD_W as u32, LCD_H as u32));
+
+        // About 30 hours of discharging, two hours on the charger, then
+        // discharging again. The history is not yet full.
+        let mut history = BatteryHistory::new();
+        let mut percent: u8 = 100;
+        for i in 0..240 {
+            let charging = (180..192).contains(&i);
+            match charging {
+                true => percent = (percent + 6).min(100),
+                false if i % 2 == 0 => percent = percent.saturating_sub(1),
+                false => {}
+            }
+            history.push(BatteryLevel { percent, charging });
+        }
+
+        draw_battery_history(&mut display, &history).unwrap();
 
         assert_snapshot(test_name, display);
     }
@@ -0,0 +1,377 @@
+//! Skipping pixels which are already on the display.
+//!
+//! The app redraws every element of a window on each tick, and most of them
+//! haven't changed. A full shadow of the screen would take 112KiB, more RAM
+//! than the PineTime has, so `FrameDiff` instead remembers a hash of each
+//! horizontal span of pixels it sent, indexed by line. Spans which are drawn
+//! again with the same pixels are skipped, and only the lines which changed
+//! are sent, each with its own address window.
+//!
+//! A span is only skipped if it exactly matches one drawn before, and any
+//! drawing over part of a span forgets it, so the display is always correct
+//! unless two different spans hash the same (about one in four billion).
+
+use embedded_graphics::{
+    draw_target::DrawTarget,
+    geometry::{Dimensions, Point, Size},
+    pixelcolor::IntoStorage,
+    primitives::Rectangle,
+    Pixel,
+};
+
+use crate::interface::{DisplayColor, LCD_H, LCD_W};
+
+#[derive(Clone, Copy)]
+struct Span {
+    start_x: u8,
+    /// Inclusive.
+    end_x: u8,
+    hash: u32,
+}
+
+impl Span {
+    const EMPTY: Span = Span {
+        start_x: 1,
+        end_x: 0,
+        hash: 0,
+    };
+
+    fn is_empty(&self) -> bool {
+        self.start_x > self.end_x
+    }
+
+    fn overlaps(&self, start_x: u8, end_x: u8) -> bool {
+        !self.is_empty() && self.start_x <= end_x && start_x <= self.end_x
+    }
+}
+
+/// The spans remembered for each line, `SLOTS` per line. Each slot takes 8
+/// bytes, so with 4 slots the table takes 7.5KiB.
+///
+/// This is separate from `FrameDiff` so it can be placed in a static, rather
+/// than in the future of the task which draws.
+pub struct SpanTable<const SLOTS: usize> {
+    lines: [[Span; SLOTS]; LCD_H as usize],
+}
+
+impl<const SLOTS: usize> Default for SpanTable<SLOTS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const SLOTS: usize> SpanTable<SLOTS> {
+    pub const fn new() -> Self {
+        Self {
+            lines: [[Span::EMPTY; SLOTS]; LCD_H as usize],
+        }
+    }
+
+    /// Records the span as being on the display, returning true if it
+    /// already was.
+    fn update(&mut self, y: usize, start_x: u8, end_x: u8, hash: u32) -> bool {
+        let line = &mut self.lines[y];
+        if line
+            .iter()
+            .any(|span| span.start_x == start_x && span.end_x == end_x && span.hash == hash)
+        {
+            return true;
+        }
+
+        // Spans drawn over no longer describe what is on the display.
+        for span in line.iter_mut() {
+            if span.overlaps(start_x, end_x) {
+                *span = Span::EMPTY;
+            }
+        }
+        // When the line is full an arbitrary span is forgotten, which only
+        // means it will be sent again.
+        let slot = line
+            .iter()
+            .position(Span::is_empty)
+            .unwrap_or(hash as usize % SLOTS);
+        line[slot] = Span {
+            start_x,
+            end_x,
+            hash,
+        };
+
+        false
+    }
+
+    fn fill(&mut self, hash: u32) {
+        for line in self.lines.iter_mut() {
+            *line = [Span::EMPTY; SLOTS];
+            line[0] = Span {
+                start_x: 0,
+                end_x: (LCD_W - 1) as u8,
+                hash,
+            };
+        }
+    }
+}
+
+/// Wraps a `DrawTarget`, only passing on lines of pixels which differ from
+/// what was previously drawn there.
+pub struct FrameDiff<'a, D, const SLOTS: usize> {
+    inner: D,
+    spans: &'a mut SpanTable<SLOTS>,
+    /// Pixels from `draw_iter` are gathered into runs along a line, so each
+    /// run can be compared and sent as a whole.
+    run_start: Point,
+    run: arrayvec::ArrayVec<DisplayColor, { LCD_W as usize }>,
+}
+
+impl<'a, D, const SLOTS: usize> FrameDiff<'a, D, SLOTS>
+where
+    D: DrawTarget<Color = DisplayColor>,
+{
+    /// `spans` should be empty, or describe what `inner` shows.
+    pub fn new(inner: D, spans: &'a mut SpanTable<SLOTS>) -> Self {
+        Self {
+            inner,
+            spans,
+            run_start: Point::zero(),
+            run: arrayvec::ArrayVec::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    fn screen(&self) -> Rectangle {
+        Rectangle::new(Point::zero(), Size::new(LCD_W.into(), LCD_H.into()))
+            .intersection(&self.inner.bounding_box())
+    }
+
+    fn flush_run(&mut self) -> Result<(), D::Error> {
+        if self.run.is_empty() {
+            return Ok(());
+        }
+
+        let start_x = self.run_start.x as u8;
+        let end_x = start_x + (self.run.len() - 1) as u8;
+        let hash = hash_colors(self.run.iter().copied());
+        if !self
+            .spans
+            .update(self.run_start.y as usize, start_x, end_x, hash)
+        {
+            let area = Rectangle::new(self.run_start, Size::new(self.run.len() as u32, 1));
+            self.inner
+                .fill_contiguous(&area, self.run.iter().copied())?;
+        }
+        self.run.clear();
+
+        Ok(())
+    }
+}
+
+impl<D, const SLOTS: usize> Dimensions for FrameDiff<'_, D, SLOTS>
+where
+    D: DrawTarget<Color = DisplayColor>,
+{
+    fn bounding_box(&self) -> Rectangle {
+        self.inner.bounding_box()
+    }
+}
+
+impl<D, const SLOTS: usize> DrawTarget for FrameDiff<'_, D, SLOTS>
+where
+    D: DrawTarget<Color = DisplayColor>,
+{
+    type Color = DisplayColor;
+    type Error = D::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let screen = self.screen();
+
+        for Pixel(point, color) in pixels {
+            if !screen.contains(point) {
+                continue;
+            }
+
+            let continues_run = !self.run.is_empty()
+                && point.y == self.run_start.y
+                && point.x == self.run_start.x + self.run.len() as i32;
+            if !continues_run {
+                self.flush_run()?;
+                self.run_start = point;
+            }
+            // The run can't be longer than a line.
+            self.run.push(color);
+        }
+
+        self.flush_run()
+    }
+
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Self::Color>,
+    {
+        let visible = area.intersection(&self.screen());
+        let mut colors = colors.into_iter();
+
+        for y in area.rows() {
+            for x in area.columns() {
+                let Some(color) = colors.next() else {
+                    return self.flush_run();
+                };
+                if visible.contains(Point::new(x, y)) {
+                    if self.run.is_empty() {
+                        self.run_start = Point::new(x, y);
+                    }
+                    self.run.push(color);
+                }
+            }
+            self.flush_run()?;
+        }
+
+        Ok(())
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        let area = area.intersection(&self.screen());
+        if area.is_zero_sized() {
+            return Ok(());
+        }
+
+        let start_x = area.top_left.x as u8;
+        let end_x = start_x + (area.size.width - 1) as u8;
+        let hash = hash_colors((0..area.size.width).map(|_| color));
+
+        // Consecutive changed lines are sent together.
+        let mut changed_rows: Option<(i32, u32)> = None;
+        for y in area.rows() {
+            if self.spans.update(y as usize, start_x, end_x, hash) {
+                if let Some((top, height)) = changed_rows.take() {
+                    let rows = Rectangle::new(
+                        Point::new(area.top_left.x, top),
+                        Size::new(area.size.width, height),
+                    );
+                    self.inner.fill_solid(&rows, color)?;
+                }
+            } else {
+                changed_rows = match changed_rows {
+                    Some((top, height)) => Some((top, height + 1)),
+                    None => Some((y, 1)),
+                };
+            }
+        }
+        if let Some((top, height)) = changed_rows {
+            let rows = Rectangle::new(
+                Point::new(area.top_left.x, top),
+                Size::new(area.size.width, height),
+            );
+            self.inner.fill_solid(&rows, color)?;
+        }
+
+        Ok(())
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        self.spans.fill(hash_colors((0..LCD_W).map(|_| color)));
+
+        self.inner.clear(color)
+    }
+}
+
+/// 32 bit FNV-1a.
+fn hash_colors(colors: impl Iterator<Item = DisplayColor>) -> u32 {
+    colors.fold(0x811c9dc5, |hash, color| {
+        color
+            .into_storage()
+            .to_le_bytes()
+            .iter()
+            .fold(hash, |hash, byte| {
+                (hash ^ *byte as u32).wrapping_mul(0x01000193)
+            })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_graphics::pixelcolor::RgbColor;
+
+    use crate::{interface::AppInput, render_cost::CostCounter, test_infra::SimDisplay, App};
+
+    use super::*;
+
+    fn new_display() -> SimDisplay {
+        SimDisplay::new(Size::new(LCD_W.into(), LCD_H.into()))
+    }
+
+    #[test]
+    fn skips_unchanged_spans() {
+        let mut display = new_display();
+        let mut spans = SpanTable::<4>::new();
+        let mut diff = FrameDiff::new(CostCounter::new(&mut display), &mut spans);
+        let rectangle = Rectangle::new(Point::new(10, 10), Size::new(20, 5));
+
+        diff.clear(DisplayColor::BLACK).unwrap();
+        diff.fill_solid(&rectangle, DisplayColor::RED).unwrap();
+        let drawn = diff.inner().cost().pixels;
+        assert_eq!(240 * 240 + 20 * 5, drawn);
+
+        diff.fill_solid(&rectangle, DisplayColor::RED).unwrap();
+        assert_eq!(drawn, diff.inner().cost().pixels);
+
+        // Drawing over part of the rectangle means it has to be sent again.
+        diff.draw_iter([Pixel(Point::new(15, 12), DisplayColor::BLUE)])
+            .unwrap();
+        diff.fill_solid(&rectangle, DisplayColor::RED).unwrap();
+        assert_eq!(drawn + 1 + 20, diff.inner().cost().pixels);
+    }
+
+    #[test]
+    fn matches_drawing_directly() {
+        let inputs = || {
+            [
+                AppInput::Battery(crate::interface::BatteryData {
+                    charging: false,
+                    voltage: 3.9,
+                }),
+                AppInput::Tick,
+                AppInput::Tick,
+                AppInput::ButtonPressed,
+                AppInput::Tick,
+                AppInput::ButtonPressed,
+                AppInput::Tick,
+                AppInput::ButtonPressed,
+                AppInput::Tick,
+                AppInput::ButtonPressed,
+                AppInput::Tick,
+                AppInput::Tick,
+            ]
+        };
+
+        let mut expected = new_display();
+        let mut app = App::init(&mut expected, 0).unwrap();
+        for (i, input) in inputs().into_iter().enumerate() {
+            app.handle_event(&mut expected, 1000 * i as u64, input)
+                .unwrap();
+        }
+
+        let mut actual = new_display();
+        let mut spans = SpanTable::<4>::new();
+        let mut diff = FrameDiff::new(CostCounter::new(&mut actual), &mut spans);
+        let mut app = App::init(&mut diff, 0).unwrap();
+        let mut previous_pixels = 0;
+        for (i, input) in inputs().into_iter().enumerate() {
+            app.handle_event(&mut diff, 1000 * i as u64, input).unwrap();
+
+            let pixels = diff.inner().cost().pixels;
+            if i == inputs().len() - 1 {
+                // Only the seconds change on the last tick.
+                assert!(pixels - previous_pixels < 2_000);
+            }
+            previous_pixels = pixels;
+        }
+        drop(diff);
+
+        assert!(actual == expected);
+    }
+}
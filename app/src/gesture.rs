@@ -0,0 +1,737 @@
+//! Software gesture recognition from raw touch contact streams.
+//!
+//! The touch controller only reliably reports swipes and single clicks as
+//! gestures, so `GestureRecognizer` works from the raw Down/Contact/Up
+//! reports instead. It is fed every report along with a timestamp, and
+//! should be polled (see `next_deadline`) so gestures which depend on the
+//! passage of time, such as long presses, are reported without further
+//! touch input.
+//!
+//! On the watch, `ControllerGestures` keeps the controller's own taps, and
+//! takes everything else from `GestureRecognizer`.
+
+use crate::interface::{Gesture, Touch, TouchType};
+
+/// Tunable thresholds for gesture recognition.
+///
+/// Distances are in pixels, durations in milliseconds.
+#[derive(Clone, Copy, Debug)]
+pub struct GestureConfig {
+    /// A contact held without moving for this long is a long press.
+    pub long_press_ms: u64,
+    /// Maximum time between the end of one tap and the end of the next for
+    /// the pair to be considered a double tap. Single taps are reported
+    /// only after this window passes, so setting it to zero disables double
+    /// tap recognition and reports taps immediately.
+    pub double_tap_window_ms: u64,
+    /// Maximum distance between two taps for them to be a double tap.
+    pub double_tap_max_distance: u8,
+    /// A contact which moves further than this from where it started is a
+    /// drag rather than a tap.
+    pub drag_min_distance: u8,
+    /// A drag which ends at least this far from where it started, along
+    /// either axis, is a swipe.
+    pub swipe_min_distance: u8,
+    /// A drag must end within this time of starting to be a swipe.
+    pub swipe_max_ms: u64,
+    /// The controller rarely reports `TouchType::Up`, so a contact is
+    /// considered released once it hasn't been reported for this long.
+    pub release_timeout_ms: u64,
+}
+
+impl Default for GestureConfig {
+    fn default() -> Self {
+        Self {
+            long_press_ms: 600,
+            double_tap_window_ms: 250,
+            double_tap_max_distance: 30,
+            drag_min_distance: 12,
+            swipe_min_distance: 50,
+            swipe_max_ms: 800,
+            release_timeout_ms: 120,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwipeDirection {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecognizedGesture {
+    Tap {
+        x: u8,
+        y: u8,
+    },
+    DoubleTap {
+        x: u8,
+        y: u8,
+    },
+    /// Reported while the contact is still held.
+    LongPress {
+        x: u8,
+        y: u8,
+    },
+    /// Reported each time a contact moves, after it has moved far enough to
+    /// no longer be a tap.
+    Drag {
+        start_x: u8,
+        start_y: u8,
+        x: u8,
+        y: u8,
+    },
+    /// Reported when a quick drag is released.
+    Swipe {
+        direction: SwipeDirection,
+        start_x: u8,
+        start_y: u8,
+    },
+}
+
+impl From<RecognizedGesture> for Touch {
+    /// Converts to the `Touch` the hardware would report for this gesture.
+    ///
+    /// Drags don't have an equivalent gesture, so they are reported as a
+    /// contact at the current position.
+    fn from(value: RecognizedGesture) -> Self {
+        let (gesture, event_type, x, y) = match value {
+            RecognizedGesture::Tap { x, y } => (Gesture::SingleClick, TouchType::Up, x, y),
+            RecognizedGesture::DoubleTap { x, y } => (Gesture::DoubleClick, TouchType::Up, x, y),
+            RecognizedGesture::LongPress { x, y } => (Gesture::LongPress, TouchType::Contact, x, y),
+            RecognizedGesture::Drag { x, y, .. } => (Gesture::None, TouchType::Contact, x, y),
+            RecognizedGesture::Swipe {
+                direction,
+                start_x,
+                start_y,
+            } => (
+                match direction {
+                    SwipeDirection::Up => Gesture::SlideUp,
+                    SwipeDirection::Down => Gesture::SlideDown,
+                    SwipeDirection::Left => Gesture::SlideLeft,
+                    SwipeDirection::Right => Gesture::SlideRight,
+                },
+                TouchType::Up,
+                start_x,
+                start_y,
+            ),
+        };
+
+        Touch {
+            gesture,
+            event_type,
+            x,
+            y,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ContactPoint {
+    x: u8,
+    y: u8,
+    ms_since_boot: u64,
+}
+
+impl ContactPoint {
+    fn dx(&self, other: &ContactPoint) -> i16 {
+        self.x as i16 - other.x as i16
+    }
+
+    fn dy(&self, other: &ContactPoint) -> i16 {
+        self.y as i16 - other.y as i16
+    }
+
+    /// Chebyshev distance, which is cheap and good enough for thresholds.
+    fn distance(&self, other: &ContactPoint) -> u8 {
+        self.dx(other)
+            .unsigned_abs()
+            .max(self.dy(other).unsigned_abs()) as u8
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Contact {
+    start: ContactPoint,
+    last: ContactPoint,
+    dragging: bool,
+    long_press_reported: bool,
+}
+
+pub struct GestureRecognizer {
+    config: GestureConfig,
+    contact: Option<Contact>,
+    /// A tap which may yet become the first half of a double tap.
+    pending_tap: Option<ContactPoint>,
+}
+
+impl GestureRecognizer {
+    pub fn new(config: GestureConfig) -> Self {
+        Self {
+            config,
+            contact: None,
+            pending_tap: None,
+        }
+    }
+
+    /// Handles a raw touch report. The gesture reported by the hardware, if
+    /// any, is ignored.
+    pub fn handle_touch(
+        &mut self,
+        event_type: TouchType,
+        x: u8,
+        y: u8,
+        ms_since_boot: u64,
+    ) -> Option<RecognizedGesture> {
+        let point = ContactPoint {
+            x,
+            y,
+            ms_since_boot,
+        };
+
+        match (event_type, self.contact.as_mut()) {
+            (TouchType::Down | TouchType::Contact, None) => {
+                self.contact = Some(Contact {
+                    start: point,
+                    last: point,
+                    dragging: false,
+                    long_press_reported: false,
+                });
+
+                // A tap which is still pending when the next contact starts
+                // can't become a double tap if the window has passed.
+                self.expire_pending_tap(ms_since_boot)
+            }
+            (TouchType::Down | TouchType::Contact, Some(contact)) => {
+                contact.last = point;
+
+                if !contact.dragging
+                    && !contact.long_press_reported
+                    && point.distance(&contact.start) >= self.config.drag_min_distance
+                {
+                    contact.dragging = true;
+                }
+
+                if contact.dragging {
+                    Some(RecognizedGesture::Drag {
+                        start_x: contact.start.x,
+                        start_y: contact.start.y,
+                        x,
+                        y,
+                    })
+                } else {
+                    self.check_long_press(ms_since_boot)
+                }
+            }
+            (TouchType::Up, Some(contact)) => {
+                contact.last = point;
+                self.release()
+            }
+            (TouchType::Up, None) => None,
+        }
+    }
+
+    /// Reports gestures which are recognized by the passage of time.
+    pub fn poll(&mut self, ms_since_boot: u64) -> Option<RecognizedGesture> {
+        if let Some(contact) = self.contact {
+            if ms_since_boot.saturating_sub(contact.last.ms_since_boot)
+                >= self.config.release_timeout_ms
+            {
+                return self.release();
+            }
+
+            return self.check_long_press(ms_since_boot);
+        }
+
+        self.expire_pending_tap(ms_since_boot)
+    }
+
+    /// Returns the time at which `poll` should next be called, if there is
+    /// anything waiting on the passage of time.
+    pub fn next_deadline(&self) -> Option<u64> {
+        if let Some(contact) = self.contact {
+            let release = contact.last.ms_since_boot + self.config.release_timeout_ms;
+            let long_press = (!contact.dragging && !contact.long_press_reported)
+                .then_some(contact.start.ms_since_boot + self.config.long_press_ms);
+
+            return Some(long_press.map_or(release, |long_press| long_press.min(release)));
+        }
+
+        self.pending_tap
+            .map(|tap| tap.ms_since_boot + self.config.double_tap_window_ms)
+    }
+
+    fn check_long_press(&mut self, ms_since_boot: u64) -> Option<RecognizedGesture> {
+        let contact = self.contact.as_mut()?;
+
+        if contact.dragging
+            || contact.long_press_reported
+            || ms_since_boot.saturating_sub(contact.start.ms_since_boot) < self.config.long_press_ms
+        {
+            return None;
+        }
+
+        contact.long_press_reported = true;
+        // A long press is never half of a double tap.
+        self.pending_tap = None;
+
+        Some(RecognizedGesture::LongPress {
+            x: contact.start.x,
+            y: contact.start.y,
+        })
+    }
+
+    fn expire_pending_tap(&mut self, ms_since_boot: u64) -> Option<RecognizedGesture> {
+        let tap = self.pending_tap?;
+
+        if ms_since_boot.saturating_sub(tap.ms_since_boot) < self.config.double_tap_window_ms {
+            return None;
+        }
+
+        self.pending_tap = None;
+
+        Some(RecognizedGesture::Tap { x: tap.x, y: tap.y })
+    }
+
+    fn release(&mut self) -> Option<RecognizedGesture> {
+        let contact = self.contact.take()?;
+
+        if contact.long_press_reported {
+            return None;
+        }
+
+        if contact.dragging {
+            let duration = contact.last.ms_since_boot - contact.start.ms_since_boot;
+            let dx = contact.last.dx(&contact.start);
+            let dy = contact.last.dy(&contact.start);
+            let min = self.config.swipe_min_distance as u16;
+
+            if duration > self.config.swipe_max_ms
+                || (dx.unsigned_abs() < min && dy.unsigned_abs() < min)
+            {
+                return None;
+            }
+
+            let direction = if dx.unsigned_abs() >= dy.unsigned_abs() {
+                match dx > 0 {
+                    true => SwipeDirection::Right,
+                    false => SwipeDirection::Left,
+                }
+            } else {
+                match dy > 0 {
+                    true => SwipeDirection::Down,
+                    false => SwipeDirection::Up,
+                }
+            };
+
+            return Some(RecognizedGesture::Swipe {
+                direction,
+                start_x: contact.start.x,
+                start_y: contact.start.y,
+            });
+        }
+
+        let tap = ContactPoint {
+            x: contact.start.x,
+            y: contact.start.y,
+            ms_since_boot: contact.last.ms_since_boot,
+        };
+
+        match self.pending_tap.take() {
+            Some(previous)
+                if tap.ms_since_boot - previous.ms_since_boot
+                    < self.config.double_tap_window_ms
+                    && tap.distance(&previous) <= self.config.double_tap_max_distance =>
+            {
+                Some(RecognizedGesture::DoubleTap {
+                    x: previous.x,
+                    y: previous.y,
+                })
+            }
+            previous => {
+                if self.config.double_tap_window_ms == 0 {
+                    return Some(RecognizedGesture::Tap { x: tap.x, y: tap.y });
+                }
+
+                // If there was a pending tap which couldn't pair with this one,
+                // it is reported now and this tap waits for a partner instead.
+                self.pending_tap = Some(tap);
+                previous.map(|p| RecognizedGesture::Tap { x: p.x, y: p.y })
+            }
+        }
+    }
+}
+
+/// Decides which gestures to report from the touch controller's reports.
+///
+/// The controller reports taps as soon as they end, so those are left to it.
+/// From the recognizer, a tap is only reported once it can't become half of a
+/// double tap, and two quick taps become one double tap. Here a double tap is
+/// reported as well as the two taps.
+///
+/// Everything else comes from the recognizer, so the thresholds in
+/// `GestureConfig` apply. The controller's own swipes are dropped, and
+/// contacts are only reported as drags, once they have moved
+/// `drag_min_distance`.
+pub struct ControllerGestures {
+    recognizer: GestureRecognizer,
+}
+
+impl ControllerGestures {
+    pub fn new(config: GestureConfig) -> Self {
+        Self {
+            recognizer: GestureRecognizer::new(config),
+        }
+    }
+
+    /// Returns the touches to report for a report from the controller: its
+    /// tap, if it has one, then any gesture `touch` completes.
+    pub fn handle_report(
+        &mut self,
+        touch: &Touch,
+        ms_since_boot: u64,
+    ) -> impl Iterator<Item = Touch> {
+        let tap = matches!(touch.gesture, Gesture::SingleClick).then_some(Touch {
+            gesture: Gesture::SingleClick,
+            event_type: touch.event_type,
+            x: touch.x,
+            y: touch.y,
+        });
+        let recognized = self
+            .recognizer
+            .handle_touch(touch.event_type, touch.x, touch.y, ms_since_boot)
+            .and_then(Self::synthesized);
+
+        [tap, recognized].into_iter().flatten()
+    }
+
+    /// See `GestureRecognizer::poll`.
+    pub fn poll(&mut self, ms_since_boot: u64) -> Option<Touch> {
+        self.recognizer
+            .poll(ms_since_boot)
+            .and_then(Self::synthesized)
+    }
+
+    /// See `GestureRecognizer::next_deadline`.
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.recognizer.next_deadline()
+    }
+
+    fn synthesized(gesture: RecognizedGesture) -> Option<Touch> {
+        match gesture {
+            RecognizedGesture::Tap { .. } => None,
+            _ => Some(gesture.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{vec, vec::Vec};
+
+    use super::*;
+
+    /// Replays a synthetic trace of `(ms_since_boot, event_type, x, y)`
+    /// reports, polling every millisecond as the platform would, until
+    /// `end_ms`.
+    fn replay(
+        config: GestureConfig,
+        trace: &[(u64, TouchType, u8, u8)],
+        end_ms: u64,
+    ) -> Vec<(u64, RecognizedGesture)> {
+        let mut recognizer = GestureRecognizer::new(config);
+        let mut gestures = Vec::new();
+        let mut trace = trace.iter().peekable();
+
+        for ms in 0..=end_ms {
+            while let Some((_, event_type, x, y)) = trace.next_if(|(t, ..)| *t == ms) {
+                if let Some(g) = recognizer.handle_touch(*event_type, *x, *y, ms) {
+                    gestures.push((ms, g));
+                }
+            }
+            if recognizer
+                .next_deadline()
+                .is_some_and(|deadline| deadline <= ms)
+            {
+                if let Some(g) = recognizer.poll(ms) {
+                    gestures.push((ms, g));
+                }
+            }
+        }
+
+        gestures
+    }
+
+    /// A contact moving in a straight line, reported every 20ms without an Up.
+    fn line(
+        start_ms: u64,
+        from: (u8, u8),
+        to: (u8, u8),
+        steps: u8,
+    ) -> Vec<(u64, TouchType, u8, u8)> {
+        (0..=steps)
+            .map(|i| {
+                let lerp = |a: u8, b: u8| {
+                    (a as i16 + (b as i16 - a as i16) * i as i16 / steps as i16) as u8
+                };
+                (
+                    start_ms + i as u64 * 20,
+                    if i == 0 {
+                        TouchType::Down
+                    } else {
+                        TouchType::Contact
+                    },
+                    lerp(from.0, to.0),
+                    lerp(from.1, to.1),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tap_reported_after_double_tap_window() {
+        let config = GestureConfig::default();
+        let gestures = replay(config, &line(0, (100, 100), (102, 101), 2), 1000);
+
+        // The tap is considered to have ended at the last report, even
+        // though the release is only noticed after the timeout.
+        assert_eq!(
+            vec![(
+                40 + config.double_tap_window_ms,
+                RecognizedGesture::Tap { x: 100, y: 100 }
+            )],
+            gestures
+        );
+    }
+
+    #[test]
+    fn tap_reported_on_up_without_double_tap_window() {
+        let config = GestureConfig {
+            double_tap_window_ms: 0,
+            ..Default::default()
+        };
+        let gestures = replay(
+            config,
+            &[(0, TouchType::Down, 10, 20), (50, TouchType::Up, 10, 20)],
+            1000,
+        );
+
+        assert_eq!(
+            vec![(50, RecognizedGesture::Tap { x: 10, y: 20 })],
+            gestures
+        );
+    }
+
+    #[test]
+    fn double_tap() {
+        let trace = [
+            (0, TouchType::Down, 100, 100),
+            (40, TouchType::Up, 100, 100),
+            (150, TouchType::Down, 105, 98),
+            (190, TouchType::Up, 105, 98),
+        ];
+        let gestures = replay(GestureConfig::default(), &trace, 1000);
+
+        assert_eq!(
+            vec![(190, RecognizedGesture::DoubleTap { x: 100, y: 100 })],
+            gestures
+        );
+    }
+
+    #[test]
+    fn taps_too_far_apart_are_two_taps() {
+        let config = GestureConfig::default();
+        let trace = [
+            (0, TouchType::Down, 20, 20),
+            (40, TouchType::Up, 20, 20),
+            (150, TouchType::Down, 200, 200),
+            (190, TouchType::Up, 200, 200),
+        ];
+        let gestures = replay(config, &trace, 1000);
+
+        assert_eq!(
+            vec![
+                (190, RecognizedGesture::Tap { x: 20, y: 20 }),
+                (
+                    190 + config.double_tap_window_ms,
+                    RecognizedGesture::Tap { x: 200, y: 200 }
+                ),
+            ],
+            gestures
+        );
+    }
+
+    #[test]
+    fn long_press_while_held() {
+        let config = GestureConfig::default();
+        // Held still, with a little jitter, for a second.
+        let mut trace = line(0, (120, 120), (123, 118), 50);
+        trace.push((1020, TouchType::Up, 123, 118));
+        let gestures = replay(config, &trace, 2000);
+
+        assert_eq!(
+            vec![(
+                config.long_press_ms,
+                RecognizedGesture::LongPress { x: 120, y: 120 }
+            )],
+            gestures
+        );
+    }
+
+    #[test]
+    fn drag_reports_positions_then_swipe() {
+        let trace = line(0, (40, 120), (200, 110), 8);
+        let gestures = replay(GestureConfig::default(), &trace, 1000);
+
+        let (drags, rest): (Vec<&(u64, RecognizedGesture)>, Vec<_>) = gestures
+            .iter()
+            .partition(|(_, g)| matches!(g, RecognizedGesture::Drag { .. }));
+
+        // The first step of 20px crosses the drag threshold.
+        assert_eq!(8, drags.len());
+        assert_eq!(
+            (
+                160,
+                RecognizedGesture::Drag {
+                    start_x: 40,
+                    start_y: 120,
+                    x: 200,
+                    y: 110
+                }
+            ),
+            *drags[7]
+        );
+        assert_eq!(
+            vec![&(
+                160 + GestureConfig::default().release_timeout_ms,
+                RecognizedGesture::Swipe {
+                    direction: SwipeDirection::Right,
+                    start_x: 40,
+                    start_y: 120,
+                }
+            )],
+            rest
+        );
+    }
+
+    #[test]
+    fn swipe_directions_and_thresholds() {
+        let swipe = |config, from, to| {
+            let mut trace = line(0, from, to, 4);
+            trace.push((100, TouchType::Up, to.0, to.1));
+            replay(config, &trace, 1000)
+                .into_iter()
+                .find_map(|(_, g)| match g {
+                    RecognizedGesture::Swipe { direction, .. } => Some(direction),
+                    _ => None,
+                })
+        };
+        let config = GestureConfig::default();
+
+        assert_eq!(
+            Some(SwipeDirection::Up),
+            swipe(config, (120, 200), (110, 40))
+        );
+        assert_eq!(
+            Some(SwipeDirection::Down),
+            swipe(config, (120, 40), (130, 200))
+        );
+        assert_eq!(
+            Some(SwipeDirection::Left),
+            swipe(config, (200, 120), (40, 120))
+        );
+        assert_eq!(
+            Some(SwipeDirection::Right),
+            swipe(config, (40, 120), (200, 120))
+        );
+
+        // A short drag is not a swipe, unless the threshold is lowered.
+        assert_eq!(None, swipe(config, (100, 100), (130, 100)));
+        let sensitive = GestureConfig {
+            swipe_min_distance: 20,
+            ..config
+        };
+        assert_eq!(
+            Some(SwipeDirection::Right),
+            swipe(sensitive, (100, 100), (130, 100))
+        );
+    }
+
+    #[test]
+    fn slow_drag_is_not_a_swipe() {
+        let trace = line(0, (40, 120), (200, 120), 60);
+        let gestures = replay(GestureConfig::default(), &trace, 3000);
+
+        assert!(gestures
+            .iter()
+            .all(|(_, g)| matches!(g, RecognizedGesture::Drag { .. })));
+    }
+
+    /// Feeds `trace` to `ControllerGestures`, with the controller reporting
+    /// `controller_gesture` on the last report, and returns the gestures
+    /// reported.
+    fn controller_gestures(
+        config: GestureConfig,
+        trace: &[(u64, TouchType, u8, u8)],
+        controller_gesture: Gesture,
+    ) -> Vec<Gesture> {
+        let mut gestures = ControllerGestures::new(config);
+        let mut reported = Vec::new();
+        for (i, &(ms, event_type, x, y)) in trace.iter().enumerate() {
+            let touch = Touch {
+                gesture: match i == trace.len() - 1 {
+                    true => controller_gesture,
+                    false => Gesture::None,
+                },
+                event_type,
+                x,
+                y,
+            };
+            reported.extend(gestures.handle_report(&touch, ms).map(|t| t.gesture));
+        }
+        while let Some(deadline) = gestures.next_deadline() {
+            reported.extend(gestures.poll(deadline).map(|t| t.gesture));
+        }
+
+        reported
+    }
+
+    #[test]
+    fn controller_swipes_follow_thresholds() {
+        let trace = line(0, (100, 100), (130, 100), 3);
+        let config = GestureConfig::default();
+
+        // The controller calls this a swipe, but it is too short, so only
+        // the drag is reported.
+        let gestures = controller_gestures(config, &trace, Gesture::SlideRight);
+        assert!(!gestures.is_empty());
+        assert!(gestures.iter().all(|g| *g == Gesture::None));
+
+        let sensitive = GestureConfig {
+            swipe_min_distance: 20,
+            ..config
+        };
+        let gestures = controller_gestures(sensitive, &trace, Gesture::SlideRight);
+        assert_eq!(Some(&Gesture::SlideRight), gestures.last());
+        assert_eq!(
+            1,
+            gestures
+                .iter()
+                .filter(|g| **g == Gesture::SlideRight)
+                .count()
+        );
+    }
+
+    #[test]
+    fn controller_taps_reported_immediately() {
+        let trace = [(0, TouchType::Down, 50, 50), (40, TouchType::Up, 50, 50)];
+        let gestures = controller_gestures(GestureConfig::default(), &trace, Gesture::SingleClick);
+
+        assert_eq!(vec![Gesture::SingleClick], gestures);
+    }
+}
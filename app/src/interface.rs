@@ -1,23 +1,82 @@
+use crate::settings::Settings;
+
 pub type DisplayColor = embedded_graphics::pixelcolor::Rgb565;
 
 pub const LCD_W: u16 = 240;
 pub const LCD_H: u16 = 240;
 
+// AppleMedia is much larger than the other variants, but without an allocator we
+// cannot box it, so we accept the size difference.
+#[allow(clippy::large_enum_variant)]
 pub enum AppInput {
     AppleMedia(AppleMediaServiceData),
     Battery(BatteryData),
     Time(TimeOfDay),
     Touch(Touch),
     ButtonPressed,
-    /// The platform should provide this input at the rate requested by the app.
-    ///
-    /// TODO see future AppOutput::TickRate
-    /// app will request higher tick rate when the display/backlight is on, for example
+    /// The platform should provide this input at the rate requested by the app
+    /// in `PowerProfile::tick_interval_ms`.
     Tick,
+    /// Platform specific values shown in the diagnostics window. The platform
+    /// should provide this at boot, and whenever the values change.
+    Diagnostics(Diagnostics),
+    /// Settings saved after an earlier `AppOutput::SaveSettings`. The
+    /// platform should provide this at boot. Until then the app uses
+    /// `Settings::default`.
+    Settings(Settings),
 }
 
+#[derive(Debug, PartialEq, Eq)]
 pub enum AppOutput {
     MediaControl(MediaControl),
+    /// The battery percentage shown on the watch changed, for the platform to
+    /// report to a connected phone.
+    BatteryLevel(u8),
+    /// Run the vibration motor for the given duration.
+    Vibrate {
+        duration_ms: u32,
+    },
+    /// The platform should apply this profile until another is output. Until
+    /// then it should use `PowerProfile::NORMAL`.
+    PowerProfile(PowerProfile),
+    /// The settings changed, and the platform should store them to provide
+    /// with `AppInput::Settings` after a reboot.
+    SaveSettings(Settings),
+    /// The platform should forget every bonded phone, so that it has to pair
+    /// again.
+    ForgetBonds,
+}
+
+/// The most outputs a single call to `App::handle_event` can produce.
+pub const MAX_OUTPUTS_PER_EVENT: usize = 4;
+pub type AppOutputs = arrayvec::ArrayVec<AppOutput, MAX_OUTPUTS_PER_EVENT>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backlight {
+    Off,
+    Low,
+    Medium,
+    High,
+}
+
+/// Platform settings which trade off power use against responsiveness.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PowerProfile {
+    pub backlight: Backlight,
+    /// Interval between `AppInput::Tick` inputs.
+    pub tick_interval_ms: u32,
+    pub ble_advertising_interval_ms: u32,
+}
+
+impl PowerProfile {
+    pub const NORMAL: PowerProfile = PowerProfile {
+        backlight: Backlight::Medium,
+        // This value is turned up to allow watching maximum fps.
+        //
+        // Something like 10hz is probably more appropriate.
+        tick_interval_ms: 1000 / 60,
+        ble_advertising_interval_ms: 250,
+    };
 }
 
 #[derive(Clone)]
@@ -26,9 +85,10 @@ pub struct AppleMediaServiceData {
     pub album: AppleMediaServiceString,
     pub title: AppleMediaServiceString,
 }
-const ATT_PAYLOAD_MAX_LEN: usize = 512;
+pub(crate) const ATT_PAYLOAD_MAX_LEN: usize = 512;
 pub type AppleMediaServiceString = arrayvec::ArrayString<ATT_PAYLOAD_MAX_LEN>;
 
+#[derive(Debug, PartialEq, Eq)]
 pub enum MediaControl {
     TogglePlayPause,
     NextTrack,
@@ -37,12 +97,46 @@ pub enum MediaControl {
     VolumeDown,
 }
 
+#[derive(Clone, Copy)]
+pub struct Diagnostics {
+    pub firmware_version: FirmwareVersion,
+    pub ble: BleState,
+    pub display_writes: DisplayWrites,
+}
+pub type FirmwareVersion = arrayvec::ArrayString<16>;
+
+/// How the platform sends pixels to the display. It is shown next to the
+/// frame rate in the diagnostics window, so the two can be compared.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisplayWrites {
+    /// Only in recordings made before this was reported.
+    Unknown,
+    /// The simulator, which draws to a framebuffer in memory.
+    Simulator,
+    /// The CPU waits for each transfer to the display to finish.
+    Blocking,
+    /// Transfers run in the background while the app carries on drawing.
+    Dma,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BleState {
+    /// The platform doesn't support BLE, for example the simulator.
+    Unavailable,
+    Advertising,
+    Connected {
+        /// Most significant byte first, as addresses are usually written.
+        peer_address: [u8; 6],
+    },
+}
+
+#[derive(Clone, Copy)]
 pub struct BatteryData {
     pub charging: bool,
     pub voltage: f32,
 }
 
-#[derive(Default, Clone)]
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
 pub struct TimeOfDay {
     pub hours: u8,
     pub minutes: u8,
@@ -58,20 +152,25 @@ pub struct Touch {
     pub y: u8,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Gesture {
     SlideDown,
     SlideUp,
     SlideLeft,
     SlideRight,
     SingleClick,
-    /// I have not observed the hardware reporting this value
+    /// I have not observed the hardware reporting this value. The
+    /// `gesture::GestureRecognizer` reports drags with this value.
     None,
-    /// I have not observed the hardware reporting this value
+    /// I have not observed the hardware reporting this value, but it is
+    /// synthesized by `gesture::GestureRecognizer`.
     DoubleClick,
-    /// I have not observed the hardware reporting this value
+    /// I have not observed the hardware reporting this value, but it is
+    /// synthesized by `gesture::GestureRecognizer`.
     LongPress,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum TouchType {
     /// Represents a quick touch
     Down,
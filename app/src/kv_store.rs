@@ -0,0 +1,851 @@
+//! A key-value store in flash, for data the platform keeps across reboots.
+//!
+//! The store is a log of records spread over a region of two or more flash
+//! pages. Setting or removing a value appends a record to the newest page,
+//! and the last record for a key holds its value. Each page starts with a
+//! header:
+//!
+//! * a little endian sequence number, one more than the page before it
+//! * a little endian magic number, written after the sequence number, which
+//!   marks the page as in use
+//! * a second little endian magic number, which marks that copying from the
+//!   oldest page has finished, as described below
+//!
+//! followed by records, each starting on a word boundary:
+//!
+//! * a little endian key
+//! * the value's length
+//! * the kind of record, a value or a removal
+//! * the value, padded to a whole word with 0xFF
+//! * a little endian CRC-32 of the key, length, kind and value
+//!
+//! Pages are filled in turn, wrapping around at the end of the region, so
+//! they are erased equally often. One page is always kept spare: as soon as
+//! a new page is started, the values in the oldest page which haven't been
+//! replaced since are copied to the new page, and the oldest page is erased.
+//!
+//! Flash words are only written once between erases, and anything which
+//! could be left half written when power is lost is checked before it is
+//! trusted. A record whose checksum doesn't match was interrupted, so the
+//! previous value for its key is used instead, and a page without its magic
+//! number wasn't finished being started. Nothing is appended to a new page
+//! until copying has finished, and the oldest page is only erased after that,
+//! so interrupted copying is started again from scratch, on the erased new
+//! page, when the store is next mounted.
+
+use core::ops::Range;
+
+use embedded_storage_async::nor_flash::NorFlash;
+
+use crate::crc::Crc32;
+
+pub type Key = u16;
+
+/// The longest value a record's length can describe. Values must also fit
+/// in a page, with its header.
+pub const MAX_VALUE_LEN: usize = u8::MAX as usize;
+
+const WORD_LEN: u32 = 4;
+const PAGE_HEADER_LEN: u32 = 3 * WORD_LEN;
+const PAGE_MAGIC: u32 = 0x4D5A_4B56;
+const COPIED_MAGIC: u32 = 0x4350_5944;
+const RECORD_HEADER_LEN: u32 = WORD_LEN;
+const CHECKSUM_LEN: u32 = WORD_LEN;
+const ERASED: u8 = 0xFF;
+
+/// Neither kind is 0xFF, so an erased header is never a record.
+const KIND_VALUE: u8 = 0x01;
+const KIND_REMOVED: u8 = 0x00;
+
+/// The amount of flash read or written at once.
+const CHUNK_LEN: usize = 32;
+
+/// Some flash, such as the nRF52's, can only be written from word aligned
+/// buffers.
+#[repr(align(4))]
+struct Chunk([u8; CHUNK_LEN]);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error<E> {
+    Flash(E),
+    /// The region isn't at least two whole pages, or the flash can't be read
+    /// and written a word at a time.
+    InvalidRegion,
+    /// The value doesn't fit in a page.
+    ValueTooLong,
+    /// The buffer passed to `get` is shorter than the value.
+    BufferTooSmall,
+    /// The values stored don't fit in the region while keeping a page spare.
+    /// Removing a value takes space too, so regions should be much larger
+    /// than the values they hold.
+    Full,
+}
+
+pub struct KvStore<F> {
+    flash: F,
+    /// Where the region starts in the flash.
+    start: u32,
+    page_count: u32,
+    /// Unset until the first record is written.
+    newest: Option<NewestPage>,
+}
+
+#[derive(Clone, Copy)]
+struct NewestPage {
+    page: u32,
+    sequence: u32,
+    /// Where in the flash the next record will be written.
+    next_record: u32,
+}
+
+#[derive(Clone, Copy)]
+struct RecordHeader {
+    key: Key,
+    len: u8,
+    kind: u8,
+}
+
+impl RecordHeader {
+    fn to_bytes(self) -> [u8; RECORD_HEADER_LEN as usize] {
+        let [key_low, key_high] = self.key.to_le_bytes();
+        [key_low, key_high, self.len, self.kind]
+    }
+
+    fn record_len(self) -> u32 {
+        RECORD_HEADER_LEN + u32::from(self.len).next_multiple_of(WORD_LEN) + CHECKSUM_LEN
+    }
+}
+
+enum Slot {
+    /// A record, which is invalid if writing it was interrupted.
+    Record { header: RecordHeader, valid: bool },
+    /// Nothing has been written here since the page was erased.
+    Erased,
+    /// Something which isn't a record, so nothing after it can be trusted.
+    Corrupt,
+}
+
+/// A position in the log, visiting pages from oldest to newest.
+#[derive(Clone, Copy, Default)]
+struct Cursor {
+    /// Counts pages from the one after the newest page, which is the oldest
+    /// page if every page is in use.
+    index: u32,
+    /// The next record in the current page, or None if the page hasn't been
+    /// started yet.
+    address: Option<u32>,
+}
+
+impl<F: NorFlash> KvStore<F> {
+    /// Opens the store in `region` of `flash`, which must be a whole number
+    /// of pages. Erased flash is an empty store.
+    pub async fn mount(flash: F, region: Range<u32>) -> Result<Self, Error<F::Error>> {
+        let page_size = F::ERASE_SIZE as u32;
+        if !WORD_LEN.is_multiple_of(F::READ_SIZE as u32)
+            || !WORD_LEN.is_multiple_of(F::WRITE_SIZE as u32)
+            || !page_size.is_multiple_of(WORD_LEN)
+            || !region.start.is_multiple_of(page_size)
+            || !region.end.is_multiple_of(page_size)
+            || region.end < region.start + 2 * page_size
+        {
+            return Err(Error::InvalidRegion);
+        }
+
+        let mut store = Self {
+            flash,
+            start: region.start,
+            page_count: (region.end - region.start) / page_size,
+            newest: None,
+        };
+
+        let mut newest = None;
+        for page in 0..store.page_count {
+            if let Some(sequence) = store.page_sequence(page).await? {
+                if newest.is_none_or(|(_, newest)| sequence > newest) {
+                    newest = Some((page, sequence));
+                }
+            }
+        }
+        if let Some((page, sequence)) = newest {
+            store.newest = Some(NewestPage {
+                page,
+                sequence,
+                next_record: store.end_of_records(page).await?,
+            });
+        }
+
+        store.make_spare_page().await?;
+
+        Ok(store)
+    }
+
+    /// Copies the value for `key` into `buf`, returning the part of `buf`
+    /// it was copied into, or None if there is no value.
+    pub async fn get<'a>(
+        &mut self,
+        key: Key,
+        buf: &'a mut [u8],
+    ) -> Result<Option<&'a [u8]>, Error<F::Error>> {
+        let Some((address, header)) = self.latest(key).await? else {
+            return Ok(None);
+        };
+        if header.kind == KIND_REMOVED {
+            return Ok(None);
+        }
+
+        let value = buf
+            .get_mut(..header.len.into())
+            .ok_or(Error::BufferTooSmall)?;
+        self.read_bytes(address + RECORD_HEADER_LEN, value).await?;
+
+        Ok(Some(value))
+    }
+
+    pub async fn set(&mut self, key: Key, value: &[u8]) -> Result<(), Error<F::Error>> {
+        let len = u8::try_from(value.len()).map_err(|_| Error::ValueTooLong)?;
+        self.append(
+            RecordHeader {
+                key,
+                len,
+                kind: KIND_VALUE,
+            },
+            value,
+        )
+        .await
+    }
+
+    pub async fn remove(&mut self, key: Key) -> Result<(), Error<F::Error>> {
+        match self.latest(key).await? {
+            Some((_, header)) if header.kind == KIND_VALUE => {
+                self.append(
+                    RecordHeader {
+                        key,
+                        len: 0,
+                        kind: KIND_REMOVED,
+                    },
+                    &[],
+                )
+                .await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Gives the flash back, such as to mount the store again.
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    fn page_size(&self) -> u32 {
+        F::ERASE_SIZE as u32
+    }
+
+    fn page_start(&self, page: u32) -> u32 {
+        self.start + page * self.page_size()
+    }
+
+    fn page_end(&self, page: u32) -> u32 {
+        self.page_start(page) + self.page_size()
+    }
+
+    /// The page `index` pages after the newest, wrapping around the region.
+    fn page_after_newest(&self, index: u32) -> u32 {
+        let newest = self.newest.map_or(0, |newest| newest.page);
+        (newest + 1 + index) % self.page_count
+    }
+
+    /// The sequence number of a page which is in use.
+    async fn page_sequence(&mut self, page: u32) -> Result<Option<u32>, Error<F::Error>> {
+        let mut words = [[0; WORD_LEN as usize]; 2];
+        self.read(self.page_start(page), words.as_flattened_mut())
+            .await?;
+
+        let [sequence, magic] = words.map(u32::from_le_bytes);
+        Ok((magic == PAGE_MAGIC).then_some(sequence))
+    }
+
+    /// Where the next record in `page` can be written, which is the end of the
+    /// page if anything after the last record isn't erased.
+    async fn end_of_records(&mut self, page: u32) -> Result<u32, Error<F::Error>> {
+        let end = self.page_end(page);
+        let mut address = self.page_start(page) + PAGE_HEADER_LEN;
+        while address < end {
+            match self.slot(address, end).await? {
+                Slot::Record { header, .. } => address += header.record_len(),
+                Slot::Erased if self.is_erased(address..end).await? => return Ok(address),
+                Slot::Erased | Slot::Corrupt => break,
+            }
+        }
+
+        Ok(end)
+    }
+
+    async fn slot(&mut self, address: u32, page_end: u32) -> Result<Slot, Error<F::Error>> {
+        let mut bytes = [0; RECORD_HEADER_LEN as usize];
+        self.read(address, &mut bytes).await?;
+        if bytes == [ERASED; RECORD_HEADER_LEN as usize] {
+            return Ok(Slot::Erased);
+        }
+
+        let [key_low, key_high, len, kind] = bytes;
+        let header = RecordHeader {
+            key: Key::from_le_bytes([key_low, key_high]),
+            len,
+            kind,
+        };
+        let well_formed = match kind {
+            KIND_VALUE => true,
+            KIND_REMOVED => len == 0,
+            _ => false,
+        };
+        if !well_formed || address + header.record_len() > page_end {
+            return Ok(Slot::Corrupt);
+        }
+
+        let mut crc = Crc32::new();
+        crc.update(&bytes);
+        let mut chunk = Chunk([0; CHUNK_LEN]);
+        let value_len = u32::from(len);
+        for offset in (0..value_len).step_by(CHUNK_LEN) {
+            let bytes = &mut chunk.0[..(value_len - offset).min(CHUNK_LEN as u32) as usize];
+            self.read_bytes(address + RECORD_HEADER_LEN + offset, bytes)
+                .await?;
+            crc.update(bytes);
+        }
+
+        let mut checksum = [0; CHECKSUM_LEN as usize];
+        self.read(address + header.record_len() - CHECKSUM_LEN, &mut checksum)
+            .await?;
+        Ok(Slot::Record {
+            header,
+            valid: u32::from_le_bytes(checksum) == crc.finish(),
+        })
+    }
+
+    /// Moves `cursor` past the next valid record, returning the index of its
+    /// page, its address and its header.
+    async fn next_record(
+        &mut self,
+        cursor: &mut Cursor,
+    ) -> Result<Option<(u32, u32, RecordHeader)>, Error<F::Error>> {
+        while cursor.index < self.page_count && self.newest.is_some() {
+            let page = self.page_after_newest(cursor.index);
+            let end = self.page_end(page);
+            let address = match cursor.address {
+                Some(address) => address,
+                None if self.page_sequence(page).await?.is_some() => {
+                    self.page_start(page) + PAGE_HEADER_LEN
+                }
+                None => end,
+            };
+
+            if address < end {
+                if let Slot::Record { header, valid } = self.slot(address, end).await? {
+                    cursor.address = Some(address + header.record_len());
+                    if valid {
+                        return Ok(Some((cursor.index, address, header)));
+                    }
+                    continue;
+                }
+            }
+
+            cursor.index += 1;
+            cursor.address = None;
+        }
+
+        Ok(None)
+    }
+
+    /// The address and header of the last record for `key`.
+    async fn latest(&mut self, key: Key) -> Result<Option<(u32, RecordHeader)>, Error<F::Error>> {
+        self.latest_after(key, Cursor::default()).await
+    }
+
+    async fn latest_after(
+        &mut self,
+        key: Key,
+        mut cursor: Cursor,
+    ) -> Result<Option<(u32, RecordHeader)>, Error<F::Error>> {
+        let mut latest = None;
+        while let Some((_, address, header)) = self.next_record(&mut cursor).await? {
+            if header.key == key {
+                latest = Some((address, header));
+            }
+        }
+
+        Ok(latest)
+    }
+
+    async fn append(&mut self, header: RecordHeader, value: &[u8]) -> Result<(), Error<F::Error>> {
+        let len = header.record_len();
+        if PAGE_HEADER_LEN + len > self.page_size() {
+            return Err(Error::ValueTooLong);
+        }
+
+        // Each new page may be filled by values copied from the oldest page,
+        // so try each page in turn before giving up.
+        for _ in 0..self.page_count {
+            if let Some(newest) = self.newest {
+                let end = self.page_end(newest.page);
+                if newest.next_record + len <= end {
+                    let result = self.write_record(newest.next_record, header, value).await;
+                    // Whatever is after a failed write can't be trusted, so
+                    // the next record goes in a new page.
+                    self.advance(match result {
+                        Ok(()) => len,
+                        Err(_) => end - newest.next_record,
+                    });
+                    return result;
+                }
+            }
+
+            self.start_next_page().await?;
+        }
+
+        Err(Error::Full)
+    }
+
+    async fn write_record(
+        &mut self,
+        address: u32,
+        header: RecordHeader,
+        value: &[u8],
+    ) -> Result<(), Error<F::Error>> {
+        let mut crc = Crc32::new();
+        crc.update(&header.to_bytes());
+        crc.update(value);
+
+        let bytes = header.to_bytes().into_iter().chain(value.iter().copied());
+        self.write_bytes(address, bytes).await?;
+        // The checksum is written last, so it only matches once the rest of
+        // the record has been written.
+        self.write_bytes(
+            address + header.record_len() - CHECKSUM_LEN,
+            crc.finish().to_le_bytes().into_iter(),
+        )
+        .await
+    }
+
+    fn advance(&mut self, len: u32) {
+        if let Some(newest) = &mut self.newest {
+            newest.next_record += len;
+        }
+    }
+
+    async fn start_next_page(&mut self) -> Result<(), Error<F::Error>> {
+        // In case an error stopped the last page being started.
+        self.make_spare_page().await?;
+
+        let (page, sequence) = match self.newest {
+            Some(newest) => ((newest.page + 1) % self.page_count, newest.sequence + 1),
+            None => (0, 0),
+        };
+        let start = self.page_start(page);
+        if !self.is_erased(start..self.page_end(page)).await? {
+            self.erase(start..self.page_end(page)).await?;
+        }
+        self.write_page_header(page, sequence).await?;
+        self.newest = Some(NewestPage {
+            page,
+            sequence,
+            next_record: start + PAGE_HEADER_LEN,
+        });
+
+        self.make_spare_page().await
+    }
+
+    async fn write_page_header(&mut self, page: u32, sequence: u32) -> Result<(), Error<F::Error>> {
+        let start = self.page_start(page);
+        // The magic number marks the page as in use, so it is written last.
+        self.write_bytes(start, sequence.to_le_bytes().into_iter())
+            .await?;
+        self.write_bytes(start + WORD_LEN, PAGE_MAGIC.to_le_bytes().into_iter())
+            .await
+    }
+
+    /// Finishes starting the newest page. If every page is in use, the
+    /// values in the oldest page which haven't been replaced are copied to
+    /// the newest page, and then the oldest page is erased.
+    async fn make_spare_page(&mut self) -> Result<(), Error<F::Error>> {
+        let Some(newest) = self.newest else {
+            return Ok(());
+        };
+        let oldest = (newest.page + 1) % self.page_count;
+        let start = self.page_start(newest.page);
+        let copied_address = start + 2 * WORD_LEN;
+
+        let mut copied = [0; WORD_LEN as usize];
+        self.read(copied_address, &mut copied).await?;
+        if u32::from_le_bytes(copied) != COPIED_MAGIC {
+            // Nothing is appended until copying has finished, and the oldest
+            // page is only erased after that, so if copying was interrupted
+            // it can be started again from scratch.
+            if !self
+                .is_erased(copied_address..self.page_end(newest.page))
+                .await?
+            {
+                self.erase(start..self.page_end(newest.page)).await?;
+                self.write_page_header(newest.page, newest.sequence).await?;
+                self.newest = Some(NewestPage {
+                    next_record: start + PAGE_HEADER_LEN,
+                    ..newest
+                });
+            }
+
+            if self.page_sequence(oldest).await?.is_some() {
+                let mut cursor = Cursor::default();
+                while let Some((0, address, header)) = self.next_record(&mut cursor).await? {
+                    // Nothing is older than the oldest page, so removals in it
+                    // have nothing left to remove.
+                    if header.kind == KIND_REMOVED
+                        || self.latest_after(header.key, cursor).await?.is_some()
+                    {
+                        continue;
+                    }
+                    self.copy_record(address, header).await?;
+                }
+            }
+            self.write_bytes(copied_address, COPIED_MAGIC.to_le_bytes().into_iter())
+                .await?;
+        }
+
+        if self.page_sequence(oldest).await?.is_some() {
+            let start = self.page_start(oldest);
+            self.erase(start..self.page_end(oldest)).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn copy_record(
+        &mut self,
+        from: u32,
+        header: RecordHeader,
+    ) -> Result<(), Error<F::Error>> {
+        let Some(newest) = self.newest else {
+            return Ok(());
+        };
+        let len = header.record_len();
+        if newest.next_record + len > self.page_end(newest.page) {
+            return Err(Error::Full);
+        }
+
+        let to = newest.next_record;
+        let checksum_offset = len - CHECKSUM_LEN;
+        let mut chunk = Chunk([0; CHUNK_LEN]);
+        for offset in (0..checksum_offset).step_by(CHUNK_LEN) {
+            let bytes = &mut chunk.0[..(checksum_offset - offset).min(CHUNK_LEN as u32) as usize];
+            self.read(from + offset, bytes).await?;
+            self.write(to + offset, bytes).await?;
+        }
+        // As when appending, the checksum is written last.
+        let checksum = &mut chunk.0[..CHECKSUM_LEN as usize];
+        self.read(from + checksum_offset, checksum).await?;
+        self.write(to + checksum_offset, checksum).await?;
+
+        self.advance(len);
+        Ok(())
+    }
+
+    async fn is_erased(&mut self, range: Range<u32>) -> Result<bool, Error<F::Error>> {
+        let mut chunk = Chunk([0; CHUNK_LEN]);
+        for start in range.clone().step_by(CHUNK_LEN) {
+            let bytes = &mut chunk.0[..(range.end - start).min(CHUNK_LEN as u32) as usize];
+            self.read(start, bytes).await?;
+            if bytes.iter().any(|&byte| byte != ERASED) {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Reads bytes which may not be a whole number of words.
+    async fn read_bytes(&mut self, address: u32, buf: &mut [u8]) -> Result<(), Error<F::Error>> {
+        let mut chunk = Chunk([0; CHUNK_LEN]);
+        for (i, part) in buf.chunks_mut(CHUNK_LEN).enumerate() {
+            let words = &mut chunk.0[..part.len().next_multiple_of(WORD_LEN as usize)];
+            self.read(address + (i * CHUNK_LEN) as u32, words).await?;
+            part.copy_from_slice(&words[..part.len()]);
+        }
+
+        Ok(())
+    }
+
+    /// Writes `bytes`, padded to a whole number of words.
+    async fn write_bytes(
+        &mut self,
+        address: u32,
+        mut bytes: impl Iterator<Item = u8>,
+    ) -> Result<(), Error<F::Error>> {
+        let mut chunk = Chunk([0; CHUNK_LEN]);
+        let mut offset = address;
+        loop {
+            let mut len: usize = 0;
+            for (byte, value) in chunk.0.iter_mut().zip(&mut bytes) {
+                *byte = value;
+                len += 1;
+            }
+            if len == 0 {
+                return Ok(());
+            }
+
+            let padded = len.next_multiple_of(WORD_LEN as usize);
+            chunk.0[len..padded].fill(ERASED);
+            self.write(offset, &chunk.0[..padded]).await?;
+            offset += padded as u32;
+        }
+    }
+
+    async fn read(&mut self, address: u32, bytes: &mut [u8]) -> Result<(), Error<F::Error>> {
+        self.flash.read(address, bytes).await.map_err(Error::Flash)
+    }
+
+    async fn write(&mut self, address: u32, bytes: &[u8]) -> Result<(), Error<F::Error>> {
+        self.flash.write(address, bytes).await.map_err(Error::Flash)
+    }
+
+    async fn erase(&mut self, range: Range<u32>) -> Result<(), Error<F::Error>> {
+        self.flash
+            .erase(range.start, range.end)
+            .await
+            .map_err(Error::Flash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{collections::BTreeMap, vec::Vec};
+
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::ram_flash::{RamFlash, RamFlashError};
+
+    /// Small pages, so that a few values fill them.
+    type SmallFlash = RamFlash<128, 3>;
+    const SMALL_REGION: Range<u32> = 0..3 * 128;
+
+    fn mount<F: NorFlash>(flash: F, region: Range<u32>) -> KvStore<F> {
+        block_on(KvStore::mount(flash, region)).unwrap()
+    }
+
+    fn get<F: NorFlash>(store: &mut KvStore<F>, key: Key) -> Option<Vec<u8>>
+    where
+        F::Error: core::fmt::Debug,
+    {
+        let mut buf = [0; MAX_VALUE_LEN];
+        block_on(store.get(key, &mut buf))
+            .unwrap()
+            .map(<[u8]>::to_vec)
+    }
+
+    /// A value for each step of a test, which differs in length and content.
+    fn value(step: usize) -> Vec<u8> {
+        (0..step % 13).map(|i| (step + i) as u8).collect()
+    }
+
+    #[test]
+    fn set_get_and_remove() {
+        let mut store = mount(RamFlash::<4096, 4>::new(), 0..4 * 4096);
+        assert_eq!(None, get(&mut store, 1));
+
+        block_on(store.set(1, b"one")).unwrap();
+        block_on(store.set(2, b"two")).unwrap();
+        block_on(store.set(1, b"uno")).unwrap();
+        block_on(store.set(3, &[])).unwrap();
+        assert_eq!(Some(b"uno".to_vec()), get(&mut store, 1));
+        assert_eq!(Some(b"two".to_vec()), get(&mut store, 2));
+        assert_eq!(Some(Vec::new()), get(&mut store, 3));
+
+        block_on(store.remove(2)).unwrap();
+        block_on(store.remove(4)).unwrap();
+        assert_eq!(None, get(&mut store, 2));
+
+        let mut buf = [0; 2];
+        assert_eq!(Err(Error::BufferTooSmall), block_on(store.get(1, &mut buf)));
+
+        let mut store = mount(store.into_inner(), 0..4 * 4096);
+        assert_eq!(Some(b"uno".to_vec()), get(&mut store, 1));
+        assert_eq!(None, get(&mut store, 2));
+        assert_eq!(Some(Vec::new()), get(&mut store, 3));
+    }
+
+    #[test]
+    fn rejects_invalid_regions_and_values() {
+        for region in [0..4096, 0..4096 + 128, 128..2 * 4096 + 128] {
+            assert!(matches!(
+                block_on(KvStore::mount(RamFlash::<4096, 4>::new(), region)),
+                Err(Error::InvalidRegion)
+            ));
+        }
+
+        let mut store = mount(SmallFlash::new(), SMALL_REGION);
+        let too_long = [0; 128 - PAGE_HEADER_LEN as usize - 7];
+        assert_eq!(Err(Error::ValueTooLong), block_on(store.set(1, &too_long)));
+        assert_eq!(
+            Err(Error::ValueTooLong),
+            block_on(store.set(1, &[0; MAX_VALUE_LEN + 1]))
+        );
+    }
+
+    #[test]
+    fn values_survive_compaction() {
+        let mut store = mount(SmallFlash::new(), SMALL_REGION);
+        let mut expected = BTreeMap::new();
+
+        // Written once, so it has to be copied from page to page.
+        block_on(store.set(5, b"once")).unwrap();
+        expected.insert(5, b"once".to_vec());
+        for step in 0..500 {
+            let key = (step % 5) as Key;
+            if step % 7 == 0 {
+                block_on(store.remove(key)).unwrap();
+                expected.remove(&key);
+            } else {
+                block_on(store.set(key, &value(step))).unwrap();
+                expected.insert(key, value(step));
+            }
+        }
+
+        let mut store = mount(store.into_inner(), SMALL_REGION);
+        for key in 0..6 {
+            assert_eq!(expected.get(&key).cloned(), get(&mut store, key));
+        }
+
+        // Every page is erased about as often as the others.
+        let erase_counts = store.into_inner().erase_counts().to_vec();
+        let (min, max) = (erase_counts.iter().min(), erase_counts.iter().max());
+        assert!(max.unwrap() - min.unwrap() <= 1, "{erase_counts:?}");
+        assert!(*min.unwrap() > 10, "{erase_counts:?}");
+    }
+
+    #[test]
+    fn full() {
+        let mut store = mount(SmallFlash::new(), SMALL_REGION);
+        let mut key = 0;
+        let error = loop {
+            match block_on(store.set(key, &[key as u8; 8])) {
+                Ok(()) => key += 1,
+                Err(error) => break error,
+            }
+        };
+        assert_eq!(Error::Full, error);
+        // Two pages of 8 byte values, less room for copying the last one.
+        assert!(key > 10, "{key}");
+
+        let mut store = mount(store.into_inner(), SMALL_REGION);
+        for key in 0..key {
+            assert_eq!(Some(std::vec![key as u8; 8]), get(&mut store, key));
+        }
+    }
+
+    #[test]
+    fn ignores_data_which_isnt_a_store() {
+        let mut flash = SmallFlash::new();
+        block_on(flash.write(0, &[1, 8, 2, 0, 15, 0x12, 0x34, 0x56])).unwrap();
+        block_on(flash.write(128, &PAGE_MAGIC.to_le_bytes())).unwrap();
+
+        let mut store = mount(flash, SMALL_REGION);
+        assert_eq!(None, get(&mut store, 1));
+        block_on(store.set(1, b"one")).unwrap();
+
+        let mut store = mount(store.into_inner(), SMALL_REGION);
+        assert_eq!(Some(b"one".to_vec()), get(&mut store, 1));
+    }
+
+    enum Operation {
+        Set(Key, Vec<u8>),
+        Remove(Key),
+    }
+
+    /// Enough operations to fill every page a couple of times, with one
+    /// value which is never replaced.
+    fn operations() -> Vec<Operation> {
+        (0..40)
+            .map(|step| match step % 6 {
+                0 if step == 0 => Operation::Set(3, b"once".to_vec()),
+                5 => Operation::Remove((step % 3) as Key),
+                _ => Operation::Set((step % 3) as Key, value(step)),
+            })
+            .collect()
+    }
+
+    /// Runs `operations` until power is lost, returning how many finished.
+    fn run(store: &mut KvStore<&mut SmallFlash>, operations: &[Operation]) -> usize {
+        for (i, operation) in operations.iter().enumerate() {
+            let result = match operation {
+                Operation::Set(key, value) => block_on(store.set(*key, value)),
+                Operation::Remove(key) => block_on(store.remove(*key)),
+            };
+            match result {
+                Ok(()) => {}
+                Err(Error::Flash(RamFlashError::PowerLoss)) => return i,
+                Err(e) => panic!("operation {i} failed: {e:?}"),
+            }
+        }
+
+        operations.len()
+    }
+
+    fn apply(expected: &mut BTreeMap<Key, Vec<u8>>, operation: &Operation) {
+        match operation {
+            Operation::Set(key, value) => expected.insert(*key, value.clone()),
+            Operation::Remove(key) => expected.remove(key),
+        };
+    }
+
+    #[test]
+    fn survives_power_loss() {
+        let operations = operations();
+
+        // Power is lost at every possible point, including while mounting
+        // after an earlier loss.
+        for lose_power_after in 0.. {
+            let mut flash = SmallFlash::new();
+            flash.lose_power_after(lose_power_after);
+            let finished = match block_on(KvStore::mount(&mut flash, SMALL_REGION)) {
+                Ok(mut store) => run(&mut store, &operations),
+                Err(Error::Flash(RamFlashError::PowerLoss)) => 0,
+                Err(e) => panic!("mount failed: {e:?}"),
+            };
+            if finished == operations.len() {
+                break;
+            }
+            flash.restore_power();
+            flash.lose_power_after(lose_power_after % 7);
+            let _ = block_on(KvStore::mount(&mut flash, SMALL_REGION));
+            flash.restore_power();
+
+            let mut before = BTreeMap::new();
+            operations[..finished]
+                .iter()
+                .for_each(|operation| apply(&mut before, operation));
+            let mut after = before.clone();
+            apply(&mut after, &operations[finished]);
+
+            // The interrupted operation either happened or didn't, and
+            // nothing else changed.
+            let mut store = mount(&mut flash, SMALL_REGION);
+            let stored: BTreeMap<_, _> = (0..4)
+                .filter_map(|key| Some((key, get(&mut store, key)?)))
+                .collect();
+            assert!(
+                stored == before || stored == after,
+                "power lost after {lose_power_after}: {stored:?}"
+            );
+
+            // And the store can still be written.
+            run(&mut store, &operations[finished..]);
+            let mut expected = before;
+            operations[finished..]
+                .iter()
+                .for_each(|operation| apply(&mut expected, operation));
+            for key in 0..4 {
+                assert_eq!(expected.get(&key).cloned(), get(&mut store, key));
+            }
+        }
+    }
+}
@@ -1,10 +1,22 @@
 #![no_std]
 
 mod app;
+mod crc;
+mod diagnostics;
 mod display;
+mod power;
 
-pub use app::App;
+pub use app::{ActiveWindow, App};
+pub mod battery;
+pub mod frame_diff;
+pub mod gesture;
 pub mod interface;
+pub mod kv_store;
+pub mod ram_flash;
+pub mod recording;
+pub mod render_cost;
+pub mod settings;
+pub mod widget;
 
 #[cfg(test)]
 mod test_infra;
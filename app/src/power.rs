@@ -0,0 +1,122 @@
+//! Low battery warnings, power saving, and the screen timeout.
+
+use crate::{
+    battery::BatteryLevel,
+    interface::{Backlight, PowerProfile},
+    settings::Settings,
+};
+
+/// Below this level the user is warned, once per discharge.
+pub(crate) const LOW_BATTERY_PERCENT: u8 = 20;
+/// Below this level the watch enters power saving mode until charging starts.
+pub(crate) const CRITICAL_BATTERY_PERCENT: u8 = 10;
+pub(crate) const LOW_BATTERY_VIBRATION_MS: u32 = 400;
+
+const POWER_SAVING_SCREEN_TIMEOUT_MS: u64 = 5_000;
+
+const POWER_SAVING: PowerProfile = PowerProfile {
+    backlight: Backlight::Low,
+    tick_interval_ms: 500,
+    ble_advertising_interval_ms: 1_000,
+};
+
+/// While the screen is off there is nothing to animate, and the seconds
+/// displayed can't be seen.
+const SCREEN_OFF: PowerProfile = PowerProfile {
+    backlight: Backlight::Off,
+    tick_interval_ms: 1_000,
+    ble_advertising_interval_ms: PowerProfile::NORMAL.ble_advertising_interval_ms,
+};
+
+const POWER_SAVING_SCREEN_OFF: PowerProfile = PowerProfile {
+    backlight: Backlight::Off,
+    ..POWER_SAVING
+};
+
+pub(crate) struct PowerState {
+    power_saving: bool,
+    low_battery_warned: bool,
+    screen_on: bool,
+    last_interaction_ms_since_boot: u64,
+    /// From the settings, used outside of power saving.
+    brightness: Backlight,
+    screen_timeout_ms: u64,
+}
+
+impl PowerState {
+    pub(crate) fn new(ms_since_boot: u64, settings: &Settings) -> Self {
+        Self {
+            power_saving: false,
+            low_battery_warned: false,
+            screen_on: true,
+            last_interaction_ms_since_boot: ms_since_boot,
+            brightness: settings.brightness.into(),
+            screen_timeout_ms: u64::from(settings.screen_timeout_s) * 1000,
+        }
+    }
+
+    pub(crate) fn apply_settings(&mut self, settings: &Settings) {
+        self.brightness = settings.brightness.into();
+        self.screen_timeout_ms = u64::from(settings.screen_timeout_s) * 1000;
+    }
+
+    /// Returns true if the low battery warning should be shown.
+    pub(crate) fn handle_battery(&mut self, level: BatteryLevel) -> bool {
+        if level.charging {
+            self.power_saving = false;
+            self.low_battery_warned = false;
+
+            return false;
+        }
+
+        if level.percent <= CRITICAL_BATTERY_PERCENT {
+            self.power_saving = true;
+        }
+
+        if level.percent <= LOW_BATTERY_PERCENT && !self.low_battery_warned {
+            self.low_battery_warned = true;
+
+            return true;
+        }
+
+        false
+    }
+
+    /// Records user interaction, turning the screen on.
+    ///
+    /// Returns true if the screen was off, in which case the interaction
+    /// should only wake the screen and not be acted on otherwise.
+    pub(crate) fn handle_interaction(&mut self, ms_since_boot: u64) -> bool {
+        self.last_interaction_ms_since_boot = ms_since_boot;
+
+        let was_off = !self.screen_on;
+        self.screen_on = true;
+
+        was_off
+    }
+
+    /// Turns the screen off if there hasn't been any interaction within the
+    /// screen timeout.
+    pub(crate) fn handle_time(&mut self, ms_since_boot: u64) {
+        let timeout = match self.power_saving {
+            true => POWER_SAVING_SCREEN_TIMEOUT_MS,
+            false => self.screen_timeout_ms,
+        };
+
+        if ms_since_boot.saturating_sub(self.last_interaction_ms_since_boot) >= timeout {
+            self.screen_on = false;
+        }
+    }
+
+    pub(crate) fn profile(&self) -> PowerProfile {
+        match (self.power_saving, self.screen_on) {
+            (false, true) => PowerProfile {
+                backlight: self.brightness,
+                ..PowerProfile::NORMAL
+            },
+            (false, false) => SCREEN_OFF,
+            (true, true) => POWER_SAVING,
+            (true, false) => POWER_SAVING_SCREEN_OFF,
+        }
+    }
+}
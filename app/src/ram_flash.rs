@@ -0,0 +1,200 @@
+//! Flash kept in RAM, to test code which uses flash on the host.
+//!
+//! `RamFlash` follows the rules of NOR flash as strictly as the nRF52's
+//! internal flash does: words must be erased before they are written, and
+//! only written once. It can also lose power part way through a write or
+//! erase, leaving the flash as a power cut on the watch might, so that
+//! recovery can be tested.
+
+use embedded_storage_async::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+const ERASED: u8 = 0xFF;
+const WORD_SIZE: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RamFlashError {
+    OutOfBounds,
+    NotAligned,
+    /// A word was written without being erased since it was last written.
+    NotErased,
+    /// Power was lost, see `RamFlash::lose_power_after`.
+    PowerLoss,
+}
+
+impl NorFlashError for RamFlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Self::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            Self::NotAligned => NorFlashErrorKind::NotAligned,
+            Self::NotErased | Self::PowerLoss => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+/// `PAGES` erasable pages of `PAGE_SIZE` bytes each, written a word at a
+/// time.
+pub struct RamFlash<const PAGE_SIZE: usize, const PAGES: usize> {
+    pages: [[u8; PAGE_SIZE]; PAGES],
+    /// Which words have been written since their page was erased.
+    written: [[bool; PAGE_SIZE]; PAGES],
+    erase_counts: [u32; PAGES],
+    /// How many more words can be written or pages erased before power is
+    /// lost, if power is going to be lost.
+    operations_until_power_loss: Option<usize>,
+    powered: bool,
+}
+
+impl<const PAGE_SIZE: usize, const PAGES: usize> Default for RamFlash<PAGE_SIZE, PAGES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const PAGE_SIZE: usize, const PAGES: usize> RamFlash<PAGE_SIZE, PAGES> {
+    /// Flash which has been erased, as it is when new.
+    pub fn new() -> Self {
+        Self {
+            pages: [[ERASED; PAGE_SIZE]; PAGES],
+            written: [[false; PAGE_SIZE]; PAGES],
+            erase_counts: [0; PAGES],
+            operations_until_power_loss: None,
+            powered: true,
+        }
+    }
+
+    /// Loses power after `operations` more words have been written or pages
+    /// erased. The operation after that is left half done, and it and every
+    /// later one fails with `RamFlashError::PowerLoss` until
+    /// `restore_power` is called.
+    pub fn lose_power_after(&mut self, operations: usize) {
+        self.operations_until_power_loss = Some(operations);
+    }
+
+    pub fn restore_power(&mut self) {
+        self.operations_until_power_loss = None;
+        self.powered = true;
+    }
+
+    /// How many times each page has been erased.
+    pub fn erase_counts(&self) -> &[u32; PAGES] {
+        &self.erase_counts
+    }
+
+    /// Counts down to the power loss, returning false if the operation
+    /// shouldn't be completed.
+    fn use_power(&mut self) -> bool {
+        match &mut self.operations_until_power_loss {
+            Some(0) => {
+                self.powered = false;
+                false
+            }
+            Some(operations) => {
+                *operations -= 1;
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn locate(&self, offset: u32, len: usize) -> Result<(usize, usize), RamFlashError> {
+        let offset = offset as usize;
+        if offset + len > PAGE_SIZE * PAGES {
+            return Err(RamFlashError::OutOfBounds);
+        }
+        Ok((offset / PAGE_SIZE, offset % PAGE_SIZE))
+    }
+}
+
+impl<const PAGE_SIZE: usize, const PAGES: usize> ErrorType for RamFlash<PAGE_SIZE, PAGES> {
+    type Error = RamFlashError;
+}
+
+impl<const PAGE_SIZE: usize, const PAGES: usize> ReadNorFlash for RamFlash<PAGE_SIZE, PAGES> {
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        if !self.powered {
+            return Err(RamFlashError::PowerLoss);
+        }
+        self.locate(offset, bytes.len())?;
+
+        let offset = offset as usize;
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let address = offset + i;
+            *byte = self.pages[address / PAGE_SIZE][address % PAGE_SIZE];
+        }
+
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        PAGE_SIZE * PAGES
+    }
+}
+
+impl<const PAGE_SIZE: usize, const PAGES: usize> NorFlash for RamFlash<PAGE_SIZE, PAGES> {
+    const WRITE_SIZE: usize = WORD_SIZE;
+    const ERASE_SIZE: usize = PAGE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        if !self.powered {
+            return Err(RamFlashError::PowerLoss);
+        }
+        if from > to {
+            return Err(RamFlashError::OutOfBounds);
+        }
+        let (first, from_offset) = self.locate(from, (to - from) as usize)?;
+        if from_offset != 0 || !(to as usize).is_multiple_of(PAGE_SIZE) {
+            return Err(RamFlashError::NotAligned);
+        }
+
+        for page in first..to as usize / PAGE_SIZE {
+            if !self.use_power() {
+                // Contents are undefined after an interrupted erase. Leaving
+                // the start of the page alone keeps it looking like it did
+                // before, which is the hardest case to recover from.
+                self.pages[page][PAGE_SIZE / 2..].fill(ERASED);
+                return Err(RamFlashError::PowerLoss);
+            }
+            self.pages[page] = [ERASED; PAGE_SIZE];
+            self.written[page] = [false; PAGE_SIZE];
+            self.erase_counts[page] += 1;
+        }
+
+        Ok(())
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        if !self.powered {
+            return Err(RamFlashError::PowerLoss);
+        }
+        self.locate(offset, bytes.len())?;
+        if !(offset as usize).is_multiple_of(WORD_SIZE) || !bytes.len().is_multiple_of(WORD_SIZE) {
+            return Err(RamFlashError::NotAligned);
+        }
+
+        for (i, word) in bytes.chunks_exact(WORD_SIZE).enumerate() {
+            let address = offset as usize + i * WORD_SIZE;
+            let (page, start) = (address / PAGE_SIZE, address % PAGE_SIZE);
+            if self.written[page][start] {
+                return Err(RamFlashError::NotErased);
+            }
+
+            let powered = self.use_power();
+            let current = &mut self.pages[page][start..start + WORD_SIZE];
+            for (current, new) in current.iter_mut().zip(word) {
+                // Programming can only clear bits. Without power, only some
+                // of them are cleared.
+                *current &= if powered { *new } else { *new | 0xF0 };
+            }
+            self.written[page][start] = true;
+            if !powered {
+                return Err(RamFlashError::PowerLoss);
+            }
+        }
+
+        Ok(())
+    }
+}
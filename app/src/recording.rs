@@ -0,0 +1,600 @@
+//! Recording and replay of the inputs passed to `App`, so a bug seen on the
+//! watch or in the simulator can be reproduced.
+//!
+//! A recording is a sequence of entries. Each entry is a little endian `u16`
+//! length, followed by that many bytes holding:
+//!
+//! * `ms_since_boot` as an unsigned LEB128 varint
+//! * a tag byte for the entry type
+//! * the payload for that entry type
+//!
+//! Any run of complete entries is a valid recording, so the contents of a
+//! `RingRecorder` can be written out as is. Decoders skip any bytes left over
+//! at the end of an entry, which leaves room to add fields later.
+
+use arrayvec::{ArrayString, ArrayVec};
+use embedded_graphics::draw_target::DrawTarget;
+
+use crate::{
+    interface::{
+        AppInput, AppleMediaServiceData, BatteryData, BleState, Diagnostics, DisplayColor,
+        DisplayWrites, FirmwareVersion, Gesture, TimeOfDay, Touch, TouchType, ATT_PAYLOAD_MAX_LEN,
+    },
+    settings::Settings,
+    App,
+};
+
+const TAG_INIT: u8 = 0;
+const TAG_APPLE_MEDIA: u8 = 1;
+const TAG_BATTERY: u8 = 2;
+const TAG_TIME: u8 = 3;
+const TAG_TOUCH: u8 = 4;
+const TAG_BUTTON_PRESSED: u8 = 5;
+const TAG_TICK: u8 = 6;
+const TAG_DIAGNOSTICS: u8 = 7;
+const TAG_SETTINGS: u8 = 8;
+
+const MAX_VARINT_LEN: usize = 10;
+
+/// The longest possible entry, which is `AppInput::AppleMedia` with every
+/// string full.
+pub const MAX_ENCODED_LEN: usize = 2 + MAX_VARINT_LEN + 1 + 3 * (2 + ATT_PAYLOAD_MAX_LEN);
+
+pub type EncodedEntry = ArrayVec<u8, MAX_ENCODED_LEN>;
+
+// Input is much larger than Init, but without an allocator we cannot box it,
+// so we accept the size difference.
+#[allow(clippy::large_enum_variant)]
+pub enum Entry {
+    /// `App::init` was called.
+    Init { ms_since_boot: u64 },
+    /// `App::handle_event` was called.
+    Input { ms_since_boot: u64, input: AppInput },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The recording ends part way through an entry.
+    UnexpectedEnd,
+    UnknownTag(u8),
+    /// A field holds a value which can't be represented, for example an
+    /// unknown gesture or a string which isn't valid UTF-8.
+    InvalidValue,
+}
+
+#[derive(Debug)]
+pub enum ReplayError<E> {
+    Decode(DecodeError),
+    Display(E),
+}
+
+pub fn encode_init(ms_since_boot: u64) -> EncodedEntry {
+    Writer::new(ms_since_boot, TAG_INIT).finish()
+}
+
+pub fn encode_input(ms_since_boot: u64, input: &AppInput) -> EncodedEntry {
+    let tag = match input {
+        AppInput::AppleMedia(_) => TAG_APPLE_MEDIA,
+        AppInput::Battery(_) => TAG_BATTERY,
+        AppInput::Time(_) => TAG_TIME,
+        AppInput::Touch(_) => TAG_TOUCH,
+        AppInput::ButtonPressed => TAG_BUTTON_PRESSED,
+        AppInput::Tick => TAG_TICK,
+        AppInput::Diagnostics(_) => TAG_DIAGNOSTICS,
+        AppInput::Settings(_) => TAG_SETTINGS,
+    };
+    let mut writer = Writer::new(ms_since_boot, tag);
+
+    match input {
+        AppInput::AppleMedia(media) => {
+            writer.str16(&media.artist);
+            writer.str16(&media.album);
+            writer.str16(&media.title);
+        }
+        AppInput::Battery(battery) => {
+            writer.u8(battery.charging.into());
+            writer.bytes(&battery.voltage.to_le_bytes());
+        }
+        AppInput::Time(time) => {
+            writer.bytes(&[time.hours, time.minutes, time.seconds]);
+        }
+        AppInput::Touch(touch) => {
+            let gesture = match touch.gesture {
+                Gesture::None => 0x00,
+                Gesture::SlideDown => 0x01,
+                Gesture::SlideUp => 0x02,
+                Gesture::SlideLeft => 0x03,
+                Gesture::SlideRight => 0x04,
+                Gesture::SingleClick => 0x05,
+                Gesture::DoubleClick => 0x0B,
+                Gesture::LongPress => 0x0C,
+            };
+            let event_type = match touch.event_type {
+                TouchType::Down => 0,
+                TouchType::Up => 1,
+                TouchType::Contact => 2,
+            };
+            writer.bytes(&[gesture, event_type, touch.x, touch.y]);
+        }
+        AppInput::ButtonPressed | AppInput::Tick => {}
+        AppInput::Diagnostics(diagnostics) => {
+            writer.str16(&diagnostics.firmware_version);
+            match diagnostics.ble {
+                BleState::Unavailable => writer.u8(0),
+                BleState::Advertising => writer.u8(1),
+                BleState::Connected { peer_address } => {
+                    writer.u8(2);
+                    writer.bytes(&peer_address);
+                }
+            }
+            writer.u8(match diagnostics.display_writes {
+                DisplayWrites::Unknown => 0,
+                DisplayWrites::Simulator => 1,
+                DisplayWrites::Blocking => 2,
+                DisplayWrites::Dma => 3,
+            });
+        }
+        AppInput::Settings(settings) => writer.bytes(&settings.encode()),
+    }
+
+    writer.finish()
+}
+
+struct Writer(EncodedEntry);
+
+impl Writer {
+    fn new(ms_since_boot: u64, tag: u8) -> Self {
+        let mut writer = Self(ArrayVec::new());
+        // The length is filled in by `finish`.
+        writer.bytes(&[0, 0]);
+
+        let mut value = ms_since_boot;
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                writer.u8(byte);
+                break;
+            }
+            writer.u8(byte | 0x80);
+        }
+
+        writer.u8(tag);
+
+        writer
+    }
+
+    // The unwraps on writes are safe because MAX_ENCODED_LEN is large enough
+    // to hold the longest entry.
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.0.try_extend_from_slice(bytes).unwrap();
+    }
+
+    fn str16(&mut self, s: &str) {
+        self.bytes(&(s.len() as u16).to_le_bytes());
+        self.bytes(s.as_bytes());
+    }
+
+    fn finish(mut self) -> EncodedEntry {
+        let len = (self.0.len() - 2) as u16;
+        self.0[..2].copy_from_slice(&len.to_le_bytes());
+
+        self.0
+    }
+}
+
+/// Iterates over the entries in a recording.
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(recording: &'a [u8]) -> Self {
+        Self { bytes: recording }
+    }
+}
+
+impl Iterator for Decoder<'_> {
+    type Item = Result<Entry, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+
+        let mut reader = Reader(self.bytes);
+        let entry = reader
+            .u16()
+            .and_then(|len| reader.take(len.into()))
+            .and_then(|body| decode_entry(&mut Reader(body)));
+
+        // Stop after an error, as there's no way to find the next entry.
+        self.bytes = match entry {
+            Ok(_) => reader.0,
+            Err(_) => &[],
+        };
+
+        Some(entry)
+    }
+}
+
+fn decode_entry(reader: &mut Reader) -> Result<Entry, DecodeError> {
+    let ms_since_boot = reader.varint()?;
+    let input = match reader.u8()? {
+        TAG_INIT => return Ok(Entry::Init { ms_since_boot }),
+        TAG_APPLE_MEDIA => AppInput::AppleMedia(AppleMediaServiceData {
+            artist: reader.str16()?,
+            album: reader.str16()?,
+            title: reader.str16()?,
+        }),
+        TAG_BATTERY => AppInput::Battery(BatteryData {
+            charging: reader.u8()? != 0,
+            voltage: f32::from_le_bytes(reader.array()?),
+        }),
+        TAG_TIME => {
+            let [hours, minutes, seconds] = reader.array()?;
+            AppInput::Time(TimeOfDay {
+                hours,
+                minutes,
+                seconds,
+            })
+        }
+        TAG_TOUCH => {
+            let [gesture, event_type, x, y] = reader.array()?;
+            AppInput::Touch(Touch {
+                gesture: gesture.try_into().map_err(|_| DecodeError::InvalidValue)?,
+                event_type: event_type
+                    .try_into()
+                    .map_err(|_| DecodeError::InvalidValue)?,
+                x,
+                y,
+            })
+        }
+        TAG_BUTTON_PRESSED => AppInput::ButtonPressed,
+        TAG_TICK => AppInput::Tick,
+        TAG_DIAGNOSTICS => {
+            let firmware_version: FirmwareVersion = reader.str16()?;
+            let ble = match reader.u8()? {
+                0 => BleState::Unavailable,
+                1 => BleState::Advertising,
+                2 => BleState::Connected {
+                    peer_address: reader.array()?,
+                },
+                _ => return Err(DecodeError::InvalidValue),
+            };
+            // Added after the first recordings were made.
+            let display_writes = match reader.0.is_empty() {
+                true => DisplayWrites::Unknown,
+                false => match reader.u8()? {
+                    0 => DisplayWrites::Unknown,
+                    1 => DisplayWrites::Simulator,
+                    2 => DisplayWrites::Blocking,
+                    3 => DisplayWrites::Dma,
+                    _ => return Err(DecodeError::InvalidValue),
+                },
+            };
+            AppInput::Diagnostics(Diagnostics {
+                firmware_version,
+                ble,
+                display_writes,
+            })
+        }
+        TAG_SETTINGS => {
+            AppInput::Settings(Settings::decode(reader.0).map_err(|_| DecodeError::InvalidValue)?)
+        }
+        other => return Err(DecodeError::UnknownTag(other)),
+    };
+
+    Ok(Entry::Input {
+        ms_since_boot,
+        input,
+    })
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.0.len() < len {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        // The unwrap is safe because `take` returns exactly N bytes.
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut value = 0;
+        for i in 0..MAX_VARINT_LEN {
+            let byte = self.u8()?;
+            value |= u64::from(byte & 0x7F) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(DecodeError::InvalidValue)
+    }
+
+    fn str16<const N: usize>(&mut self) -> Result<ArrayString<N>, DecodeError> {
+        let len = self.u16()?;
+        let s =
+            core::str::from_utf8(self.take(len.into())?).map_err(|_| DecodeError::InvalidValue)?;
+
+        ArrayString::from(s).map_err(|_| DecodeError::InvalidValue)
+    }
+}
+
+/// Keeps the most recent entries in a fixed amount of RAM, dropping the
+/// oldest entries as new ones are recorded.
+///
+/// Once the `Entry::Init` has been dropped, replaying the recording starts
+/// from a freshly initialized `App` rather than the state the watch was in,
+/// so a larger buffer makes a faithful replay more likely.
+pub struct RingRecorder<const N: usize> {
+    buffer: [u8; N],
+    start: usize,
+    len: usize,
+}
+
+impl<const N: usize> RingRecorder<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; N],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    pub fn record_init(&mut self, ms_since_boot: u64) {
+        self.push(&encode_init(ms_since_boot));
+    }
+
+    pub fn record(&mut self, ms_since_boot: u64, input: &AppInput) {
+        self.push(&encode_input(ms_since_boot, input));
+    }
+
+    /// The recording, oldest entries first, split in two where it wraps
+    /// around the end of the buffer.
+    pub fn as_slices(&self) -> (&[u8], &[u8]) {
+        let end = self.start + self.len;
+        if end <= N {
+            (&self.buffer[self.start..end], &[])
+        } else {
+            (&self.buffer[self.start..], &self.buffer[..end - N])
+        }
+    }
+
+    fn push(&mut self, entry: &[u8]) {
+        if entry.len() > N {
+            // This entry would replace the whole recording, and still not
+            // fit.
+            return;
+        }
+
+        while N - self.len < entry.len() {
+            let oldest_len =
+                u16::from_le_bytes([self.buffer[self.start], self.buffer[(self.start + 1) % N]]);
+            let oldest_len = 2 + usize::from(oldest_len);
+            self.start = (self.start + oldest_len) % N;
+            self.len -= oldest_len;
+        }
+
+        let end = (self.start + self.len) % N;
+        let first = entry.len().min(N - end);
+        self.buffer[end..end + first].copy_from_slice(&entry[..first]);
+        self.buffer[..entry.len() - first].copy_from_slice(&entry[first..]);
+        self.len += entry.len();
+    }
+}
+
+impl<const N: usize> Default for RingRecorder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Feeds a recording into a new `App`, leaving the display showing what it
+/// showed at the end of the recording.
+///
+/// Outputs from the app are discarded. If the recording doesn't start with
+/// an `Entry::Init`, the app is initialized at the time of the first input.
+pub fn replay<D, E>(display: &mut D, recording: &[u8]) -> Result<App, ReplayError<E>>
+where
+    D: DrawTarget<Color = DisplayColor, Error = E>,
+    E: core::fmt::Debug,
+{
+    let mut app = None;
+
+    for entry in Decoder::new(recording) {
+        match entry.map_err(ReplayError::Decode)? {
+            Entry::Init { ms_since_boot } => {
+                app = Some(App::init(display, ms_since_boot).map_err(ReplayError::Display)?);
+            }
+            Entry::Input {
+                ms_since_boot,
+                input,
+            } => {
+                let app = match app.as_mut() {
+                    Some(app) => app,
+                    None => {
+                        app.insert(App::init(display, ms_since_boot).map_err(ReplayError::Display)?)
+                    }
+                };
+                app.handle_event(display, ms_since_boot, input)
+                    .map_err(ReplayError::Display)?;
+            }
+        }
+    }
+
+    match app {
+        Some(app) => Ok(app),
+        None => App::init(display, 0).map_err(ReplayError::Display),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::str::FromStr;
+
+    use embedded_graphics::geometry::Size;
+
+    use crate::{
+        interface::{LCD_H, LCD_W},
+        test_infra::SimDisplay,
+    };
+
+    use super::*;
+
+    fn inputs() -> [AppInput; 8] {
+        [
+            AppInput::Diagnostics(Diagnostics {
+                firmware_version: FirmwareVersion::from("0.1.0").unwrap(),
+                ble: BleState::Connected {
+                    peer_address: [1, 2, 3, 4, 5, 6],
+                },
+                display_writes: DisplayWrites::Dma,
+            }),
+            AppInput::Time(TimeOfDay {
+                hours: 12,
+                minutes: 30,
+                seconds: 59,
+            }),
+            AppInput::Battery(BatteryData {
+                charging: false,
+                voltage: 3.8,
+            }),
+            AppInput::AppleMedia(AppleMediaServiceData {
+                artist: ArrayString::from_str("artist").unwrap(),
+                album: ArrayString::from_str("").unwrap(),
+                title: ArrayString::from_str("title ✓").unwrap(),
+            }),
+            AppInput::Touch(Touch {
+                gesture: Gesture::SingleClick,
+                event_type: TouchType::Down,
+                x: 120,
+                y: 120,
+            }),
+            AppInput::ButtonPressed,
+            AppInput::Tick,
+            AppInput::Settings(Settings::default()),
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut recording = std::vec::Vec::new();
+        recording.extend_from_slice(&encode_init(5));
+        for (i, input) in inputs().iter().enumerate() {
+            recording.extend_from_slice(&encode_input(1_000_000 * i as u64, input));
+        }
+
+        let mut decoder = Decoder::new(&recording);
+        assert!(matches!(
+            decoder.next(),
+            Some(Ok(Entry::Init { ms_since_boot: 5 }))
+        ));
+        // AppInput doesn't implement PartialEq, so compare the encodings.
+        for (i, expected) in inputs().iter().enumerate() {
+            match decoder.next() {
+                Some(Ok(Entry::Input {
+                    ms_since_boot,
+                    input,
+                })) => {
+                    assert_eq!(1_000_000 * i as u64, ms_since_boot);
+                    assert_eq!(
+                        encode_input(ms_since_boot, expected),
+                        encode_input(ms_since_boot, &input)
+                    );
+                }
+                _ => panic!("expected input {i}"),
+            }
+        }
+        assert!(decoder.next().is_none());
+
+        // A truncated recording is reported, rather than silently ending.
+        let mut decoder = Decoder::new(&recording[..recording.len() - 1]);
+        assert_eq!(
+            Some(DecodeError::UnexpectedEnd),
+            decoder.find_map(Result::err)
+        );
+    }
+
+    #[test]
+    fn decodes_diagnostics_from_older_recordings() {
+        // Recordings made before `display_writes` was added end the entry
+        // after the BLE state.
+        let mut entry = encode_input(0, &inputs()[0]);
+        entry.pop();
+        let len = (entry.len() - 2) as u16;
+        entry[..2].copy_from_slice(&len.to_le_bytes());
+
+        match Decoder::new(&entry).next() {
+            Some(Ok(Entry::Input {
+                input: AppInput::Diagnostics(diagnostics),
+                ..
+            })) => assert_eq!(DisplayWrites::Unknown, diagnostics.display_writes),
+            _ => panic!("expected diagnostics"),
+        }
+    }
+
+    #[test]
+    fn ring_drops_oldest_entries() {
+        let tick_len = encode_input(0, &AppInput::Tick).len();
+        let mut recorder = RingRecorder::<18>::new();
+        for ms in 0..10 {
+            recorder.record(ms, &AppInput::Tick);
+        }
+
+        let (first, second) = recorder.as_slices();
+        let recording = [first, second].concat();
+        assert_eq!(18 / tick_len * tick_len, recording.len());
+        let times = Decoder::new(&recording)
+            .map(|entry| match entry {
+                Ok(Entry::Input { ms_since_boot, .. }) => ms_since_boot,
+                _ => panic!("expected a tick"),
+            })
+            .collect::<std::vec::Vec<_>>();
+        assert_eq!(std::vec![6, 7, 8, 9], times);
+    }
+
+    #[test]
+    fn replay_produces_same_screen() {
+        let mut recorder = RingRecorder::<4096>::new();
+        let mut display = SimDisplay::new(Size::new(LCD_W as u32, LCD_H as u32));
+        recorder.record_init(0);
+        let mut app = App::init(&mut display, 0).unwrap();
+        for (i, input) in inputs().into_iter().enumerate() {
+            let ms_since_boot = 100 * i as u64;
+            recorder.record(ms_since_boot, &input);
+            app.handle_event(&mut display, ms_since_boot, input)
+                .unwrap();
+        }
+
+        let (first, second) = recorder.as_slices();
+        let mut replayed = SimDisplay::new(Size::new(LCD_W as u32, LCD_H as u32));
+        replay(&mut replayed, &[first, second].concat()).unwrap();
+
+        assert!(display == replayed, "replayed screen differs");
+    }
+}
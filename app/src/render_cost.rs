@@ -0,0 +1,204 @@
+//! Estimates of what drawing costs on the PineTime's ST7789.
+//!
+//! `CostCounter` wraps a `DrawTarget` and counts the calls and pixels passed
+//! through it. From those it estimates the bytes sent over SPI, the way the
+//! `mipidsi` driver sends them: every call sets an address window, then
+//! writes two bytes per pixel. Time spent toggling the data/command and chip
+//! select pins isn't counted, so the real transfer takes a little longer.
+
+use embedded_graphics::{
+    draw_target::DrawTarget,
+    geometry::{Dimensions, Point},
+    primitives::Rectangle,
+    Pixel,
+};
+
+/// Matches `spim::Frequency::M8` in the firmware's `display::create`.
+pub const SPI_FREQUENCY_HZ: u32 = 8_000_000;
+
+/// Column address set and page address set commands with their four bytes of
+/// parameters, followed by the memory write command.
+const ADDRESS_WINDOW_BYTES: u32 = (1 + 4) + (1 + 4) + 1;
+/// RGB565.
+const BYTES_PER_PIXEL: u32 = 2;
+/// `mipidsi` batches pixels from `draw_iter` which are contiguous within a
+/// row into a single address window, up to this many at a time. It can also
+/// merge rows into blocks, which isn't modelled, so `draw_iter` costs are
+/// slightly overestimated.
+const MAX_BATCHED_ROW_PIXELS: u32 = 50;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RenderCost {
+    pub draw_iter_calls: u32,
+    pub fill_contiguous_calls: u32,
+    /// Includes calls to `clear`.
+    pub fill_solid_calls: u32,
+    pub pixels: u32,
+    pub address_windows: u32,
+}
+
+impl RenderCost {
+    pub fn spi_bytes(&self) -> u32 {
+        self.address_windows * ADDRESS_WINDOW_BYTES + self.pixels * BYTES_PER_PIXEL
+    }
+
+    /// The estimated time to send `spi_bytes` at `SPI_FREQUENCY_HZ`.
+    pub fn transfer_us(&self) -> u32 {
+        (self.spi_bytes() as u64 * 8 * 1_000_000 / SPI_FREQUENCY_HZ as u64) as u32
+    }
+}
+
+/// Counts what is drawn to the wrapped `DrawTarget`. Like the adapters in
+/// `DrawTargetExt`, it borrows the target, so it can wrap a single event.
+pub struct CostCounter<'a, D> {
+    inner: &'a mut D,
+    cost: RenderCost,
+}
+
+impl<'a, D> CostCounter<'a, D> {
+    pub fn new(inner: &'a mut D) -> Self {
+        Self {
+            inner,
+            cost: RenderCost::default(),
+        }
+    }
+
+    /// The cost of everything drawn so far.
+    pub fn cost(&self) -> RenderCost {
+        self.cost
+    }
+}
+
+impl<D: DrawTarget> Dimensions for CostCounter<'_, D> {
+    fn bounding_box(&self) -> Rectangle {
+        self.inner.bounding_box()
+    }
+}
+
+impl<D: DrawTarget> DrawTarget for CostCounter<'_, D> {
+    type Color = D::Color;
+    type Error = D::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        self.cost.draw_iter_calls += 1;
+
+        let cost = &mut self.cost;
+        // The previous pixel, and the length of the row batch it is in.
+        let mut batch: Option<(Point, u32)> = None;
+        let pixels = pixels.into_iter().inspect(|Pixel(point, _)| {
+            cost.pixels += 1;
+            batch = match batch {
+                Some((previous, len))
+                    if point.y == previous.y
+                        && point.x == previous.x + 1
+                        && len < MAX_BATCHED_ROW_PIXELS =>
+                {
+                    Some((*point, len + 1))
+                }
+                _ => {
+                    cost.address_windows += 1;
+                    Some((*point, 1))
+                }
+            };
+        });
+
+        self.inner.draw_iter(pixels)
+    }
+
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Self::Color>,
+    {
+        // The driver only sends the part of the area on screen, but the
+        // colors cover the whole area, so it is passed on unclipped.
+        let clipped = area.intersection(&self.bounding_box());
+        self.cost.fill_contiguous_calls += 1;
+        self.cost.address_windows += 1;
+        self.cost.pixels += clipped.size.width * clipped.size.height;
+
+        self.inner.fill_contiguous(area, colors)
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        // The driver only sends the part of the area on screen.
+        let area = area.intersection(&self.bounding_box());
+        self.cost.fill_solid_calls += 1;
+        self.cost.address_windows += 1;
+        self.cost.pixels += area.size.width * area.size.height;
+
+        self.inner.fill_solid(&area, color)
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        let size = self.bounding_box().size;
+        self.cost.fill_solid_calls += 1;
+        self.cost.address_windows += 1;
+        self.cost.pixels += size.width * size.height;
+
+        self.inner.clear(color)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_graphics::{
+        geometry::Size,
+        pixelcolor::{Rgb565, RgbColor},
+    };
+
+    use crate::{
+        interface::{LCD_H, LCD_W},
+        test_infra::SimDisplay,
+    };
+
+    use super::*;
+
+    #[test]
+    fn counts_pixels_and_address_windows() {
+        let mut display = SimDisplay::new(Size::new(LCD_W.into(), LCD_H.into()));
+        let mut display = CostCounter::new(&mut display);
+
+        display.clear(Rgb565::BLACK).unwrap();
+        // Partly off screen.
+        display
+            .fill_solid(
+                &Rectangle::new(Point::new(230, 0), Size::new(20, 2)),
+                Rgb565::WHITE,
+            )
+            .unwrap();
+        // Also partly off screen, with only 5 of the 10 pixels on it.
+        display
+            .fill_contiguous(
+                &Rectangle::new(Point::new(235, 0), Size::new(10, 1)),
+                core::iter::repeat(Rgb565::GREEN),
+            )
+            .unwrap();
+        // A row of 60 contiguous pixels, batched as 50 and 10, then a lone
+        // pixel.
+        display
+            .draw_iter(
+                (0..60)
+                    .map(|x| Pixel(Point::new(x, 5), Rgb565::RED))
+                    .chain([Pixel(Point::new(0, 7), Rgb565::RED)]),
+            )
+            .unwrap();
+
+        let cost = display.cost();
+        assert_eq!(
+            RenderCost {
+                draw_iter_calls: 1,
+                fill_contiguous_calls: 1,
+                fill_solid_calls: 2,
+                pixels: 240 * 240 + 10 * 2 + 5 + 61,
+                address_windows: 3 + 3,
+            },
+            cost
+        );
+        assert_eq!(6 * 11 + (240 * 240 + 86) * 2, cost.spi_bytes());
+        // One byte per microsecond at 8MHz.
+        assert_eq!(cost.spi_bytes(), cost.transfer_us());
+    }
+}
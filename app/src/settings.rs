@@ -0,0 +1,273 @@
+//! Settings chosen on the watch, which the platform keeps across reboots.
+//!
+//! The platform stores the bytes from `Settings::encode`, and passes the
+//! decoded settings back to the app at boot with `AppInput::Settings`. The
+//! encoding is:
+//!
+//! * a version byte
+//! * a payload length byte
+//! * the payload, one byte per field
+//! * a little endian CRC-32 of the version, length and payload
+//!
+//! Fields are only ever added at the end of the payload. Settings saved by
+//! an older version, with fewer fields, are migrated by giving the missing
+//! fields their defaults, and fields added by a newer version are ignored.
+//! Settings saved with any other version byte are rejected with
+//! `DecodeError::UnsupportedVersion`.
+
+use arrayvec::ArrayVec;
+
+use crate::{crc::crc32, interface::Backlight};
+
+const VERSION: u8 = 1;
+const PAYLOAD_LEN: usize = 3;
+const HEADER_LEN: usize = 2;
+const CHECKSUM_LEN: usize = 4;
+
+pub const MAX_ENCODED_LEN: usize = HEADER_LEN + PAYLOAD_LEN + CHECKSUM_LEN;
+
+pub type EncodedSettings = ArrayVec<u8, MAX_ENCODED_LEN>;
+
+/// The screen timeouts which can be chosen, in order.
+pub(crate) const SCREEN_TIMEOUTS_S: [u8; 5] = [5, 10, 15, 30, 60];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Settings {
+    /// The backlight level while the screen is on, outside of power saving.
+    pub brightness: Brightness,
+    pub time_format: TimeFormat,
+    /// How long the screen stays on after an interaction, outside of power
+    /// saving. Never 0.
+    pub screen_timeout_s: u8,
+}
+
+/// The backlight levels which can be chosen, which unlike `Backlight` can't
+/// be off.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Brightness {
+    Low,
+    Medium,
+    High,
+}
+
+impl From<Brightness> for Backlight {
+    fn from(brightness: Brightness) -> Self {
+        match brightness {
+            Brightness::Low => Backlight::Low,
+            Brightness::Medium => Backlight::Medium,
+            Brightness::High => Backlight::High,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeFormat {
+    TwentyFourHour,
+    TwelveHour,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The bytes end before the end of the settings, which is also what
+    /// erased flash decodes as.
+    UnexpectedEnd,
+    ChecksumMismatch,
+    /// Saved by a newer version with an incompatible encoding.
+    UnsupportedVersion(u8),
+    /// A field holds a value which can't be represented.
+    InvalidValue,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            brightness: Brightness::Medium,
+            time_format: TimeFormat::TwentyFourHour,
+            screen_timeout_s: 15,
+        }
+    }
+}
+
+impl Settings {
+    pub fn encode(&self) -> EncodedSettings {
+        debug_assert_ne!(0, self.screen_timeout_s, "decode rejects a 0s timeout");
+
+        let mut bytes = EncodedSettings::new();
+        bytes.push(VERSION);
+        bytes.push(PAYLOAD_LEN as u8);
+        bytes.push(match self.brightness {
+            Brightness::Low => 1,
+            Brightness::Medium => 2,
+            Brightness::High => 3,
+        });
+        bytes.push(match self.time_format {
+            TimeFormat::TwentyFourHour => 0,
+            TimeFormat::TwelveHour => 1,
+        });
+        bytes.push(self.screen_timeout_s);
+
+        let checksum = crc32(&bytes);
+        // The unwrap is safe because MAX_ENCODED_LEN includes the checksum.
+        bytes
+            .try_extend_from_slice(&checksum.to_le_bytes())
+            .unwrap();
+
+        bytes
+    }
+
+    /// Decodes settings from the start of `bytes`, ignoring anything after
+    /// them.
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let [version, payload_len, ..] = *bytes else {
+            return Err(DecodeError::UnexpectedEnd);
+        };
+        let checked_len = HEADER_LEN + usize::from(payload_len);
+        let checksum = bytes
+            .get(checked_len..checked_len + CHECKSUM_LEN)
+            .ok_or(DecodeError::UnexpectedEnd)?;
+        if crc32(&bytes[..checked_len]).to_le_bytes() != checksum {
+            return Err(DecodeError::ChecksumMismatch);
+        }
+        let payload = &bytes[HEADER_LEN..checked_len];
+
+        match version {
+            VERSION => Self::decode_payload(payload),
+            other => Err(DecodeError::UnsupportedVersion(other)),
+        }
+    }
+
+    fn decode_payload(payload: &[u8]) -> Result<Self, DecodeError> {
+        let defaults = Self::default();
+        let field = |index: usize| payload.get(index).copied();
+
+        Ok(Self {
+            brightness: match field(0) {
+                None => defaults.brightness,
+                Some(1) => Brightness::Low,
+                Some(2) => Brightness::Medium,
+                Some(3) => Brightness::High,
+                Some(_) => return Err(DecodeError::InvalidValue),
+            },
+            time_format: match field(1) {
+                None => defaults.time_format,
+                Some(0) => TimeFormat::TwentyFourHour,
+                Some(1) => TimeFormat::TwelveHour,
+                Some(_) => return Err(DecodeError::InvalidValue),
+            },
+            screen_timeout_s: match field(2) {
+                None => defaults.screen_timeout_s,
+                Some(0) => return Err(DecodeError::InvalidValue),
+                Some(timeout) => timeout,
+            },
+        })
+    }
+
+    pub(crate) fn cycle_brightness(&mut self) {
+        self.brightness = match self.brightness {
+            Brightness::Low => Brightness::Medium,
+            Brightness::Medium => Brightness::High,
+            Brightness::High => Brightness::Low,
+        };
+    }
+
+    pub(crate) fn toggle_time_format(&mut self) {
+        self.time_format = match self.time_format {
+            TimeFormat::TwentyFourHour => TimeFormat::TwelveHour,
+            TimeFormat::TwelveHour => TimeFormat::TwentyFourHour,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    fn settings() -> Settings {
+        Settings {
+            brightness: Brightness::High,
+            time_format: TimeFormat::TwelveHour,
+            screen_timeout_s: 30,
+        }
+    }
+
+    /// Encodes a payload as a given version would have.
+    fn encode_payload(version: u8, payload: &[u8]) -> std::vec::Vec<u8> {
+        let mut bytes = std::vec![version, payload.len() as u8];
+        bytes.extend_from_slice(payload);
+        let checksum = crc32(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let encoded = settings().encode();
+        assert_eq!(Ok(settings()), Settings::decode(&encoded));
+
+        // Flash is written in whole words, so there may be padding.
+        let mut padded = encoded.to_vec();
+        padded.extend_from_slice(&[0xFF; 3]);
+        assert_eq!(Ok(settings()), Settings::decode(&padded));
+    }
+
+    #[test]
+    fn every_setting_round_trips() {
+        for brightness in [Brightness::Low, Brightness::Medium, Brightness::High] {
+            for time_format in [TimeFormat::TwentyFourHour, TimeFormat::TwelveHour] {
+                for screen_timeout_s in SCREEN_TIMEOUTS_S {
+                    let settings = Settings {
+                        brightness,
+                        time_format,
+                        screen_timeout_s,
+                    };
+                    assert_eq!(Ok(settings), Settings::decode(&settings.encode()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn missing_fields_get_defaults() {
+        assert_eq!(
+            Ok(Settings {
+                brightness: Brightness::High,
+                ..Settings::default()
+            }),
+            Settings::decode(&encode_payload(VERSION, &[3]))
+        );
+
+        // Fields added by a newer version are ignored.
+        assert_eq!(
+            Ok(settings()),
+            Settings::decode(&encode_payload(VERSION, &[3, 1, 30, 7]))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        // Erased flash.
+        assert_eq!(
+            Err(DecodeError::UnexpectedEnd),
+            Settings::decode(&[0xFF; MAX_ENCODED_LEN])
+        );
+
+        let mut corrupted = settings().encode();
+        corrupted[3] ^= 1;
+        assert_eq!(
+            Err(DecodeError::ChecksumMismatch),
+            Settings::decode(&corrupted)
+        );
+
+        assert_eq!(
+            Err(DecodeError::UnsupportedVersion(VERSION + 1)),
+            Settings::decode(&encode_payload(VERSION + 1, &[3, 1, 30]))
+        );
+        assert_eq!(
+            Err(DecodeError::InvalidValue),
+            Settings::decode(&encode_payload(VERSION, &[0, 1, 30]))
+        );
+    }
+}
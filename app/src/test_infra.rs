@@ -1,6 +1,19 @@
 extern crate std;
 
-use crate::interface::DisplayColor;
+use core::str::FromStr;
+
+use arrayvec::ArrayString;
+use embedded_graphics::geometry::Size;
+use image::{Rgb, RgbImage};
+
+use crate::{
+    interface::{
+        AppInput, AppOutput, AppOutputs, AppleMediaServiceData, BatteryData, DisplayColor, Gesture,
+        TimeOfDay, Touch, TouchType, LCD_H, LCD_W,
+    },
+    render_cost::{CostCounter, RenderCost},
+    App,
+};
 
 // Taken from stdext: https://docs.rs/stdext/0.3.3/src/stdext/macros.rs.html#63-74
 macro_rules! function_name {
@@ -18,38 +31,398 @@ pub(crate) use function_name;
 
 pub(crate) type SimDisplay = embedded_graphics_simulator::SimulatorDisplay<DisplayColor>;
 
+/// How different a snapshot may be from its golden image and still pass.
+#[derive(Clone, Copy)]
+pub(crate) struct Tolerance {
+    /// Pixels whose channels all differ by at most this much are the same.
+    pub(crate) channel: u8,
+    /// The number of differing pixels allowed.
+    pub(crate) pixels: usize,
+}
+
+impl Tolerance {
+    pub(crate) const EXACT: Tolerance = Tolerance {
+        channel: 0,
+        pixels: 0,
+    };
+}
+
 pub(crate) fn assert_snapshot(test_name: &str, display: SimDisplay) {
+    assert_snapshot_with_tolerance(test_name, display, Tolerance::EXACT);
+}
+
+/// Set to `1` to create missing golden images, overwrite ones which don't
+/// match, and delete ones without a test, rather than failing.
+const UPDATE_SNAPSHOTS_VAR: &str = "UPDATE_SNAPSHOTS";
+
+fn update_mode() -> bool {
+    std::env::var(UPDATE_SNAPSHOTS_VAR).is_ok_and(|value| value == "1")
+}
+
+/// Compares the display pixel by pixel with the golden image. On failure, a
+/// diff image highlighting the differing pixels is saved next to it.
+pub(crate) fn assert_snapshot_with_tolerance(
+    test_name: &str,
+    display: SimDisplay,
+    tolerance: Tolerance,
+) {
     let test_image_path = std::format!("snapshots/{test_name}.test.png");
     let golden_image_path = std::format!("snapshots/{test_name}.golden.png");
+    let diff_image_path = std::format!("snapshots/{test_name}.diff.png");
+
+    let output_image = display.to_rgb_output_image(&core::default::Default::default());
+    let buffer = output_image.as_image_buffer();
+    let test_image =
+        RgbImage::from_raw(buffer.width(), buffer.height(), buffer.into_raw().into()).unwrap();
+    // Save our current image for reference by the user.
+    test_image.save(&test_image_path).unwrap();
 
-    let golden = std::fs::read(&golden_image_path);
-    match golden {
-        Ok(golden_image) => {
-            // There is an existing golden image. Save our current image for reference by
-            // the user.
-            display
-                .to_rgb_output_image(&core::default::Default::default())
-                .save_png(&test_image_path)
-                .unwrap();
-
-            // Now compare the test image to the golden image.
-            // Kindof hacky to read this back in right after we wrote it out, but
-            // meh good enough for now.
-            let test_image = std::fs::read(&test_image_path).unwrap();
-            assert_eq!(
-                test_image, golden_image,
-                "{test_image_path} does not match {golden_image_path}"
+    if !std::path::Path::new(&golden_image_path).exists() {
+        // This is either a new test, or the test was renamed.
+        if update_mode() {
+            test_image.save(&golden_image_path).unwrap();
+            return;
+        }
+        panic!(
+            "{golden_image_path} does not exist, run the tests with {UPDATE_SNAPSHOTS_VAR}=1 to \
+             create it from {test_image_path}"
+        );
+    }
+
+    let golden_image = image::open(&golden_image_path)
+        .unwrap_or_else(|e| panic!("failed to read {golden_image_path}: {e}"))
+        .to_rgb8();
+    match compare(&test_image, &golden_image, tolerance) {
+        Ok(()) => {}
+        Err(_) if update_mode() => test_image.save(&golden_image_path).unwrap(),
+        Err(diff) => {
+            if let Some(diff_image) = &diff.image {
+                diff_image.save(&diff_image_path).unwrap();
+            }
+            panic!(
+                "{test_image_path} does not match {golden_image_path}: {diff}, see \
+                 {diff_image_path}, or run the tests with {UPDATE_SNAPSHOTS_VAR}=1 to accept it"
             );
         }
-        Err(_) => {
-            // There is no golden image.
-            //
-            // This is either a new test, or the user has deleted the golden image
-            // on purpose. In either case, save the current result as the golden image.
-            display
-                .to_rgb_output_image(&core::default::Default::default())
-                .save_png(&golden_image_path)
-                .unwrap();
+    }
+    // Don't leave a diff from a previous failure lying around.
+    let _ = std::fs::remove_file(&diff_image_path);
+}
+
+/// Golden images whose test no longer exists.
+///
+/// Tests run in parallel, so there is no point at which every test is known
+/// to have run. Instead, each golden image's name is mapped back to the
+/// source file of its module, which is checked for the test function.
+fn stale_goldens() -> std::vec::Vec<std::string::String> {
+    let mut stale = std::vec::Vec::new();
+
+    for entry in std::fs::read_dir("snapshots").unwrap() {
+        let file_name = entry.unwrap().file_name();
+        let Some(test_name) = file_name
+            .to_str()
+            .and_then(|name| name.strip_suffix(".golden.png"))
+        else {
+            continue;
+        };
+
+        let mut path = test_name.split("::").skip(1).collect::<std::vec::Vec<_>>();
+        let function = path.pop().unwrap_or_default();
+        path.retain(|module| *module != "tests");
+        let source = std::fs::read_to_string(std::format!("src/{}.rs", path.join("/")))
+            .or_else(|_| std::fs::read_to_string(std::format!("src/{}/mod.rs", path.join("/"))))
+            .unwrap_or_default();
+
+        if !source.contains(&std::format!("fn {function}(")) {
+            stale.push(std::format!("snapshots/{test_name}.golden.png"));
+        }
+    }
+    stale.sort();
+
+    stale
+}
+
+struct SnapshotDiff {
+    description: std::string::String,
+    /// The golden image faded to grey, with differing pixels in red. Images
+    /// of different sizes can't be overlaid, so have no diff image.
+    image: Option<RgbImage>,
+}
+
+impl core::fmt::Display for SnapshotDiff {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(&self.description)
+    }
+}
+
+fn compare(test: &RgbImage, golden: &RgbImage, tolerance: Tolerance) -> Result<(), SnapshotDiff> {
+    if test.dimensions() != golden.dimensions() {
+        return Err(SnapshotDiff {
+            description: std::format!(
+                "size is {:?}, expected {:?}",
+                test.dimensions(),
+                golden.dimensions()
+            ),
+            image: None,
+        });
+    }
+
+    let mut diff_image = RgbImage::new(golden.width(), golden.height());
+    let mut differing_pixels = 0;
+    // Inclusive (min x, min y, max x, max y) of the differing pixels.
+    let mut bounding_box: Option<(u32, u32, u32, u32)> = None;
+
+    for (x, y, golden_pixel) in golden.enumerate_pixels() {
+        let test_pixel = test.get_pixel(x, y);
+        let differs = golden_pixel
+            .0
+            .iter()
+            .zip(test_pixel.0)
+            .any(|(golden, test)| golden.abs_diff(test) > tolerance.channel);
+
+        if differs {
+            differing_pixels += 1;
+            bounding_box = Some(match bounding_box {
+                Some((min_x, min_y, max_x, max_y)) => {
+                    (min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y))
+                }
+                None => (x, y, x, y),
+            });
+            diff_image.put_pixel(x, y, Rgb([255, 0, 0]));
+        } else {
+            let [r, g, b] = golden_pixel.0;
+            let grey = ((r as u32 + g as u32 + b as u32) / 3 / 4) as u8;
+            diff_image.put_pixel(x, y, Rgb([grey, grey, grey]));
+        }
+    }
+
+    match bounding_box {
+        Some((min_x, min_y, max_x, max_y)) if differing_pixels > tolerance.pixels => {
+            Err(SnapshotDiff {
+                description: std::format!(
+                    "{differing_pixels} pixels differ, within x {min_x}..={max_x}, y {min_y}..={max_y}"
+                ),
+                image: Some(diff_image),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Drives an `App` through a flow of inputs, owning the display and the
+/// clock so each step only says what changes. What each input costs to draw
+/// is counted, so screens can be held to a budget.
+///
+/// ```ignore
+/// Scenario::new()
+///     .battery(true, 4.1)
+///     .tap(120, 120)
+///     .expect_output(AppOutput::MediaControl(MediaControl::TogglePlayPause))
+///     .advance(15_000)
+///     .snapshot(function_name!());
+/// ```
+pub(crate) struct Scenario {
+    display: SimDisplay,
+    app: App,
+    ms_since_boot: u64,
+    /// The outputs of the most recent input.
+    outputs: AppOutputs,
+    /// What the most recent input cost to draw.
+    render_cost: RenderCost,
+}
+
+impl Scenario {
+    pub(crate) fn new() -> Self {
+        let mut display = SimDisplay::new(Size::new(LCD_W as u32, LCD_H as u32));
+        let mut counter = CostCounter::new(&mut display);
+        let app = App::init(&mut counter, 0).unwrap();
+        let render_cost = counter.cost();
+
+        Self {
+            display,
+            app,
+            ms_since_boot: 0,
+            outputs: AppOutputs::new(),
+            render_cost,
         }
     }
+
+    /// Sets the time following inputs are sent at, without sending an input.
+    pub(crate) fn at(mut self, ms_since_boot: u64) -> Self {
+        assert!(
+            ms_since_boot >= self.ms_since_boot,
+            "time went backwards from {} to {ms_since_boot}",
+            self.ms_since_boot
+        );
+        self.ms_since_boot = ms_since_boot;
+
+        self
+    }
+
+    /// Moves the clock forward and ticks, as the platform would after that
+    /// long.
+    pub(crate) fn advance(self, ms: u64) -> Self {
+        let ms_since_boot = self.ms_since_boot + ms;
+
+        self.at(ms_since_boot).tick()
+    }
+
+    pub(crate) fn input(mut self, input: AppInput) -> Self {
+        let mut counter = CostCounter::new(&mut self.display);
+        self.outputs = self
+            .app
+            .handle_event(&mut counter, self.ms_since_boot, input)
+            .unwrap();
+        self.render_cost = counter.cost();
+        // Drawing is instant here, so the frame takes as long as sending it
+        // to the watch's display would.
+        self.app
+            .frame_sent(self.ms_since_boot + u64::from(self.render_cost.transfer_us() / 1000));
+
+        self
+    }
+
+    pub(crate) fn tick(self) -> Self {
+        self.input(AppInput::Tick)
+    }
+
+    pub(crate) fn button(self) -> Self {
+        self.input(AppInput::ButtonPressed)
+    }
+
+    pub(crate) fn battery(self, charging: bool, voltage: f32) -> Self {
+        self.input(AppInput::Battery(BatteryData { charging, voltage }))
+    }
+
+    pub(crate) fn time(self, hours: u8, minutes: u8, seconds: u8) -> Self {
+        self.input(AppInput::Time(TimeOfDay {
+            hours,
+            minutes,
+            seconds,
+        }))
+    }
+
+    pub(crate) fn media(self, artist: &str, album: &str, title: &str) -> Self {
+        self.input(AppInput::AppleMedia(AppleMediaServiceData {
+            artist: ArrayString::from_str(artist).unwrap(),
+            album: ArrayString::from_str(album).unwrap(),
+            title: ArrayString::from_str(title).unwrap(),
+        }))
+    }
+
+    pub(crate) fn touch(self, gesture: Gesture, x: u8, y: u8) -> Self {
+        self.input(AppInput::Touch(Touch {
+            gesture,
+            event_type: TouchType::Down,
+            x,
+            y,
+        }))
+    }
+
+    pub(crate) fn tap(self, x: u8, y: u8) -> Self {
+        self.touch(Gesture::SingleClick, x, y)
+    }
+
+    /// Checks the most recent input emitted `output`, among others.
+    #[track_caller]
+    pub(crate) fn expect_output(self, output: AppOutput) -> Self {
+        assert!(
+            self.outputs.contains(&output),
+            "expected {output:?} at {}ms, found {:?}",
+            self.ms_since_boot,
+            self.outputs
+        );
+
+        self
+    }
+
+    #[track_caller]
+    pub(crate) fn expect_no_outputs(self) -> Self {
+        assert!(
+            self.outputs.is_empty(),
+            "expected no outputs at {}ms, found {:?}",
+            self.ms_since_boot,
+            self.outputs
+        );
+
+        self
+    }
+
+    /// Checks the most recent input took at most `budget_us` to send to the
+    /// display, as estimated by `RenderCost::transfer_us`.
+    #[track_caller]
+    pub(crate) fn expect_render_within(self, budget_us: u32) -> Self {
+        assert!(
+            self.render_cost.transfer_us() <= budget_us,
+            "expected drawing at {}ms to take at most {budget_us}us, took {}us: {:?}",
+            self.ms_since_boot,
+            self.render_cost.transfer_us(),
+            self.render_cost
+        );
+
+        self
+    }
+
+    /// The outputs of the most recent input, for checks `expect_output`
+    /// can't express.
+    pub(crate) fn outputs(&self) -> &[AppOutput] {
+        &self.outputs
+    }
+
+    pub(crate) fn app(&self) -> &App {
+        &self.app
+    }
+
+    /// Compares the display with the golden image for `test_name`, which
+    /// should come from `function_name!()`.
+    pub(crate) fn snapshot(self, test_name: &str) -> Self {
+        assert_snapshot(test_name, self.display.clone());
+
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_stale_goldens() {
+        let stale = stale_goldens();
+        if update_mode() {
+            for path in stale {
+                std::fs::remove_file(path).unwrap();
+            }
+            return;
+        }
+
+        assert!(
+            stale.is_empty(),
+            "no test uses {stale:?}, delete them or run the tests with {UPDATE_SNAPSHOTS_VAR}=1"
+        );
+    }
+
+    #[test]
+    fn compare_reports_differing_region() {
+        let golden = RgbImage::new(8, 8);
+        let mut test = golden.clone();
+        test.put_pixel(2, 3, Rgb([255, 255, 255]));
+        test.put_pixel(5, 1, Rgb([255, 255, 255]));
+        test.put_pixel(6, 6, Rgb([2, 2, 2]));
+
+        let diff = compare(&test, &golden, Tolerance::EXACT).unwrap_err();
+        assert_eq!(
+            "3 pixels differ, within x 2..=6, y 1..=6",
+            diff.description.as_str()
+        );
+        assert_eq!(&Rgb([255, 0, 0]), diff.image.unwrap().get_pixel(5, 1));
+
+        let tolerance = Tolerance {
+            channel: 2,
+            pixels: 2,
+        };
+        assert!(compare(&test, &golden, tolerance).is_ok());
+
+        assert!(compare(&RgbImage::new(8, 9), &golden, tolerance).is_err());
+    }
 }
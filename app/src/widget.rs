@@ -0,0 +1,508 @@
+//! A small widget layer which screens are composed from.
+//!
+//! Each widget owns a rectangular hit region. Touches are routed to a widget
+//! with `handle_touch`, which records pressed state (so the widget can be
+//! drawn differently for a short time after it is touched) and reports
+//! whether the touch activated the widget.
+
+use embedded_graphics::{
+    draw_target::DrawTarget,
+    geometry::{Point, Size},
+    mono_font::{ascii, MonoTextStyleBuilder},
+    pixelcolor::WebColors,
+    prelude::RgbColor,
+    primitives::{Primitive, PrimitiveStyleBuilder, Rectangle, StrokeAlignment},
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+    Drawable,
+};
+
+use crate::interface::{DisplayColor, Gesture, Touch};
+
+/// How long a widget is drawn in its pressed state after being touched.
+///
+/// The touch controller rarely reports `TouchType::Up`, so we cannot rely on
+/// it to clear the pressed state.
+const PRESSED_FEEDBACK_MS: u64 = 200;
+
+/// Returns true if the touch lands within the region.
+pub fn hit_test(region: &Rectangle, touch: &Touch) -> bool {
+    region.contains(Point::new(touch.x as i32, touch.y as i32))
+}
+
+/// Tracks when a widget (or part of a widget) was last touched.
+#[derive(Clone, Copy)]
+struct PressedState {
+    pressed_at_ms_since_boot: Option<u64>,
+}
+
+impl PressedState {
+    const fn new() -> Self {
+        Self {
+            pressed_at_ms_since_boot: None,
+        }
+    }
+
+    fn press(&mut self, ms_since_boot: u64) {
+        self.pressed_at_ms_since_boot = Some(ms_since_boot);
+    }
+
+    fn is_pressed(&self, ms_since_boot: u64) -> bool {
+        self.pressed_at_ms_since_boot
+            .map(|pressed_at| ms_since_boot.saturating_sub(pressed_at) < PRESSED_FEEDBACK_MS)
+            .unwrap_or(false)
+    }
+}
+
+pub struct Button {
+    region: Rectangle,
+    pressed: PressedState,
+}
+
+impl Button {
+    pub const fn new(region: Rectangle) -> Self {
+        Self {
+            region,
+            pressed: PressedState::new(),
+        }
+    }
+
+    pub fn region(&self) -> Rectangle {
+        self.region
+    }
+
+    pub fn is_pressed(&self, ms_since_boot: u64) -> bool {
+        self.pressed.is_pressed(ms_since_boot)
+    }
+
+    /// Returns true if the touch was a tap on this button.
+    pub fn handle_touch(&mut self, touch: &Touch, ms_since_boot: u64) -> bool {
+        if !hit_test(&self.region, touch) {
+            return false;
+        }
+
+        self.pressed.press(ms_since_boot);
+
+        matches!(touch.gesture, Gesture::SingleClick)
+    }
+
+    /// Draws the button as an outlined box with a centered text label.
+    pub fn draw<D>(&self, display: &mut D, ms_since_boot: u64, label: &str) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = DisplayColor>,
+    {
+        let (fg, bg) = colors(self.is_pressed(ms_since_boot));
+        draw_box(display, self.region, fg, bg)?;
+        draw_centered_text(display, self.region, label, fg, bg)
+    }
+}
+
+pub struct Toggle {
+    region: Rectangle,
+    on: bool,
+    pressed: PressedState,
+}
+
+impl Toggle {
+    pub const fn new(region: Rectangle, on: bool) -> Self {
+        Self {
+            region,
+            on,
+            pressed: PressedState::new(),
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on
+    }
+
+    pub fn set(&mut self, on: bool) {
+        self.on = on;
+    }
+
+    pub fn is_pressed(&self, ms_since_boot: u64) -> bool {
+        self.pressed.is_pressed(ms_since_boot)
+    }
+
+    /// Returns the new state if the touch was a tap on this toggle.
+    pub fn handle_touch(&mut self, touch: &Touch, ms_since_boot: u64) -> Option<bool> {
+        if !hit_test(&self.region, touch) {
+            return None;
+        }
+
+        self.pressed.press(ms_since_boot);
+
+        match touch.gesture {
+            Gesture::SingleClick => {
+                self.on = !self.on;
+                Some(self.on)
+            }
+            _ => None,
+        }
+    }
+
+    /// Draws the label on the left of the region, and a switch on the right.
+    pub fn draw<D>(&self, display: &mut D, ms_since_boot: u64, label: &str) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = DisplayColor>,
+    {
+        let (fg, bg) = colors(self.is_pressed(ms_since_boot));
+        draw_box(display, self.region, fg, bg)?;
+
+        let text_style = TextStyleBuilder::new()
+            .baseline(Baseline::Middle)
+            .alignment(Alignment::Left)
+            .build();
+        Text::with_text_style(
+            label,
+            self.region.top_left + Point::new(6, self.region.size.height as i32 / 2),
+            MonoTextStyleBuilder::new()
+                .font(&ascii::FONT_9X15)
+                .text_color(fg)
+                .background_color(bg)
+                .build(),
+            text_style,
+        )
+        .draw(display)?;
+
+        let switch_size = Size::new(36, 18);
+        let switch_top_left = Point::new(
+            self.region.top_left.x + self.region.size.width as i32 - switch_size.width as i32 - 6,
+            self.region.top_left.y + (self.region.size.height - switch_size.height) as i32 / 2,
+        );
+        let switch_color = match self.on {
+            true => DisplayColor::new(85, 255, 85),
+            false => DisplayColor::CSS_GRAY,
+        };
+        Rectangle::new(switch_top_left, switch_size)
+            .into_styled(
+                PrimitiveStyleBuilder::new()
+                    .fill_color(bg)
+                    .stroke_width(2)
+                    .stroke_alignment(StrokeAlignment::Inside)
+                    .stroke_color(switch_color)
+                    .build(),
+            )
+            .draw(display)?;
+
+        let knob_size = Size::new(switch_size.height - 6, switch_size.height - 6);
+        let knob_x = match self.on {
+            true => switch_top_left.x + (switch_size.width - knob_size.width) as i32 - 3,
+            false => switch_top_left.x + 3,
+        };
+        Rectangle::new(Point::new(knob_x, switch_top_left.y + 3), knob_size)
+            .into_styled(
+                PrimitiveStyleBuilder::new()
+                    .fill_color(switch_color)
+                    .build(),
+            )
+            .draw(display)?;
+
+        Ok(())
+    }
+}
+
+/// A vertically scrollable list of fixed height rows.
+///
+/// The list only tracks which rows are visible and touched. The caller
+/// owns the items, and passes them in when drawing.
+pub struct List {
+    region: Rectangle,
+    row_height: u32,
+    len: usize,
+    /// Index of the first visible row.
+    scroll_offset: usize,
+    pressed_row: Option<usize>,
+    pressed: PressedState,
+}
+
+impl List {
+    pub const fn new(region: Rectangle, row_height: u32, len: usize) -> Self {
+        Self {
+            region,
+            row_height,
+            len,
+            scroll_offset: 0,
+            pressed_row: None,
+            pressed: PressedState::new(),
+        }
+    }
+
+    pub fn visible_rows(&self) -> usize {
+        (self.region.size.height / self.row_height) as usize
+    }
+
+    pub fn scroll_offset(&self) -> usize {
+        self.scroll_offset
+    }
+
+    fn max_scroll_offset(&self) -> usize {
+        self.len.saturating_sub(self.visible_rows())
+    }
+
+    pub fn scroll_down(&mut self) {
+        self.scroll_offset = (self.scroll_offset + 1).min(self.max_scroll_offset());
+    }
+
+    pub fn scroll_up(&mut self) {
+        self.scroll_offset = self.scroll_offset.saturating_sub(1);
+    }
+
+    /// Returns the index of the touched row, if the touch was a tap on a row.
+    ///
+    /// Sliding up within the list reveals rows further down the list, similar
+    /// to dragging a list on a phone.
+    pub fn handle_touch(&mut self, touch: &Touch, ms_since_boot: u64) -> Option<usize> {
+        if !hit_test(&self.region, touch) {
+            return None;
+        }
+
+        match touch.gesture {
+            Gesture::SlideUp => {
+                self.scroll_down();
+                None
+            }
+            Gesture::SlideDown => {
+                self.scroll_up();
+                None
+            }
+            Gesture::SingleClick => {
+                let row_in_view =
+                    (touch.y as i32 - self.region.top_left.y) as u32 / self.row_height;
+                let row = self.scroll_offset + row_in_view as usize;
+                if row < self.len {
+                    self.pressed_row = Some(row);
+                    self.pressed.press(ms_since_boot);
+                    Some(row)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn row_region(&self, row_in_view: usize) -> Rectangle {
+        Rectangle::new(
+            self.region.top_left + Point::new(0, (row_in_view as u32 * self.row_height) as i32),
+            Size::new(self.region.size.width, self.row_height),
+        )
+    }
+
+    /// Draws the visible rows.
+    ///
+    /// `items` should have the same length the list was created with.
+    pub fn draw<D>(
+        &self,
+        display: &mut D,
+        ms_since_boot: u64,
+        items: &[&str],
+    ) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = DisplayColor>,
+    {
+        for row_in_view in 0..self.visible_rows() {
+            let row = self.scroll_offset + row_in_view;
+            let row_region = self.row_region(row_in_view);
+
+            match items.get(row) {
+                Some(item) => {
+                    let pressed =
+                        self.pressed_row == Some(row) && self.pressed.is_pressed(ms_since_boot);
+                    let (fg, bg) = colors(pressed);
+                    draw_box(display, row_region, fg, bg)?;
+                    draw_centered_text(display, row_region, item, fg, bg)?;
+                }
+                None => {
+                    row_region
+                        .into_styled(
+                            PrimitiveStyleBuilder::new()
+                                .fill_color(DisplayColor::BLACK)
+                                .build(),
+                        )
+                        .draw(display)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the (foreground, background) colors for a widget.
+fn colors(pressed: bool) -> (DisplayColor, DisplayColor) {
+    match pressed {
+        true => (DisplayColor::BLACK, DisplayColor::WHITE),
+        false => (DisplayColor::WHITE, DisplayColor::BLACK),
+    }
+}
+
+fn draw_box<D>(
+    display: &mut D,
+    region: Rectangle,
+    fg: DisplayColor,
+    bg: DisplayColor,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = DisplayColor>,
+{
+    region
+        .into_styled(
+            PrimitiveStyleBuilder::new()
+                .fill_color(bg)
+                .stroke_width(1)
+                .stroke_alignment(StrokeAlignment::Inside)
+                .stroke_color(fg)
+                .build(),
+        )
+        .draw(display)?;
+
+    Ok(())
+}
+
+fn draw_centered_text<D>(
+    display: &mut D,
+    region: Rectangle,
+    text: &str,
+    fg: DisplayColor,
+    bg: DisplayColor,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = DisplayColor>,
+{
+    let character_style = MonoTextStyleBuilder::new()
+        .font(&ascii::FONT_9X15)
+        .text_color(fg)
+        .background_color(bg)
+        .build();
+    let text_style = TextStyleBuilder::new()
+        .baseline(Baseline::Middle)
+        .alignment(Alignment::Center)
+        .build();
+
+    Text::with_text_style(text, region.center(), character_style, text_style).draw(display)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use crate::{
+        interface::{TouchType, LCD_H, LCD_W},
+        test_infra::{assert_snapshot, function_name, SimDisplay},
+    };
+
+    use super::*;
+
+    fn touch(gesture: Gesture, x: u8, y: u8) -> Touch {
+        Touch {
+            gesture,
+            event_type: TouchType::Down,
+            x,
+            y,
+        }
+    }
+
+    #[test]
+    fn button_only_activated_by_tap_inside_region() {
+        let mut button = Button::new(Rectangle::new(Point::new(10, 10), Size::new(20, 20)));
+
+        assert!(!button.handle_touch(&touch(Gesture::SingleClick, 5, 15), 0));
+        assert!(!button.is_pressed(0));
+
+        assert!(!button.handle_touch(&touch(Gesture::SlideLeft, 15, 15), 0));
+        assert!(button.is_pressed(0));
+
+        assert!(button.handle_touch(&touch(Gesture::SingleClick, 29, 29), 1000));
+        assert!(button.is_pressed(1000 + PRESSED_FEEDBACK_MS - 1));
+        assert!(!button.is_pressed(1000 + PRESSED_FEEDBACK_MS));
+    }
+
+    #[test]
+    fn toggle_flips_on_tap() {
+        let mut toggle = Toggle::new(Rectangle::new(Point::new(0, 0), Size::new(240, 40)), false);
+
+        assert_eq!(
+            None,
+            toggle.handle_touch(&touch(Gesture::SingleClick, 100, 40), 0)
+        );
+        assert_eq!(
+            Some(true),
+            toggle.handle_touch(&touch(Gesture::SingleClick, 100, 20), 0)
+        );
+        assert_eq!(
+            Some(false),
+            toggle.handle_touch(&touch(Gesture::SingleClick, 100, 20), 0)
+        );
+        assert!(!toggle.is_on());
+    }
+
+    #[test]
+    fn list_scrolls_and_selects() {
+        // Three visible rows, five items.
+        let mut list = List::new(Rectangle::new(Point::new(0, 60), Size::new(240, 90)), 30, 5);
+
+        assert_eq!(
+            Some(0),
+            list.handle_touch(&touch(Gesture::SingleClick, 10, 60), 0)
+        );
+        assert_eq!(
+            Some(2),
+            list.handle_touch(&touch(Gesture::SingleClick, 10, 149), 0)
+        );
+        assert_eq!(
+            None,
+            list.handle_touch(&touch(Gesture::SingleClick, 10, 150), 0)
+        );
+
+        for _ in 0..5 {
+            list.handle_touch(&touch(Gesture::SlideUp, 10, 100), 0);
+        }
+        assert_eq!(2, list.scroll_offset());
+        assert_eq!(
+            Some(4),
+            list.handle_touch(&touch(Gesture::SingleClick, 10, 149), 0)
+        );
+
+        list.handle_touch(&touch(Gesture::SlideDown, 10, 100), 0);
+        assert_eq!(1, list.scroll_offset());
+    }
+
+    #[test]
+    fn widgets() {
+        let test_name = function_name!();
+        let mut display = SimDisplay::new(Size::new(LCD_W as u32, LCD_H as u32));
+
+        let button = Button::new(Rectangle::new(Point::new(10, 10), Size::new(100, 30)));
+        let pressed_button = {
+            let mut b = Button::new(Rectangle::new(Point::new(130, 10), Size::new(100, 30)));
+            b.handle_touch(&touch(Gesture::SingleClick, 150, 20), 0);
+            b
+        };
+        button.draw(&mut display, 0, "Button").unwrap();
+        pressed_button.draw(&mut display, 0, "Pressed").unwrap();
+
+        Toggle::new(Rectangle::new(Point::new(10, 50), Size::new(220, 30)), true)
+            .draw(&mut display, 0, "On")
+            .unwrap();
+        Toggle::new(
+            Rectangle::new(Point::new(10, 90), Size::new(220, 30)),
+            false,
+        )
+        .draw(&mut display, 0, "Off")
+        .unwrap();
+
+        let items = ["zero", "one", "two", "three", "four"];
+        let mut list = List::new(
+            Rectangle::new(Point::new(10, 130), Size::new(220, 90)),
+            30,
+            items.len(),
+        );
+        list.handle_touch(&touch(Gesture::SlideUp, 100, 150), 0);
+        list.handle_touch(&touch(Gesture::SingleClick, 100, 170), 0);
+        list.draw(&mut display, 0, &items).unwrap();
+
+        assert_snapshot(test_name, display);
+    }
+}
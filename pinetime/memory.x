@@ -1,5 +1,8 @@
 MEMORY
 {
-  FLASH : ORIGIN = 0x00026000, LENGTH = 512K - 152K
+  FLASH : ORIGIN = 0x00026000, LENGTH = 512K - 152K - 16K
+  /* Kept across firmware updates, for settings and BLE bonds. Must match
+     REGION in src/tasks/storage.rs. */
+  STORAGE : ORIGIN = 0x0007C000, LENGTH = 16K
   RAM : ORIGIN = 0x20000000 + 16128, LENGTH = 64K - 16128
 }
@@ -1,14 +1,22 @@
-use embassy_futures::select::{select, select4, Either, Either4::*};
-use embassy_time::Instant;
+use embassy_futures::select::{select, select4, Either, Either4, Either4::*};
+use embassy_time::{Duration, Instant};
+#[cfg(feature = "frame-diff")]
+use mesozoic_app::frame_diff::{FrameDiff, SpanTable};
+#[cfg(feature = "record-inputs")]
+use mesozoic_app::recording::RingRecorder;
 use mesozoic_app::{
-    interface::{AppInput, AppOutput, MediaControl, Touch},
+    interface::{
+        AppInput, AppOutput, Backlight, Diagnostics, FirmwareVersion, MediaControl, PowerProfile,
+        Touch,
+    },
     App,
 };
 
 use crate::{
     battery::BATTERY_DATA,
-    ble::{APPLE_MEDIA_SERVICE_DATA, TIME_SERVICE_DATA},
-    display::SpiDisplay,
+    ble::{APPLE_MEDIA_SERVICE_DATA, BATTERY_PERCENT, BLE_STATE, FORGET_BONDS, TIME_SERVICE_DATA},
+    display::{SpiDisplay, DISPLAY_WRITES},
+    storage::{LOADED_SETTINGS, SAVE_SETTINGS},
     tick::TICK,
 };
 
@@ -30,9 +38,60 @@ pub static MEDIA_CONTROL: embassy_sync::channel::Channel<
     MediaControl,
     5,
 > = embassy_sync::channel::Channel::new();
+pub static VIBRATE: embassy_sync::signal::Signal<
+    embassy_sync::blocking_mutex::raw::ThreadModeRawMutex,
+    Duration,
+> = embassy_sync::signal::Signal::new();
+pub static BACKLIGHT: embassy_sync::signal::Signal<
+    embassy_sync::blocking_mutex::raw::ThreadModeRawMutex,
+    Backlight,
+> = embassy_sync::signal::Signal::new();
+pub static TICK_INTERVAL: embassy_sync::signal::Signal<
+    embassy_sync::blocking_mutex::raw::ThreadModeRawMutex,
+    Duration,
+> = embassy_sync::signal::Signal::new();
+/// Applied the next time advertising starts, or immediately if currently advertising.
+pub static ADVERTISING_INTERVAL: embassy_sync::signal::Signal<
+    embassy_sync::blocking_mutex::raw::ThreadModeRawMutex,
+    Duration,
+> = embassy_sync::signal::Signal::new();
+
+/// Size of the RAM ring buffer holding the most recent app inputs, when the
+/// `record-inputs` feature is enabled.
+#[cfg(feature = "record-inputs")]
+const RECORDING_LEN: usize = 8 * 1024;
+
+/// Spans of pixels remembered per display line, when the `frame-diff`
+/// feature is enabled.
+#[cfg(feature = "frame-diff")]
+const FRAME_DIFF_SLOTS: usize = 4;
+
+pub async fn run(display: SpiDisplay) -> ! {
+    #[cfg(feature = "frame-diff")]
+    let mut display = {
+        static SPANS: static_cell::StaticCell<SpanTable<FRAME_DIFF_SLOTS>> =
+            static_cell::StaticCell::new();
+        FrameDiff::new(display, SPANS.init(SpanTable::new()))
+    };
+    #[cfg(not(feature = "frame-diff"))]
+    let mut display = display;
+
+    #[cfg(feature = "record-inputs")]
+    let recorder = {
+        static RECORDER: static_cell::StaticCell<RingRecorder<RECORDING_LEN>> =
+            static_cell::StaticCell::new();
+        RECORDER.init(RingRecorder::new())
+    };
 
-pub async fn run(mut display: SpiDisplay) -> ! {
-    let mut app = App::init(&mut display, Instant::now().as_millis()).unwrap();
+    let init_ms_since_boot = Instant::now().as_millis();
+    #[cfg(feature = "record-inputs")]
+    recorder.record_init(init_ms_since_boot);
+    let mut app = App::init(&mut display, init_ms_since_boot).unwrap();
+    // Otherwise the end of the first frame isn't sent until the first event.
+    #[cfg(not(feature = "blocking-display"))]
+    crate::display::flush();
+    // The BLE task starts advertising with the normal profile's interval.
+    let mut advertising_interval_ms = PowerProfile::NORMAL.ble_advertising_interval_ms;
 
     loop {
         let event = match select(
@@ -50,7 +109,12 @@ pub async fn run(mut display: SpiDisplay) -> ! {
                 TIME_SERVICE_DATA.wait(),
                 TICK.wait(),
             ),
-            select(TOUCH_DATA.receive(), BUTTON_DATA.receive()),
+            select4(
+                TOUCH_DATA.receive(),
+                BUTTON_DATA.receive(),
+                BLE_STATE.wait(),
+                LOADED_SETTINGS.wait(),
+            ),
         )
         .await
         {
@@ -58,19 +122,71 @@ pub async fn run(mut display: SpiDisplay) -> ! {
             Either::First(Second(e)) => AppInput::Battery(e),
             Either::First(Third(current_time)) => AppInput::Time(current_time.into()),
             Either::First(Fourth(_)) => AppInput::Tick,
-            Either::Second(Either::First(touch)) => AppInput::Touch(touch),
-            Either::Second(Either::Second(_button_pressed)) => AppInput::ButtonPressed,
+            Either::Second(Either4::First(touch)) => AppInput::Touch(touch),
+            Either::Second(Either4::Second(_button_pressed)) => AppInput::ButtonPressed,
+            Either::Second(Either4::Third(ble)) => AppInput::Diagnostics(Diagnostics {
+                firmware_version: FirmwareVersion::from(env!("CARGO_PKG_VERSION")).unwrap(),
+                ble,
+                display_writes: DISPLAY_WRITES,
+            }),
+            Either::Second(Either4::Fourth(settings)) => AppInput::Settings(settings),
         };
         // Currently we are taking this timestamp to mean time when the event is being
         // handled. Is it more appropriate for it to mean time when the event was
         // captured? Do we need both of these times?
-        match app
-            .handle_event(&mut display, Instant::now().as_millis(), event)
-            .unwrap()
-        {
-            Some(AppOutput::MediaControl(control)) => MEDIA_CONTROL.send(control).await,
-            None => {
-                // no action to take
+        let ms_since_boot = Instant::now().as_millis();
+
+        #[cfg(feature = "record-inputs")]
+        recorder.record(ms_since_boot, &event);
+        // Logging the whole recording takes a while, so it is only dumped when
+        // asked for with a long press on the debug window, after reproducing
+        // a bug.
+        #[cfg(feature = "record-inputs")]
+        let dump_recording = app.active_window() == mesozoic_app::ActiveWindow::Debug
+            && matches!(
+                event,
+                AppInput::Touch(Touch {
+                    gesture: mesozoic_app::interface::Gesture::LongPress,
+                    ..
+                })
+            );
+
+        let outputs = app
+            .handle_event(&mut display, ms_since_boot, event)
+            .unwrap();
+        // The frame is finished, so there's nothing to wait for before
+        // sending the rest of it.
+        #[cfg(not(feature = "blocking-display"))]
+        crate::display::flush();
+        app.frame_sent(Instant::now().as_millis());
+
+        #[cfg(feature = "record-inputs")]
+        if dump_recording {
+            let (first, second) = recorder.as_slices();
+            defmt::info!("input recording: {=[u8]:02x}{=[u8]:02x}", first, second);
+        }
+
+        for output in outputs {
+            match output {
+                AppOutput::MediaControl(control) => MEDIA_CONTROL.send(control).await,
+                AppOutput::BatteryLevel(percent) => BATTERY_PERCENT.signal(percent),
+                AppOutput::Vibrate { duration_ms } => {
+                    VIBRATE.signal(Duration::from_millis(duration_ms.into()))
+                }
+                AppOutput::PowerProfile(profile) => {
+                    BACKLIGHT.signal(profile.backlight);
+                    TICK_INTERVAL.signal(Duration::from_millis(profile.tick_interval_ms.into()));
+                    // A new interval restarts advertising, so the interval is
+                    // only sent when it changes, rather than whenever the
+                    // screen turns on or off.
+                    if profile.ble_advertising_interval_ms != advertising_interval_ms {
+                        advertising_interval_ms = profile.ble_advertising_interval_ms;
+                        ADVERTISING_INTERVAL
+                            .signal(Duration::from_millis(advertising_interval_ms.into()));
+                    }
+                }
+                AppOutput::SaveSettings(settings) => SAVE_SETTINGS.signal(settings),
+                AppOutput::ForgetBonds => FORGET_BONDS.signal(()),
             }
         }
     }
@@ -12,7 +12,7 @@ mod event_loop;
 mod nrf;
 mod tasks;
 
-use tasks::{backlight, battery, ble, button, display, haptics, tick, touch};
+use tasks::{backlight, battery, ble, button, display, haptics, storage, tick, touch};
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
@@ -20,9 +20,12 @@ async fn main(spawner: Spawner) {
 
     unwrap!(spawner.spawn(backlight::task(p.P0_14, p.P0_22, p.P0_23)));
     unwrap!(spawner.spawn(battery::task(p.P0_12, p.SAADC, p.P0_31)));
-    unwrap!(spawner.spawn(ble::task(ble::init(&spawner).await)));
+    let ble = ble::init(&spawner).await;
+    let flash = nrf_softdevice::Flash::take(ble.softdevice());
+    unwrap!(spawner.spawn(ble::task(ble)));
     unwrap!(spawner.spawn(button::task(p.P0_13, p.P0_15)));
     unwrap!(spawner.spawn(haptics::task(p.P0_16)));
+    unwrap!(spawner.spawn(storage::task(flash)));
     unwrap!(spawner.spawn(tick::task()));
     unwrap!(spawner.spawn(touch::task(p.P0_10, p.P0_28, p.TWISPI0, p.P0_06, p.P0_07)));
 
@@ -2,16 +2,36 @@ use embassy_nrf::{
     gpio::{Level, Output, OutputDrive},
     peripherals::{P0_14, P0_22, P0_23},
 };
+use mesozoic_app::interface::{Backlight, PowerProfile};
+
+use crate::event_loop::BACKLIGHT;
 
 #[embassy_executor::task]
-pub async fn task(backlight_low_pin: P0_14, _backlight_mid_pin: P0_22, _backlight_high_pin: P0_23) {
-    // Turn on the backlight, then `forget` this pin to skip the drop implementation
-    // which resets the configuration.
-    core::mem::forget(Output::new(
-        backlight_low_pin,
-        // These pins are active low, so we are turning
-        // the backlight ON here.
-        Level::Low,
-        OutputDrive::Standard,
-    ));
+pub async fn task(backlight_low_pin: P0_14, backlight_mid_pin: P0_22, backlight_high_pin: P0_23) {
+    // These pins are active low, so they all start with the backlight OFF.
+    let mut low = Output::new(backlight_low_pin, Level::High, OutputDrive::Standard);
+    let mut mid = Output::new(backlight_mid_pin, Level::High, OutputDrive::Standard);
+    let mut high = Output::new(backlight_high_pin, Level::High, OutputDrive::Standard);
+
+    let mut backlight = PowerProfile::NORMAL.backlight;
+    loop {
+        let (low_on, mid_on, high_on) = match backlight {
+            Backlight::Off => (false, false, false),
+            Backlight::Low => (true, false, false),
+            Backlight::Medium => (false, true, false),
+            Backlight::High => (false, false, true),
+        };
+        low.set_level(active_low(low_on));
+        mid.set_level(active_low(mid_on));
+        high.set_level(active_low(high_on));
+
+        backlight = BACKLIGHT.wait().await;
+    }
+}
+
+fn active_low(on: bool) -> Level {
+    match on {
+        true => Level::Low,
+        false => Level::High,
+    }
 }
@@ -40,11 +40,12 @@ pub async fn task(
             2. * 3.3 * (buf[0] as f32) / 4095.
         };
 
-        BATTERY_DATA.signal(BatteryData {
+        let battery_data = BatteryData {
             // Charging indication is inverted, low means the battery is charging.
             charging: charging_indication_input.is_low(),
             voltage,
-        });
+        };
+        BATTERY_DATA.signal(battery_data);
 
         let _ = select(
             charging_indication_input.wait_for_any_edge(),
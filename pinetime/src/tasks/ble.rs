@@ -6,20 +6,26 @@ use core::{
 use arrayvec::ArrayVec;
 use defmt::{debug, info, unwrap};
 use embassy_executor::{SendSpawner, Spawner};
-use embassy_futures::select::{select, Either};
-use mesozoic_app::interface::{AppleMediaServiceData, AppleMediaServiceString, MediaControl};
+use embassy_futures::select::{select, select3, select4, Either, Either3, Either4};
+use embassy_time::Duration;
+use mesozoic_app::interface::{
+    AppleMediaServiceData, AppleMediaServiceString, BleState, MediaControl, PowerProfile,
+};
 use nrf_softdevice::ble::gatt_server::builder::ServiceBuilder;
 use nrf_softdevice::ble::gatt_server::characteristic::{Attribute, Metadata, Properties};
-use nrf_softdevice::ble::gatt_server::{set_sys_attrs, RegisterError, WriteOp};
+use nrf_softdevice::ble::gatt_server::{set_sys_attrs, NotifyValueError, RegisterError, WriteOp};
 use nrf_softdevice::ble::security::{IoCapabilities, SecurityHandler};
 use nrf_softdevice::ble::{
-    gatt_client, gatt_server, peripheral, Connection, EncryptionInfo, GattValue, IdentityKey,
-    MasterId, SecurityMode, Uuid,
+    gatt_client, gatt_server, peripheral, Address, Connection, EncryptionInfo, GattValue,
+    IdentityKey, IdentityResolutionKey, MasterId, SecurityMode, Uuid,
 };
-use nrf_softdevice::{raw, Softdevice};
+use nrf_softdevice::{raw, RawError, Softdevice};
 use static_cell::StaticCell;
 
-use crate::event_loop::MEDIA_CONTROL;
+use crate::{
+    event_loop::{ADVERTISING_INTERVAL, MEDIA_CONTROL},
+    storage::{BondBytes, LOADED_BOND, SAVE_BOND},
+};
 
 pub static APPLE_MEDIA_SERVICE_DATA: embassy_sync::signal::Signal<
     embassy_sync::blocking_mutex::raw::ThreadModeRawMutex,
@@ -31,12 +37,37 @@ pub static TIME_SERVICE_DATA: embassy_sync::signal::Signal<
     CurrentTime,
 > = embassy_sync::signal::Signal::new();
 
+/// The battery percentage shown on the watch, for the battery service.
+pub static BATTERY_PERCENT: embassy_sync::signal::Signal<
+    embassy_sync::blocking_mutex::raw::ThreadModeRawMutex,
+    u8,
+> = embassy_sync::signal::Signal::new();
+
+pub static BLE_STATE: embassy_sync::signal::Signal<
+    embassy_sync::blocking_mutex::raw::ThreadModeRawMutex,
+    BleState,
+> = embassy_sync::signal::Signal::new();
+
+/// Forgets the bonded phone, disconnecting it if it is connected.
+pub static FORGET_BONDS: embassy_sync::signal::Signal<
+    embassy_sync::blocking_mutex::raw::ThreadModeRawMutex,
+    (),
+> = embassy_sync::signal::Signal::new();
+
 pub struct TaskParams {
     sd: &'static Softdevice,
     server: Server,
     spawner: SendSpawner,
 }
 
+impl TaskParams {
+    /// For other SoftDevice functionality, such as flash, which must go
+    /// through the SoftDevice while it is enabled.
+    pub fn softdevice(&self) -> &'static Softdevice {
+        self.sd
+    }
+}
+
 const BATTERY_SERVICE: Uuid = Uuid::new_16(0x180f);
 const BATTERY_LEVEL: Uuid = Uuid::new_16(0x2a19);
 
@@ -52,6 +83,57 @@ struct Peer {
     peer_id: IdentityKey,
 }
 
+/// Changed whenever the encoding of a bond changes. Bonds saved with another
+/// version are dropped, and the phone has to pair again.
+const BOND_VERSION: u8 = 1;
+
+impl Peer {
+    const ENCODED_LEN: usize = 50;
+
+    fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        let mut bytes = [0; Self::ENCODED_LEN];
+        bytes[0..2].copy_from_slice(&self.master_id.ediv.to_le_bytes());
+        bytes[2..10].copy_from_slice(&self.master_id.rand);
+        bytes[10..26].copy_from_slice(&self.key.ltk);
+        bytes[26] = self.key.flags;
+        bytes[27..43].copy_from_slice(&self.peer_id.irk.as_raw().irk);
+        bytes[43] = self.peer_id.addr.flags;
+        bytes[44..50].copy_from_slice(&self.peer_id.addr.bytes);
+        bytes
+    }
+
+    fn decode(bytes: &[u8; Self::ENCODED_LEN]) -> Self {
+        let mut rand = [0; 8];
+        rand.copy_from_slice(&bytes[2..10]);
+        let mut ltk = [0; 16];
+        ltk.copy_from_slice(&bytes[10..26]);
+        let mut irk = [0; 16];
+        irk.copy_from_slice(&bytes[27..43]);
+        let mut addr = [0; 6];
+        addr.copy_from_slice(&bytes[44..50]);
+
+        Peer {
+            master_id: MasterId {
+                ediv: u16::from_le_bytes([bytes[0], bytes[1]]),
+                rand,
+            },
+            key: EncryptionInfo {
+                ltk,
+                flags: bytes[26],
+            },
+            peer_id: IdentityKey {
+                irk: IdentityResolutionKey::from_raw(raw::ble_gap_irk_t { irk }),
+                addr: Address {
+                    flags: bytes[43],
+                    bytes: addr,
+                },
+            },
+        }
+    }
+}
+
+/// Keeps a single bonded phone, saving it to flash through the storage task
+/// whenever it changes.
 pub struct Bonder {
     peer: Cell<Option<Peer>>,
     sys_attrs: RefCell<ArrayVec<u8, 62>>,
@@ -66,14 +148,61 @@ impl Default for Bonder {
     }
 }
 
+impl Bonder {
+    /// Restores a bond saved by `encode`, or no bond if it can't be decoded.
+    fn load(&self, bytes: &[u8]) {
+        let Some((&BOND_VERSION, rest)) = bytes.split_first() else {
+            return;
+        };
+        let Some((peer, sys_attrs)) = rest.split_first_chunk::<{ Peer::ENCODED_LEN }>() else {
+            return;
+        };
+        let Ok(sys_attrs) = ArrayVec::try_from(sys_attrs) else {
+            return;
+        };
+
+        self.peer.set(Some(Peer::decode(peer)));
+        *self.sys_attrs.borrow_mut() = sys_attrs;
+    }
+
+    /// The version, peer and system attributes, or nothing if there is no
+    /// bond.
+    fn encode(&self) -> BondBytes {
+        let mut bytes = BondBytes::new();
+        if let Some(peer) = self.peer.get() {
+            bytes.push(BOND_VERSION);
+            bytes.extend(peer.encode());
+            bytes.extend(self.sys_attrs.borrow().iter().copied());
+        }
+        bytes
+    }
+
+    /// Whether `conn` is with the bonded phone.
+    fn is_bonded(&self, conn: &Connection) -> bool {
+        self.peer
+            .get()
+            .is_some_and(|peer| peer.peer_id.is_match(conn.peer_address()))
+    }
+
+    fn save(&self) {
+        SAVE_BOND.signal(self.encode());
+    }
+
+    fn forget(&self) {
+        info!("forgetting bond");
+        self.peer.set(None);
+        self.sys_attrs.borrow_mut().clear();
+        self.save();
+    }
+}
+
 impl SecurityHandler for Bonder {
     fn io_capabilities(&self) -> IoCapabilities {
         IoCapabilities::None
     }
 
     fn can_bond(&self, _conn: &Connection) -> bool {
-        // default was true here, am I breaking something?
-        false
+        true
     }
 
     fn display_passkey(&self, passkey: &[u8; 6]) {
@@ -89,13 +218,14 @@ impl SecurityHandler for Bonder {
     ) {
         debug!("storing bond for: id: {}, key: {}", master_id, key);
 
-        // In a real application you would want to signal another task to permanently store the keys in non-volatile memory here.
+        // Only one phone is remembered, so bonding replaces any earlier bond.
         self.sys_attrs.borrow_mut().clear();
         self.peer.set(Some(Peer {
             master_id,
             key,
             peer_id,
         }));
+        self.save();
     }
 
     fn get_key(&self, _conn: &Connection, master_id: MasterId) -> Option<EncryptionInfo> {
@@ -109,17 +239,16 @@ impl SecurityHandler for Bonder {
     fn save_sys_attrs(&self, conn: &Connection) {
         debug!("saving system attributes for: {}", conn.peer_address());
 
-        if let Some(peer) = self.peer.get() {
-            if peer.peer_id.is_match(conn.peer_address()) {
-                let mut sys_attrs = self.sys_attrs.borrow_mut();
-                let capacity = sys_attrs.capacity();
-                unsafe {
-                    sys_attrs.set_len(capacity);
-                }
-                let len = unwrap!(gatt_server::get_sys_attrs(conn, &mut sys_attrs)) as u16;
-                sys_attrs.truncate(usize::from(len));
-                // In a real application you would want to signal another task to permanently store sys_attrs for this connection's peer
+        if self.is_bonded(conn) {
+            let mut sys_attrs = self.sys_attrs.borrow_mut();
+            let capacity = sys_attrs.capacity();
+            unsafe {
+                sys_attrs.set_len(capacity);
             }
+            let len = unwrap!(gatt_server::get_sys_attrs(conn, &mut sys_attrs)) as u16;
+            sys_attrs.truncate(usize::from(len));
+            drop(sys_attrs);
+            self.save();
         }
     }
 
@@ -128,13 +257,7 @@ impl SecurityHandler for Bonder {
         debug!("loading system attributes for: {}", addr);
 
         let attrs = self.sys_attrs.borrow();
-        // In a real application you would search all stored peers to find a match
-        let attrs = if self
-            .peer
-            .get()
-            .map(|peer| peer.peer_id.is_match(addr))
-            .unwrap_or(false)
-        {
+        let attrs = if self.is_bonded(conn) {
             (!attrs.is_empty()).then_some(attrs.as_slice())
         } else {
             None
@@ -145,13 +268,16 @@ impl SecurityHandler for Bonder {
 }
 
 pub struct BatteryService {
+    value_handle: u16,
     cccd_handle: u16,
+    notifications_enabled: Cell<bool>,
 }
 
 impl BatteryService {
     pub fn new(sd: &mut Softdevice) -> Result<Self, RegisterError> {
         let mut service_builder = ServiceBuilder::new(sd, BATTERY_SERVICE)?;
 
+        // The value is updated as soon as the app has estimated the level.
         let attr = Attribute::new(&[0u8]).security(SecurityMode::JustWorks);
         let metadata = Metadata::new(Properties::new().read().notify());
         let characteristic_builder =
@@ -161,13 +287,50 @@ impl BatteryService {
         let _service_handle = service_builder.build();
 
         Ok(BatteryService {
+            value_handle: characteristic_handles.value_handle,
             cccd_handle: characteristic_handles.cccd_handle,
+            notifications_enabled: Cell::new(false),
         })
     }
 
     pub fn on_write(&self, handle: u16, data: &[u8]) {
         if handle == self.cccd_handle && !data.is_empty() {
-            info!("battery notifications: {}", (data[0] & 0x01) != 0);
+            let enabled = (data[0] & 0x01) != 0;
+            info!("battery notifications: {}", enabled);
+            self.notifications_enabled.set(enabled);
+        }
+    }
+
+    /// A bonded phone's subscription is restored along with its system
+    /// attributes, without it writing the CCCD again, so it is assumed to
+    /// still be subscribed until a notification fails. Anything else has to
+    /// subscribe.
+    pub fn on_connect(&self, bonded: bool) {
+        self.notifications_enabled.set(bonded);
+    }
+
+    /// Keeps the battery level attribute up to date with the level shown on
+    /// the watch, notifying the connected central (if any, and if
+    /// subscribed) when it changes.
+    pub async fn run(&self, sd: &Softdevice, conn: Option<&Connection>) -> ! {
+        loop {
+            let percent = BATTERY_PERCENT.wait().await;
+
+            unwrap!(gatt_server::set_value(sd, self.value_handle, &[percent]));
+
+            if let Some(conn) = conn {
+                if self.notifications_enabled.get() {
+                    match gatt_server::notify_value(conn, self.value_handle, &[percent]) {
+                        Ok(()) => {}
+                        // The CCCD doesn't have notifications enabled.
+                        Err(NotifyValueError::Raw(RawError::InvalidState)) => {
+                            info!("battery notifications: false");
+                            self.notifications_enabled.set(false);
+                        }
+                        Err(e) => info!("battery notification failed: {:?}", e),
+                    }
+                }
+            }
         }
     }
 }
@@ -342,7 +505,7 @@ pub async fn task_gatt_client(conn: Connection) {
 
     // flush media control, because we don't want to act on commands received before pairing
     loop {
-        if let Err(_) = MEDIA_CONTROL.try_receive() {
+        if MEDIA_CONTROL.try_receive().is_err() {
             break;
         }
     }
@@ -519,22 +682,74 @@ pub async fn task(state: TaskParams) {
 
     static BONDER: StaticCell<Bonder> = StaticCell::new();
     let bonder = BONDER.init(Bonder::default());
+    // The bonded phone can't reconnect until its keys are loaded.
+    bonder.load(&LOADED_BOND.wait().await);
+
+    let mut advertising_interval =
+        Duration::from_millis(PowerProfile::NORMAL.ble_advertising_interval_ms.into());
 
     loop {
-        let config = peripheral::Config::default();
+        let config = peripheral::Config {
+            // The interval is configured in units of 0.625ms.
+            interval: (advertising_interval.as_micros() / 625) as u32,
+            ..Default::default()
+        };
         let adv = peripheral::ConnectableAdvertisement::ScannableUndirected {
             adv_data,
             scan_data,
         };
-        let conn = unwrap!(peripheral::advertise_pairable(sd, adv, &config, bonder).await);
+        BLE_STATE.signal(BleState::Advertising);
+
+        // The battery level is kept up to date while advertising, so it is
+        // correct as soon as a central connects. If the advertising interval
+        // changes, advertising is restarted with the new interval.
+        let conn = match select4(
+            peripheral::advertise_pairable(sd, adv, &config, bonder),
+            server.bas.run(sd, None),
+            ADVERTISING_INTERVAL.wait(),
+            FORGET_BONDS.wait(),
+        )
+        .await
+        {
+            Either4::First(conn) => unwrap!(conn),
+            Either4::Second(never) => never,
+            Either4::Third(new_advertising_interval) => {
+                advertising_interval = new_advertising_interval;
+                continue;
+            }
+            Either4::Fourth(()) => {
+                bonder.forget();
+                continue;
+            }
+        };
+
+        // The softdevice stores addresses least significant byte first.
+        let mut peer_address = conn.peer_address().bytes();
+        peer_address.reverse();
+        BLE_STATE.signal(BleState::Connected { peer_address });
+        server.bas.on_connect(bonder.is_bonded(&conn));
 
         unwrap!(spawner.spawn(task_gatt_client(conn.clone())));
 
         // Run the GATT server on the connection. This returns when the connection gets disconnected.
-        let e = gatt_server::run(&conn.clone(), &server, |_| {
+        let gatt_server = gatt_server::run(&conn, &server, |_| {
             // Do nothing
-        })
-        .await;
+        });
+        // Forgetting the bond disconnects the phone, which then ends the
+        // GATT server.
+        let forget = async {
+            loop {
+                FORGET_BONDS.wait().await;
+                bonder.forget();
+                // The phone may have disconnected already.
+                let _ = conn.disconnect();
+            }
+        };
+        let e = match select3(gatt_server, server.bas.run(sd, Some(&conn)), forget).await {
+            Either3::First(e) => e,
+            Either3::Second(never) => never,
+            Either3::Third(never) => never,
+        };
         info!("gatt_server run exited with error: {:?}", e);
     }
 }
@@ -1,15 +1,30 @@
+//! The ST7789 display, on SPIM1.
+//!
+//! By default pixels are sent with EasyDMA in the background. `DmaInterface`
+//! copies the bytes `mipidsi` sends into one of two chunks, and hands each
+//! chunk to `task` once it is full or the data/command pin has to change,
+//! and `task` sends it while the next one is filled. The event loop calls
+//! `flush` at the end of each frame to send the last chunk. It only waits
+//! for the display when both chunks are in use, so drawing overlaps with
+//! sending, and the last chunk of a frame is still being sent when the
+//! event loop goes back to waiting for events.
+//!
+//! The `blocking-display` feature sends each write before returning instead,
+//! to compare frame rates in the diagnostics window.
+
 use embassy_nrf::{
     bind_interrupts,
     gpio::{Level, Output, OutputDrive},
     peripherals::{P0_02, P0_03, P0_04, P0_14, P0_18, P0_25, TWISPI1},
     spim::{self, Spim},
 };
-use mesozoic_app::interface::{LCD_H, LCD_W};
+use mesozoic_app::interface::{DisplayWrites, LCD_H, LCD_W};
 
 bind_interrupts!(struct Irqs {
     SPIM1_SPIS1_TWIM1_TWIS1_SPI1_TWI1 => spim::InterruptHandler<TWISPI1>;
 });
 
+#[cfg(feature = "blocking-display")]
 pub type SpiDisplay = mipidsi::Display<
     display_interface_spi::SPIInterface<
         Spim<'static, TWISPI1>,
@@ -19,6 +34,15 @@ pub type SpiDisplay = mipidsi::Display<
     mipidsi::models::ST7789,
     Output<'static, P0_14>,
 >;
+#[cfg(not(feature = "blocking-display"))]
+pub type SpiDisplay =
+    mipidsi::Display<DmaInterface, mipidsi::models::ST7789, Output<'static, P0_14>>;
+
+/// Reported to the app for the diagnostics window.
+#[cfg(feature = "blocking-display")]
+pub const DISPLAY_WRITES: DisplayWrites = DisplayWrites::Blocking;
+#[cfg(not(feature = "blocking-display"))]
+pub const DISPLAY_WRITES: DisplayWrites = DisplayWrites::Dma;
 
 pub fn create(
     dc_pin: P0_18,
@@ -36,18 +60,249 @@ pub fn create(
 
         c
     };
+    #[cfg(not(feature = "blocking-display"))]
+    dma::set_interrupt_priorities();
     let display_spi = Spim::new(spim, Irqs, sck_pin, miso_pin, mosi_pin, display_spi_config);
 
     let display_dc = Output::new(dc_pin, Level::Low, OutputDrive::Standard);
     let display_cs = Output::new(cs_pin, Level::Low, OutputDrive::Standard);
+    #[cfg(feature = "blocking-display")]
     let display_interface =
         display_interface_spi::SPIInterface::new(display_spi, display_dc, display_cs);
+    #[cfg(not(feature = "blocking-display"))]
+    let display_interface = dma::start(display_spi, display_dc, display_cs);
+
+    #[cfg(feature = "blocking-display")]
+    let mut delay = embassy_time::Delay;
+    #[cfg(not(feature = "blocking-display"))]
+    let mut delay = dma::SendingDelay;
 
     // This unwrap is safe, because we pass a None in for the RST pin.
     mipidsi::Builder::st7789(display_interface)
         .with_display_size(LCD_W, LCD_H)
         .with_orientation(mipidsi::Orientation::Portrait(false))
         .with_invert_colors(mipidsi::ColorInversion::Inverted)
-        .init(&mut embassy_time::Delay, None::<Output<'static, P0_14>>)
+        .init(&mut delay, None::<Output<'static, P0_14>>)
         .unwrap()
 }
+
+#[cfg(not(feature = "blocking-display"))]
+pub use dma::{flush, DmaInterface};
+
+#[cfg(not(feature = "blocking-display"))]
+mod dma {
+    use core::cell::RefCell;
+
+    use arrayvec::ArrayVec;
+    use defmt::unwrap;
+    use display_interface::{DataFormat, DisplayError, WriteOnlyDataCommand};
+    use embassy_executor::InterruptExecutor;
+    use embassy_nrf::{
+        gpio::Output,
+        // Both the module and the attribute macro.
+        interrupt,
+        interrupt::{InterruptExt, Priority},
+        peripherals::{P0_18, P0_25, TWISPI1},
+        spim::Spim,
+    };
+    use embassy_sync::{
+        blocking_mutex::{
+            raw::{CriticalSectionRawMutex, ThreadModeRawMutex},
+            Mutex,
+        },
+        channel::Channel,
+    };
+    use embedded_hal::blocking::delay::DelayUs;
+    use mesozoic_app::interface::LCD_W;
+
+    /// Half a line of pixels. A single EasyDMA transfer on the nRF52832 is
+    /// limited to `embassy_nrf::EASY_DMA_SIZE`, 255 bytes.
+    const CHUNK_LEN: usize = LCD_W as usize;
+
+    /// Bytes to send to the display, which are either all commands or all
+    /// data, as the data/command pin can only change between transfers.
+    pub struct Chunk {
+        is_command: bool,
+        bytes: ArrayVec<u8, CHUNK_LEN>,
+    }
+
+    /// Each chunk is always in exactly one of these, or in `FILLING`, so
+    /// neither channel can be full.
+    static FREE_CHUNKS: Channel<CriticalSectionRawMutex, &'static mut Chunk, 2> = Channel::new();
+    static FILLED_CHUNKS: Channel<CriticalSectionRawMutex, &'static mut Chunk, 2> = Channel::new();
+
+    /// The chunk being filled, if any. It is kept here rather than in
+    /// `DmaInterface` so `flush` can send it, as `mipidsi` doesn't give the
+    /// interface back until the display is released.
+    static FILLING: Mutex<ThreadModeRawMutex, RefCell<Option<&'static mut Chunk>>> =
+        Mutex::new(RefCell::new(None));
+
+    /// Runs `task`, so it can send chunks while the event loop, on the thread
+    /// mode executor, is busy drawing.
+    static EXECUTOR: InterruptExecutor = InterruptExecutor::new();
+
+    #[interrupt]
+    unsafe fn SWI0_EGU0() {
+        EXECUTOR.on_interrupt()
+    }
+
+    /// The SoftDevice reserves priorities 0, 1 and 4. The transfer must be
+    /// able to interrupt the event loop, but not the GPIOTE and time
+    /// interrupts at P2.
+    pub(super) fn set_interrupt_priorities() {
+        interrupt::SWI0_EGU0.set_priority(Priority::P3);
+        interrupt::SPIM1_SPIS1_TWIM1_TWIS1_SPI1_TWI1.set_priority(Priority::P3);
+    }
+
+    pub(super) fn start(
+        spi: Spim<'static, TWISPI1>,
+        dc: Output<'static, P0_18>,
+        cs: Output<'static, P0_25>,
+    ) -> DmaInterface {
+        static CHUNKS: static_cell::StaticCell<[Chunk; 2]> = static_cell::StaticCell::new();
+        let chunks = CHUNKS.init(core::array::from_fn(|_| Chunk {
+            is_command: false,
+            bytes: ArrayVec::new(),
+        }));
+        for chunk in chunks.iter_mut() {
+            assert!(FREE_CHUNKS.try_send(chunk).is_ok());
+        }
+
+        let spawner = EXECUTOR.start(interrupt::SWI0_EGU0);
+        unwrap!(spawner.spawn(task(spi, dc, cs)));
+
+        DmaInterface { _private: () }
+    }
+
+    #[embassy_executor::task]
+    async fn task(
+        mut spi: Spim<'static, TWISPI1>,
+        mut dc: Output<'static, P0_18>,
+        // The display is the only device on the bus, so it stays selected.
+        _cs: Output<'static, P0_25>,
+    ) {
+        loop {
+            let chunk = FILLED_CHUNKS.receive().await;
+
+            match chunk.is_command {
+                true => dc.set_low(),
+                false => dc.set_high(),
+            }
+            // The chunk is in RAM, and no longer than EASY_DMA_SIZE, so this
+            // can't fail.
+            unwrap!(spi.write(&chunk.bytes).await);
+
+            FREE_CHUNKS.send(chunk).await;
+        }
+    }
+
+    pub struct DmaInterface {
+        _private: (),
+    }
+
+    /// Starts sending the bytes written since the last full chunk, rather
+    /// than waiting for more. Should be called at the end of each frame.
+    pub fn flush() {
+        FILLING.lock(|filling| send_filling(&mut filling.borrow_mut()));
+    }
+
+    /// Waits for every chunk to be sent, after `flush`.
+    fn wait_until_sent() {
+        // Each chunk is free once it has been sent, so holding both means
+        // nothing is left to send.
+        let chunks = [take_free_chunk(false), take_free_chunk(false)];
+        for chunk in chunks {
+            assert!(FREE_CHUNKS.try_send(chunk).is_ok());
+        }
+    }
+
+    /// Used while `mipidsi` sets up the display. The panel needs time after
+    /// some commands, such as a reset, before it accepts the next, so the
+    /// commands written before each delay are sent before it starts.
+    pub(super) struct SendingDelay;
+
+    impl DelayUs<u32> for SendingDelay {
+        fn delay_us(&mut self, us: u32) {
+            flush();
+            wait_until_sent();
+            embassy_time::Delay.delay_us(us);
+        }
+    }
+
+    /// Queues the chunk being filled to be sent.
+    fn send_filling(filling: &mut Option<&'static mut Chunk>) {
+        if let Some(chunk) = filling.take() {
+            assert!(FILLED_CHUNKS.try_send(chunk).is_ok());
+        }
+    }
+
+    fn write(is_command: bool, mut bytes: &[u8]) {
+        FILLING.lock(|filling| {
+            let mut filling = filling.borrow_mut();
+            while !bytes.is_empty() {
+                let chunk = match &mut *filling {
+                    Some(chunk) if chunk.is_command == is_command && !chunk.bytes.is_full() => {
+                        chunk
+                    }
+                    // The data/command pin can only change between transfers.
+                    _ => {
+                        send_filling(&mut filling);
+                        filling.insert(take_free_chunk(is_command))
+                    }
+                };
+
+                let len = bytes.len().min(chunk.bytes.remaining_capacity());
+                // The unwrap is safe because len fits in the remaining capacity.
+                chunk.bytes.try_extend_from_slice(&bytes[..len]).unwrap();
+                bytes = &bytes[len..];
+            }
+        })
+    }
+
+    fn send(is_command: bool, words: DataFormat<'_>) -> Result<(), DisplayError> {
+        // These are the formats `mipidsi` uses for the ST7789.
+        match words {
+            DataFormat::U8(bytes) => write(is_command, bytes),
+            DataFormat::U8Iter(bytes) => {
+                for byte in bytes {
+                    write(is_command, &[byte]);
+                }
+            }
+            DataFormat::U16BEIter(words) => {
+                for word in words {
+                    write(is_command, &word.to_be_bytes());
+                }
+            }
+            _ => return Err(DisplayError::DataFormatNotImplemented),
+        }
+
+        Ok(())
+    }
+
+    /// Waits for `task` to finish sending a chunk, if both are in use.
+    fn take_free_chunk(is_command: bool) -> &'static mut Chunk {
+        // `task` runs from a higher priority interrupt, and returning from
+        // it wakes the core, so there's no need to spin. The event register
+        // is set if the interrupt comes between `try_receive` and `wfe`.
+        let chunk = loop {
+            if let Ok(chunk) = FREE_CHUNKS.try_receive() {
+                break chunk;
+            }
+            cortex_m::asm::wfe();
+        };
+        chunk.is_command = is_command;
+        chunk.bytes.clear();
+
+        chunk
+    }
+
+    impl WriteOnlyDataCommand for DmaInterface {
+        fn send_commands(&mut self, cmds: DataFormat<'_>) -> Result<(), DisplayError> {
+            send(true, cmds)
+        }
+
+        fn send_data(&mut self, buf: DataFormat<'_>) -> Result<(), DisplayError> {
+            send(false, buf)
+        }
+    }
+}
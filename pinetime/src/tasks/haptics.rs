@@ -1,6 +1,21 @@
-use embassy_nrf::peripherals::P0_16;
+use embassy_nrf::{
+    gpio::{Level, Output, OutputDrive},
+    peripherals::P0_16,
+};
+use embassy_time::Timer;
+
+use crate::event_loop::VIBRATE;
 
 #[embassy_executor::task]
-pub async fn task(_vibration_motor_pin: P0_16) {
-    // Placeholder task for haptics task
+pub async fn task(vibration_motor_pin: P0_16) {
+    // The motor is active low, so it starts OFF.
+    let mut motor = Output::new(vibration_motor_pin, Level::High, OutputDrive::Standard);
+
+    loop {
+        let duration = VIBRATE.wait().await;
+
+        motor.set_low();
+        Timer::after(duration).await;
+        motor.set_high();
+    }
 }
@@ -4,5 +4,6 @@ pub(crate) mod ble;
 pub(crate) mod button;
 pub(crate) mod display;
 pub(crate) mod haptics;
+pub(crate) mod storage;
 pub(crate) mod tick;
 pub(crate) mod touch;
@@ -0,0 +1,133 @@
+//! Data kept across reboots, in a `KvStore` in the `STORAGE` region of
+//! `memory.x`.
+
+use core::ops::Range;
+
+use arrayvec::ArrayVec;
+use defmt::{info, warn, Debug2Format};
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Timer};
+use mesozoic_app::{
+    kv_store::{Key, KvStore, MAX_VALUE_LEN},
+    settings::Settings,
+};
+use nrf_softdevice::Flash;
+
+/// The settings read from flash at boot, or the defaults if none were saved.
+pub static LOADED_SETTINGS: embassy_sync::signal::Signal<
+    embassy_sync::blocking_mutex::raw::ThreadModeRawMutex,
+    Settings,
+> = embassy_sync::signal::Signal::new();
+
+pub static SAVE_SETTINGS: embassy_sync::signal::Signal<
+    embassy_sync::blocking_mutex::raw::ThreadModeRawMutex,
+    Settings,
+> = embassy_sync::signal::Signal::new();
+
+/// A bond with a phone, encoded by the BLE task. Empty if there is no bond.
+pub type BondBytes = ArrayVec<u8, MAX_VALUE_LEN>;
+
+/// The bond read from flash at boot.
+pub static LOADED_BOND: embassy_sync::signal::Signal<
+    embassy_sync::blocking_mutex::raw::ThreadModeRawMutex,
+    BondBytes,
+> = embassy_sync::signal::Signal::new();
+
+pub static SAVE_BOND: embassy_sync::signal::Signal<
+    embassy_sync::blocking_mutex::raw::ThreadModeRawMutex,
+    BondBytes,
+> = embassy_sync::signal::Signal::new();
+
+/// `STORAGE` in `memory.x`.
+const REGION: Range<u32> = 0x0007_C000..0x0008_0000;
+
+/// Keys in the store. Old values may still be in flash, so a key must never
+/// be reused for something else.
+const SETTINGS_KEY: Key = 1;
+const BOND_KEY: Key = 2;
+
+/// Cycling through the choices for a setting changes it several times in a
+/// row, so settings are only written once they've been left alone this long.
+const SAVE_DELAY: Duration = Duration::from_secs(5);
+
+#[embassy_executor::task]
+pub async fn task(flash: Flash) {
+    let mut store = match KvStore::mount(flash, REGION).await {
+        Ok(store) => store,
+        Err(e) => {
+            warn!("failed to mount storage: {}", Debug2Format(&e));
+            LOADED_SETTINGS.signal(Settings::default());
+            LOADED_BOND.signal(BondBytes::new());
+            return;
+        }
+    };
+
+    let mut saved_settings = load_settings(&mut store).await;
+    LOADED_SETTINGS.signal(saved_settings);
+    let mut saved_bond = load_bond(&mut store).await;
+    LOADED_BOND.signal(saved_bond.clone());
+
+    loop {
+        match select(SAVE_SETTINGS.wait(), SAVE_BOND.wait()).await {
+            Either::First(mut settings) => {
+                while let Either::First(newer) =
+                    select(SAVE_SETTINGS.wait(), Timer::after(SAVE_DELAY)).await
+                {
+                    settings = newer;
+                }
+
+                // Changing a setting and then changing it back needs no write.
+                if settings != saved_settings {
+                    match store.set(SETTINGS_KEY, &settings.encode()).await {
+                        Ok(()) => saved_settings = settings,
+                        Err(e) => warn!("failed to save settings: {}", Debug2Format(&e)),
+                    }
+                }
+            }
+            Either::Second(bond) => {
+                // The system attributes are saved on every disconnection,
+                // but rarely change.
+                if bond != saved_bond {
+                    let result = if bond.is_empty() {
+                        store.remove(BOND_KEY).await
+                    } else {
+                        store.set(BOND_KEY, &bond).await
+                    };
+                    match result {
+                        Ok(()) => saved_bond = bond,
+                        Err(e) => warn!("failed to save bond: {}", Debug2Format(&e)),
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn load_settings(store: &mut KvStore<Flash>) -> Settings {
+    // Settings saved by a newer version may be longer than they are now.
+    let mut buf = [0; MAX_VALUE_LEN];
+    match store.get(SETTINGS_KEY, &mut buf).await {
+        Ok(Some(bytes)) => Settings::decode(bytes).unwrap_or_else(|e| {
+            info!("using default settings: {}", Debug2Format(&e));
+            Settings::default()
+        }),
+        Ok(None) => Settings::default(),
+        Err(e) => {
+            warn!("failed to read settings: {}", Debug2Format(&e));
+            Settings::default()
+        }
+    }
+}
+
+async fn load_bond(store: &mut KvStore<Flash>) -> BondBytes {
+    let mut buf = [0; MAX_VALUE_LEN];
+    match store.get(BOND_KEY, &mut buf).await {
+        // Values are never longer than `MAX_VALUE_LEN`.
+        Ok(Some(bytes)) => BondBytes::try_from(bytes).unwrap_or_default(),
+        Ok(None) => BondBytes::new(),
+        Err(e) => {
+            warn!("failed to read bond: {}", Debug2Format(&e));
+            BondBytes::new()
+        }
+    }
+}
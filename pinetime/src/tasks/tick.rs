@@ -1,19 +1,22 @@
+use embassy_futures::select::{select, Either};
 use embassy_time::{Duration, Timer};
+use mesozoic_app::interface::PowerProfile;
+
+use crate::event_loop::TICK_INTERVAL;
 
 pub static TICK: embassy_sync::signal::Signal<
     embassy_sync::blocking_mutex::raw::ThreadModeRawMutex,
     (),
 > = embassy_sync::signal::Signal::new();
 
-/// This value is turned up to allow watching maximum fps.
-///
-/// Something like 10hz is probably more appropriate.
-const DISPLAY_ON_TICK_DURATION: Duration = Duration::from_hz(60);
-
 #[embassy_executor::task]
 pub async fn task() {
+    let mut tick_interval = Duration::from_millis(PowerProfile::NORMAL.tick_interval_ms.into());
+
     loop {
-        Timer::after(DISPLAY_ON_TICK_DURATION).await;
-        TICK.signal(());
+        match select(Timer::after(tick_interval), TICK_INTERVAL.wait()).await {
+            Either::First(_) => TICK.signal(()),
+            Either::Second(new_tick_interval) => tick_interval = new_tick_interval,
+        }
     }
 }
@@ -1,11 +1,16 @@
 use defmt::{info, unwrap};
+use embassy_futures::select::{select, Either};
 use embassy_nrf::{
     bind_interrupts,
     gpio::{Input, Pull},
     peripherals::{P0_06, P0_07, P0_10, P0_28, TWISPI0},
     twim::{self, Twim},
 };
-use mesozoic_app::interface::Touch;
+use embassy_time::{Instant, Timer};
+use mesozoic_app::{
+    gesture::{ControllerGestures, GestureConfig},
+    interface::Touch,
+};
 
 use crate::event_loop::TOUCH_DATA;
 
@@ -24,6 +29,7 @@ pub async fn task(
     mut scl_pin: P0_07,
 ) {
     let mut interrupt_pin = Input::new(interrupt_pin, Pull::None);
+    let mut gestures = ControllerGestures::new(GestureConfig::default());
 
     loop {
         // Using the falling edge here is a guess. I have not confirmed this is the
@@ -31,34 +37,57 @@ pub async fn task(
         // as rising edge.
         //
         // TODO test this further, or find documentation to confirm
-        interrupt_pin.wait_for_falling_edge().await;
-        info!("Touch controller awake, reading data..");
+        //
+        // When the recognizer is waiting on the passage of time (for example to
+        // tell a long press from a tap) we also wake up at its deadline.
+        let touch_interrupt = match gestures.next_deadline() {
+            Some(deadline) => matches!(
+                select(
+                    interrupt_pin.wait_for_falling_edge(),
+                    Timer::at(Instant::from_millis(deadline)),
+                )
+                .await,
+                Either::First(_)
+            ),
+            None => {
+                interrupt_pin.wait_for_falling_edge().await;
+                true
+            }
+        };
 
-        // The twi peripheral is re-created for each read, so it can be dropped after we are
-        // done. This saves power while we aren't actively communicating with the touch controller.
-        let mut twi = Twim::new(
-            &mut twi,
-            Irqs,
-            &mut sda_pin,
-            &mut scl_pin,
-            twim::Config::default(),
-        );
+        if touch_interrupt {
+            info!("Touch controller awake, reading data..");
 
-        // TODO all unwraps here should be replaced with proper error handling
-        let starting_addr = 0;
-        let mut buf = [0u8; 7];
-        unwrap!(
-            twi.write_read(TOUCH_CONTROLLER_ADDR, &[starting_addr], &mut buf)
-                .await
-        );
+            // The twi peripheral is re-created for each read, so it can be dropped after we are
+            // done. This saves power while we aren't actively communicating with the touch controller.
+            let mut twi = Twim::new(
+                &mut twi,
+                Irqs,
+                &mut sda_pin,
+                &mut scl_pin,
+                twim::Config::default(),
+            );
 
-        let touch_event = Touch {
-            gesture: unwrap!(buf[1].try_into()),
-            event_type: unwrap!((buf[3] >> 6).try_into()),
-            x: buf[4],
-            y: buf[6],
-        };
+            // TODO all unwraps here should be replaced with proper error handling
+            let starting_addr = 0;
+            let mut buf = [0u8; 7];
+            unwrap!(
+                twi.write_read(TOUCH_CONTROLLER_ADDR, &[starting_addr], &mut buf)
+                    .await
+            );
+
+            let touch_event = Touch {
+                gesture: unwrap!(buf[1].try_into()),
+                event_type: unwrap!((buf[3] >> 6).try_into()),
+                x: buf[4],
+                y: buf[6],
+            };
 
-        TOUCH_DATA.send(touch_event).await;
+            for touch_event in gestures.handle_report(&touch_event, Instant::now().as_millis()) {
+                TOUCH_DATA.send(touch_event).await;
+            }
+        } else if let Some(touch_event) = gestures.poll(Instant::now().as_millis()) {
+            TOUCH_DATA.send(touch_event).await;
+        }
     }
 }
@@ -0,0 +1,118 @@
+//! Recording of rendered frames to an animated GIF, so reviewers can see
+//! motion without running the sim.
+
+use std::{fs::File, io::BufWriter, path::Path};
+
+use embedded_graphics_simulator::{OutputSettings, OutputSettingsBuilder};
+use mesozoic_app::interface::{LCD_H, LCD_W};
+
+use crate::Display;
+
+/// Trades colour quality for speed, as the screen has few colours anyway.
+/// From 1 (best quality) to 30 (fastest).
+const QUANTIZATION_SPEED: i32 = 30;
+
+pub struct GifRecorder {
+    encoder: gif::Encoder<BufWriter<File>>,
+    output_settings: OutputSettings,
+    width: u16,
+    height: u16,
+    /// The most recent distinct frame and when it was rendered. A frame is
+    /// only written once the next one arrives, as that sets its duration.
+    pending: Option<(Vec<u8>, u64)>,
+}
+
+impl GifRecorder {
+    pub fn create(path: &Path, scale: u32) -> Result<Self, String> {
+        let error = |e: &dyn std::fmt::Display| format!("failed to record {}: {e}", path.display());
+
+        let width = (u32::from(LCD_W) * scale)
+            .try_into()
+            .map_err(|_| format!("scale {scale} is too large to record"))?;
+        let height = (u32::from(LCD_H) * scale)
+            .try_into()
+            .map_err(|_| format!("scale {scale} is too large to record"))?;
+        let file = File::create(path).map_err(|e| error(&e))?;
+        let mut encoder =
+            gif::Encoder::new(BufWriter::new(file), width, height, &[]).map_err(|e| error(&e))?;
+        encoder
+            .set_repeat(gif::Repeat::Infinite)
+            .map_err(|e| error(&e))?;
+
+        Ok(Self {
+            encoder,
+            output_settings: OutputSettingsBuilder::new().scale(scale).build(),
+            width,
+            height,
+            pending: None,
+        })
+    }
+
+    /// Records the display as rendered at `ms_since_boot`. Frames which are
+    /// the same as the previous one only extend its duration.
+    pub fn frame(&mut self, display: &Display, ms_since_boot: u64) -> Result<(), String> {
+        let image = display.to_rgb_output_image(&self.output_settings);
+        let pixels = image.as_image_buffer().into_raw();
+        if matches!(&self.pending, Some((pending, _)) if pending.as_slice() == pixels) {
+            return Ok(());
+        }
+
+        self.write_pending(ms_since_boot)?;
+        self.pending = Some((pixels.to_vec(), ms_since_boot));
+
+        Ok(())
+    }
+
+    /// Writes the last frame, which is shown for as long as it was before
+    /// the recording ended.
+    pub fn finish(mut self, ms_since_boot: u64) -> Result<(), String> {
+        self.write_pending(ms_since_boot)
+    }
+
+    fn write_pending(&mut self, ms_since_boot: u64) -> Result<(), String> {
+        let Some((pixels, started_ms_since_boot)) = self.pending.take() else {
+            return Ok(());
+        };
+
+        let mut frame =
+            gif::Frame::from_rgb_speed(self.width, self.height, &pixels, QUANTIZATION_SPEED);
+        // GIF delays are in hundredths of a second. Rounding the start and
+        // end of each frame, rather than its duration, stops rounding errors
+        // adding up over a long recording.
+        let delay = ms_since_boot / 10 - started_ms_since_boot / 10;
+        frame.delay = delay.min(u16::MAX.into()) as u16;
+
+        self.encoder
+            .write_frame(&frame)
+            .map_err(|e| format!("failed to write frame: {e}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_graphics::{geometry::Size, pixelcolor::RgbColor, prelude::*};
+
+    use super::*;
+
+    #[test]
+    fn identical_frames_extend_the_previous_one() {
+        let path = std::env::temp_dir().join("mesozoic-animation-test.gif");
+        let mut display = Display::new(Size::new(LCD_W.into(), LCD_H.into()));
+
+        let mut gif = GifRecorder::create(&path, 1).unwrap();
+        gif.frame(&display, 0).unwrap();
+        gif.frame(&display, 100).unwrap();
+        display.clear(RgbColor::WHITE).unwrap();
+        gif.frame(&display, 250).unwrap();
+        gif.finish(1000).unwrap();
+
+        let mut decoder = gif::DecodeOptions::new()
+            .read_info(File::open(&path).unwrap())
+            .unwrap();
+        let mut delays = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+        }
+        assert_eq!(vec![25, 75], delays);
+    }
+}
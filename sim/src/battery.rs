@@ -0,0 +1,194 @@
+//! A simulated battery, which discharges based on the power profile the app
+//! requests and charges while plugged in.
+//!
+//! The currents are rough estimates for the PineTime, chosen so a full
+//! battery lasts about a day with the screen on and about a week with it off.
+
+use mesozoic_app::{
+    battery::{percent_to_voltage, voltage_to_percent, CHARGING_VOLTAGE_OFFSET},
+    interface::{Backlight, BatteryData, PowerProfile},
+};
+
+const CAPACITY_MAH: f64 = 180.;
+/// The MCU sleeping between events, and BLE.
+const BASE_CURRENT_MA: f64 = 1.;
+/// Waking up, handling the tick, and redrawing the screen.
+const CHARGE_PER_TICK_MAS: f64 = 0.04;
+const CHARGE_CURRENT_MA: f64 = 90.;
+/// Above this level the charger tapers the current off, as a real one does
+/// in its constant voltage phase.
+const CHARGE_TAPER_PERCENT: f64 = 80.;
+/// The firmware samples the battery about once a second.
+const SAMPLE_INTERVAL_MS: u64 = 1000;
+
+pub struct SimBattery {
+    /// Fractional, and double precision, so that the tiny discharge between
+    /// frames isn't lost to rounding.
+    percent: f64,
+    charging: bool,
+    profile: PowerProfile,
+    /// How much faster than real time the battery charges and discharges.
+    acceleration: f64,
+    last_update_ms_since_boot: u64,
+    last_sample_ms_since_boot: Option<u64>,
+}
+
+impl SimBattery {
+    /// `voltage` is the resting cell voltage, regardless of `charging`.
+    pub fn new(voltage: f32, charging: bool, acceleration: f64, ms_since_boot: u64) -> Self {
+        Self {
+            percent: voltage_to_percent(voltage).into(),
+            charging,
+            profile: PowerProfile::NORMAL,
+            acceleration,
+            last_update_ms_since_boot: ms_since_boot,
+            last_sample_ms_since_boot: None,
+        }
+    }
+
+    pub fn charging(&self) -> bool {
+        self.charging
+    }
+
+    pub fn set_charging(&mut self, charging: bool, ms_since_boot: u64) {
+        self.advance(ms_since_boot);
+        self.charging = charging;
+        // The charging indication pin wakes the firmware's battery task.
+        self.last_sample_ms_since_boot = None;
+    }
+
+    pub fn set_power_profile(&mut self, profile: PowerProfile, ms_since_boot: u64) {
+        self.advance(ms_since_boot);
+        self.profile = profile;
+    }
+
+    /// Returns a sample for the app whenever the firmware would take one.
+    pub fn poll(&mut self, ms_since_boot: u64) -> Option<BatteryData> {
+        self.advance(ms_since_boot);
+
+        if let Some(last) = self.last_sample_ms_since_boot {
+            if ms_since_boot.saturating_sub(last) < SAMPLE_INTERVAL_MS {
+                return None;
+            }
+        }
+        self.last_sample_ms_since_boot = Some(ms_since_boot);
+
+        Some(self.sample())
+    }
+
+    /// The voltage the battery would be measured at now.
+    pub fn sample(&self) -> BatteryData {
+        let voltage = percent_to_voltage(self.percent as f32);
+
+        BatteryData {
+            charging: self.charging,
+            voltage: match self.charging {
+                true => voltage + CHARGING_VOLTAGE_OFFSET,
+                false => voltage,
+            },
+        }
+    }
+
+    fn advance(&mut self, ms_since_boot: u64) {
+        let elapsed_ms = ms_since_boot.saturating_sub(self.last_update_ms_since_boot);
+        self.last_update_ms_since_boot = ms_since_boot;
+
+        let elapsed_hours = elapsed_ms as f64 * self.acceleration / (60. * 60. * 1000.);
+        let charge_mah = (self.charge_current_ma() - self.load_current_ma()) * elapsed_hours;
+        self.percent = (self.percent + charge_mah / CAPACITY_MAH * 100.).clamp(0., 100.);
+    }
+
+    fn load_current_ma(&self) -> f64 {
+        let backlight_ma = match self.profile.backlight {
+            Backlight::Off => 0.,
+            Backlight::Low => 2.,
+            Backlight::Medium => 5.,
+            Backlight::High => 12.,
+        };
+        let ticks_per_second = 1000. / self.profile.tick_interval_ms.max(1) as f64;
+
+        BASE_CURRENT_MA + backlight_ma + ticks_per_second * CHARGE_PER_TICK_MAS
+    }
+
+    fn charge_current_ma(&self) -> f64 {
+        if !self.charging {
+            return 0.;
+        }
+
+        match self.percent > CHARGE_TAPER_PERCENT {
+            true => {
+                CHARGE_CURRENT_MA * (100. - self.percent) / (100. - CHARGE_TAPER_PERCENT)
+                    + self.load_current_ma()
+            }
+            false => CHARGE_CURRENT_MA,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR_MS: u64 = 60 * 60 * 1000;
+
+    fn percent(battery: &SimBattery) -> u8 {
+        voltage_to_percent(battery.sample().voltage)
+    }
+
+    #[test]
+    fn screen_off_lasts_longer() {
+        let screen_off = PowerProfile {
+            backlight: Backlight::Off,
+            tick_interval_ms: 1000,
+            ..PowerProfile::NORMAL
+        };
+
+        let mut screen_on_battery = SimBattery::new(4.2, false, 1., 0);
+        let mut screen_off_battery = SimBattery::new(4.2, false, 1., 0);
+        screen_off_battery.set_power_profile(screen_off, 0);
+
+        screen_on_battery.poll(12 * HOUR_MS);
+        screen_off_battery.poll(12 * HOUR_MS);
+
+        assert!(percent(&screen_on_battery) < 60);
+        assert!(percent(&screen_off_battery) > 90);
+    }
+
+    #[test]
+    fn acceleration_speeds_up_discharge() {
+        let mut battery = SimBattery::new(4.2, false, 60., 0);
+        battery.poll(HOUR_MS / 60);
+        let accelerated = percent(&battery);
+
+        let mut battery = SimBattery::new(4.2, false, 1., 0);
+        battery.poll(HOUR_MS);
+
+        assert_eq!(percent(&battery), accelerated);
+        assert!(accelerated < 100);
+    }
+
+    #[test]
+    fn charges_to_full() {
+        let mut battery = SimBattery::new(3.7, true, 1., 0);
+        let data = battery.poll(0).unwrap();
+        assert!(data.charging);
+        assert!(data.voltage > 3.7 + CHARGING_VOLTAGE_OFFSET - 0.01);
+
+        battery.poll(4 * HOUR_MS);
+        battery.set_charging(false, 4 * HOUR_MS);
+        assert_eq!(100, percent(&battery));
+    }
+
+    #[test]
+    fn samples_once_per_second() {
+        let mut battery = SimBattery::new(4.0, false, 1., 0);
+
+        assert!(battery.poll(0).is_some());
+        assert!(battery.poll(999).is_none());
+        assert!(battery.poll(1000).is_some());
+
+        battery.set_charging(true, 1500);
+        assert!(battery.charging());
+        assert!(battery.poll(1500).unwrap().charging);
+    }
+}
@@ -0,0 +1,137 @@
+//! The time the app sees, which can run faster than real time or jump
+//! forward, so that slow behaviour like the screen timeout, midnight
+//! rollover and battery history can be tried without waiting.
+
+use std::time::Instant;
+
+#[cfg(feature = "window")]
+use embedded_graphics_simulator::sdl2::Keycode;
+
+pub const MAX_SPEED: u32 = 1024;
+
+pub struct VirtualClock {
+    start: Instant,
+    /// When the speed last changed, and the virtual time then. Virtual time
+    /// is measured from here, so changing speed doesn't make it jump.
+    anchor: Instant,
+    anchor_ms_since_boot: u64,
+    speed: u32,
+}
+
+impl VirtualClock {
+    pub fn new(speed: u32) -> Self {
+        let now = Instant::now();
+
+        Self {
+            start: now,
+            anchor: now,
+            anchor_ms_since_boot: 0,
+            speed: speed.clamp(1, MAX_SPEED),
+        }
+    }
+
+    /// The time passed to the app.
+    pub fn ms_since_boot(&self) -> u64 {
+        self.ms_since_boot_at(Instant::now())
+    }
+
+    /// Real time since the clock started, regardless of speed and jumps.
+    pub fn real_ms_since_start(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    pub fn set_speed(&mut self, speed: u32) {
+        self.set_speed_at(speed, Instant::now());
+    }
+
+    pub fn jump_forward(&mut self, ms: u64) {
+        self.anchor_ms_since_boot += ms;
+    }
+
+    /// Handles the clock's keyboard shortcuts, returning false for other
+    /// keys.
+    ///
+    /// * `=` and `-` double and halve the speed
+    /// * `0` returns to real time
+    /// * `1` to `9` jump forward that many minutes
+    #[cfg(feature = "window")]
+    pub fn handle_key(&mut self, keycode: Keycode) -> bool {
+        let jump_minutes = match keycode {
+            Keycode::Equals => {
+                self.set_speed(self.speed.saturating_mul(2));
+                println!("speed: {}x", self.speed);
+                return true;
+            }
+            Keycode::Minus => {
+                self.set_speed(self.speed / 2);
+                println!("speed: {}x", self.speed);
+                return true;
+            }
+            Keycode::Num0 => {
+                self.set_speed(1);
+                println!("speed: {}x", self.speed);
+                return true;
+            }
+            Keycode::Num1 => 1,
+            Keycode::Num2 => 2,
+            Keycode::Num3 => 3,
+            Keycode::Num4 => 4,
+            Keycode::Num5 => 5,
+            Keycode::Num6 => 6,
+            Keycode::Num7 => 7,
+            Keycode::Num8 => 8,
+            Keycode::Num9 => 9,
+            _ => return false,
+        };
+
+        self.jump_forward(jump_minutes * 60 * 1000);
+        println!("jumped forward {jump_minutes} minutes");
+
+        true
+    }
+
+    fn ms_since_boot_at(&self, now: Instant) -> u64 {
+        let real_ms = now.saturating_duration_since(self.anchor).as_millis() as u64;
+
+        self.anchor_ms_since_boot + real_ms * u64::from(self.speed)
+    }
+
+    fn set_speed_at(&mut self, speed: u32, now: Instant) {
+        self.anchor_ms_since_boot = self.ms_since_boot_at(now);
+        self.anchor = now;
+        self.speed = speed.clamp(1, MAX_SPEED);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn speed_changes_continue_from_the_current_time() {
+        let mut clock = VirtualClock::new(1);
+        let start = clock.start;
+        let at = |ms| start + Duration::from_millis(ms);
+
+        assert_eq!(1000, clock.ms_since_boot_at(at(1000)));
+
+        clock.set_speed_at(8, at(1000));
+        assert_eq!(1000, clock.ms_since_boot_at(at(1000)));
+        assert_eq!(9000, clock.ms_since_boot_at(at(2000)));
+
+        clock.set_speed_at(1, at(2000));
+        clock.jump_forward(60_000);
+        assert_eq!(70_000, clock.ms_since_boot_at(at(3000)));
+    }
+
+    #[test]
+    fn speed_is_limited() {
+        let mut clock = VirtualClock::new(0);
+        assert_eq!(1, clock.speed);
+
+        clock.set_speed(u32::MAX);
+        assert_eq!(MAX_SPEED, clock.speed);
+    }
+}
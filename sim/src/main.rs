@@ -1,131 +1,282 @@
-use std::{str::FromStr, time::Instant};
-
-use mesozoic_app::{
-    interface::{
-        AppInput, AppOutput, AppleMediaServiceData, BatteryData, Gesture, MediaControl, TimeOfDay,
-        Touch, TouchType, LCD_H, LCD_W,
-    },
-    App,
+#[cfg(feature = "window")]
+use std::collections::VecDeque;
+
+#[cfg(feature = "window")]
+use mesozoic_app::interface::{
+    AppInput, AppOutput, BleState, Diagnostics, DisplayWrites, FirmwareVersion, TimeOfDay, Touch,
+    LCD_H, LCD_W,
 };
 
-use arrayvec::ArrayString;
+#[cfg(feature = "window")]
 use embedded_graphics::geometry::Size;
-use embedded_graphics_simulator::{
-    sdl2::Keycode, OutputSettings, SimulatorDisplay, SimulatorEvent, Window,
-};
+use embedded_graphics_simulator::SimulatorDisplay;
+#[cfg(feature = "window")]
+use embedded_graphics_simulator::{sdl2::Keycode, OutputSettingsBuilder, SimulatorEvent, Window};
+
+mod animation;
+#[cfg(any(feature = "window", test))]
+mod battery;
+// Options refer to the clock's limits even in headless runs.
+#[cfg_attr(not(feature = "window"), allow(dead_code))]
+mod clock;
+mod options;
+mod phone;
+#[cfg(feature = "window")]
+mod replay;
+mod script;
+#[cfg(feature = "window")]
+mod session;
+#[cfg(any(feature = "window", test))]
+mod settings_file;
+#[cfg(feature = "window")]
+mod touch;
+
+use animation::GifRecorder;
+#[cfg(feature = "window")]
+use battery::SimBattery;
+#[cfg(feature = "window")]
+use chrono::Timelike;
+#[cfg(feature = "window")]
+use clock::VirtualClock;
+#[cfg(feature = "window")]
+use options::StartTime;
+use options::{Options, USAGE};
+#[cfg(feature = "window")]
+use phone::{AmsClient, FakePhone};
+#[cfg(feature = "window")]
+use session::{RenderCostReport, Session};
+#[cfg(feature = "window")]
+use touch::MouseTouch;
 
 type DisplayColor = embedded_graphics::pixelcolor::Rgb565;
+type Display = SimulatorDisplay<DisplayColor>;
 
 fn main() -> Result<(), core::convert::Infallible> {
-    let mut display: SimulatorDisplay<DisplayColor> =
-        SimulatorDisplay::new(Size::new(LCD_W as u32, LCD_H as u32));
-    let mut window = Window::new("Mesozoic", &OutputSettings::default());
-    let start_time = Instant::now();
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
 
-    let mut app = App::init(&mut display, start_time.elapsed().as_millis() as u64).unwrap();
+    let gif = options
+        .gif
+        .as_deref()
+        .map(|path| GifRecorder::create(path, options.scale))
+        .transpose()
+        .unwrap_or_else(|e| {
+            eprintln!("{e}");
+            std::process::exit(1);
+        });
+
+    if let Some(script) = &options.script {
+        if let Err(e) = script::run_file(script, options.screenshot_dir.clone(), gif) {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+
+        return Ok(());
+    }
+
+    run_window(options, gif);
+
+    Ok(())
+}
+
+#[cfg(not(feature = "window"))]
+fn run_window(_options: Options, _gif: Option<GifRecorder>) {
+    eprintln!("the sim was built without the window feature, so --script is required\n\n{USAGE}");
+    std::process::exit(2);
+}
 
-    app.handle_event(
+#[cfg(feature = "window")]
+fn run_window(options: Options, mut gif: Option<GifRecorder>) {
+    let mut display: Display = SimulatorDisplay::new(Size::new(LCD_W as u32, LCD_H as u32));
+    let mut window = Window::new(
+        "Mesozoic",
+        &OutputSettingsBuilder::new().scale(options.scale).build(),
+    );
+
+    if let Some(path) = &options.replay_from {
+        replay::run(
+            &mut window,
+            &mut display,
+            VirtualClock::new(options.speed),
+            path,
+        );
+
+        return;
+    }
+
+    let mut session = Session::start(
         &mut display,
-        start_time.elapsed().as_millis() as u64,
-        AppInput::Time(TimeOfDay {
-            hours: 23,
-            minutes: 59,
-            seconds: 58,
-            ..Default::default()
-        }),
-    )
-    .unwrap();
+        VirtualClock::new(options.speed),
+        options.record_to.as_deref(),
+    );
+
+    let time = match options.time {
+        StartTime::Fixed(time) => time,
+        StartTime::Host => {
+            let now = chrono::Local::now();
+            TimeOfDay {
+                hours: now.hour() as u8,
+                minutes: now.minute() as u8,
+                seconds: now.second() as u8,
+            }
+        }
+    };
+    session.handle_event(&mut display, AppInput::Time(time));
 
-    let mut charging = true;
-    let voltage = 4.1;
-    app.handle_event(
+    session.handle_event(
         &mut display,
-        start_time.elapsed().as_millis() as u64,
-        AppInput::Battery(BatteryData { charging, voltage }),
-    )
-    .unwrap();
-
-    let mut audio_index = 0;
-    let audio = vec![
-        AppleMediaServiceData {
-            artist: ArrayString::from_str("Rustacean Station").unwrap(),
-            album: ArrayString::from_str("April 28, 2023").unwrap(),
-            title: ArrayString::from_str("Rust Embedded WG").unwrap(),
-        },
-        AppleMediaServiceData {
-            artist: ArrayString::from_str("Chats with James").unwrap(),
-            album: ArrayString::from_str("September 29, 2023").unwrap(),
-            title: ArrayString::from_str("014 - Steve Klabnik").unwrap(),
-        },
-    ];
+        AppInput::Diagnostics(Diagnostics {
+            firmware_version: FirmwareVersion::from(env!("CARGO_PKG_VERSION")).unwrap(),
+            ble: BleState::Unavailable,
+            display_writes: DisplayWrites::Simulator,
+        }),
+    );
+
+    if let Some(path) = &options.settings {
+        match settings_file::load(path) {
+            Ok(Some(settings)) => {
+                session.handle_event(&mut display, AppInput::Settings(settings));
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("{e}, using the defaults"),
+        }
+    }
+
+    let mut battery = SimBattery::new(
+        options.battery_voltage,
+        options.charging,
+        options.battery_speed,
+        session.ms_since_boot(),
+    );
+    let battery_data = battery.poll(session.ms_since_boot()).unwrap();
+    session.handle_event(&mut display, AppInput::Battery(battery_data));
+
+    // The initial window is reached the way a user would, so recordings
+    // replay faithfully. The limit guards against a window which can't be
+    // reached.
+    for _ in 0..8 {
+        if session.app().active_window() == options.window {
+            break;
+        }
+        session.handle_event(&mut display, AppInput::ButtonPressed);
+    }
+
+    let mut phone = FakePhone::new(options.playlist);
+    let mut ams_client = AmsClient::default();
+    // Inputs from the fake phone, handled before any new window events.
+    let mut pending_inputs = VecDeque::new();
+
+    let mut mouse_touch = MouseTouch::new();
+
+    let mut render_cost_report = options
+        .render_cost
+        .then(|| RenderCostReport::new(session.clock().real_ms_since_start()));
 
     'running: loop {
         window.update(&display);
 
+        let ms_since_boot = session.ms_since_boot();
         let mut events = window.events();
-        let app_input = if let Some(event) = events.next() {
+        // Frames without a recognized gesture tick the app.
+        let touch_or_tick = |touch: Option<Touch>| touch.map_or(AppInput::Tick, AppInput::Touch);
+        pending_inputs.extend(battery.poll(ms_since_boot).map(AppInput::Battery));
+        let app_input = if let Some(app_input) = pending_inputs.pop_front() {
+            app_input
+        } else if let Some(event) = events.next() {
             match event {
-                SimulatorEvent::Quit => break 'running,
+                SimulatorEvent::Quit => {
+                    if let Some(gif) = gif.take() {
+                        gif.finish(session.clock().real_ms_since_start()).unwrap();
+                    }
+                    break 'running;
+                }
                 SimulatorEvent::KeyDown { keycode, .. } => match keycode {
-                    Keycode::P => AppInput::AppleMedia(audio[audio_index].clone()),
+                    keycode if session.clock_mut().handle_key(keycode) => continue,
+                    Keycode::P => {
+                        // Connecting to the phone, which sends the current
+                        // track.
+                        for update in phone.subscribe(&phone::WATCH_SUBSCRIPTION) {
+                            pending_inputs.extend(
+                                ams_client.handle_update(&update).map(AppInput::AppleMedia),
+                            );
+                        }
+                        continue;
+                    }
                     Keycode::B => {
-                        charging = !charging;
-                        AppInput::Battery(BatteryData { charging, voltage })
+                        battery.set_charging(!battery.charging(), ms_since_boot);
+                        continue;
                     }
                     Keycode::LShift => AppInput::ButtonPressed,
-                    _ => continue,
+                    keycode => match touch::swipe_for_key(keycode) {
+                        Some(touch) => AppInput::Touch(touch),
+                        None => continue,
+                    },
                 },
-                SimulatorEvent::MouseButtonDown { point, .. } => AppInput::Touch(Touch {
-                    gesture: Gesture::SingleClick,
-                    event_type: TouchType::Down,
-                    x: point.x as u8,
-                    y: point.y as u8,
-                }),
-                _ => AppInput::Tick,
+                SimulatorEvent::MouseButtonDown { point, .. } => {
+                    touch_or_tick(mouse_touch.button_down(point, ms_since_boot))
+                }
+                SimulatorEvent::MouseMove { point } => {
+                    touch_or_tick(mouse_touch.moved(point, ms_since_boot))
+                }
+                SimulatorEvent::MouseButtonUp { point, .. } => {
+                    touch_or_tick(mouse_touch.button_up(point, ms_since_boot))
+                }
+                _ => touch_or_tick(mouse_touch.poll(ms_since_boot)),
             }
         } else {
-            AppInput::Tick
+            touch_or_tick(mouse_touch.poll(ms_since_boot))
         };
 
-        match app
-            .handle_event(
-                &mut display,
-                start_time.elapsed().as_millis() as u64,
-                app_input,
-            )
-            .unwrap()
-        {
-            // TODO the nested calls to app.handle_event here are really messy
-            Some(AppOutput::MediaControl(MediaControl::NextTrack)) => {
-                audio_index += 1;
-                audio_index %= audio.len();
-                app.handle_event(
-                    &mut display,
-                    start_time.elapsed().as_millis() as u64,
-                    AppInput::AppleMedia(audio[audio_index].clone()),
-                )
-                .unwrap();
-            }
-            Some(AppOutput::MediaControl(MediaControl::PreviousTrack)) => {
-                if audio_index > 0 {
-                    audio_index -= 1;
-                } else {
-                    audio_index = audio.len() - 1;
+        let mut phone_updates = phone.tick(ms_since_boot);
+        for output in session.handle_event(&mut display, app_input) {
+            match output {
+                AppOutput::MediaControl(control) => {
+                    phone_updates.extend(phone.handle_control(&control, ms_since_boot));
+                    println!("{}", phone.status(ms_since_boot));
                 }
-                app.handle_event(
-                    &mut display,
-                    start_time.elapsed().as_millis() as u64,
-                    AppInput::AppleMedia(audio[audio_index].clone()),
-                )
-                .unwrap();
-            }
-            Some(AppOutput::MediaControl(_)) => {
-                // Sim doesn't do anything with this for now
-            }
-            None => { // do nothing
+                // The fake phone doesn't read the battery level.
+                AppOutput::BatteryLevel(_) => {}
+                AppOutput::Vibrate { duration_ms } => println!("vibrate for {duration_ms}ms"),
+                AppOutput::PowerProfile(profile) => {
+                    // The sim always ticks as fast as it can, and has no
+                    // backlight, but the simulated battery drains as though
+                    // it didn't.
+                    println!("power profile: {profile:?}");
+                    battery.set_power_profile(profile, ms_since_boot);
+                }
+                AppOutput::SaveSettings(settings) => {
+                    println!("settings: {settings:?}");
+                    if let Some(path) = &options.settings {
+                        if let Err(e) = settings_file::save(path, &settings) {
+                            eprintln!("{e}");
+                        }
+                    }
+                }
+                // The simulator doesn't bond with the fake phone.
+                AppOutput::ForgetBonds => println!("forget bonded phones"),
+            };
+        }
+        for update in phone_updates {
+            pending_inputs.extend(ams_client.handle_update(&update).map(AppInput::AppleMedia));
+        }
+
+        if let Some(report) = render_cost_report.as_mut() {
+            let real_ms = session.clock().real_ms_since_start();
+            if let Some(summary) = report.record(session.render_cost(), real_ms) {
+                println!("{summary}");
             }
-        };
-    }
+        }
 
-    Ok(())
+        if let Some(gif) = gif.as_mut() {
+            // Frames are shown for as long as they were on screen, even when
+            // the app's clock runs faster.
+            gif.frame(&display, session.clock().real_ms_since_start())
+                .unwrap();
+        }
+    }
 }
@@ -0,0 +1,234 @@
+//! Command line options.
+
+use std::path::PathBuf;
+
+use mesozoic_app::{interface::TimeOfDay, ActiveWindow};
+
+use crate::{
+    clock,
+    phone::{self, Track},
+};
+
+pub const USAGE: &str = "\
+usage: mesozoic-sim [options]
+
+options:
+    --time <hh:mm:ss>|host   time of day at start, default 23:59:58
+    --battery <volts>        battery voltage at start, default 4.1
+    --discharging            start with the battery discharging
+    --battery-speed <n>      how many times faster than real time the battery
+                             charges and discharges, default 1
+    --playlist <file>        tracks for the fake phone, one per line as
+                             `artist | album | title | m:ss`
+    --speed <n>              how many times faster than real time the app's
+                             clock runs, default 1, see sim/src/clock.rs
+    --scale <n>              pixel scale of the window, default 1
+    --window main|battery-history|debug|settings
+                             window shown at start, default main
+    --record <file>          record the inputs passed to the app
+    --replay <file>          replay recorded inputs
+    --script <file>          run a script headlessly, see sim/src/script.rs
+    --screenshots <dir>      where scripts save screenshots, default next to
+                             the script
+    --gif <file>             record every rendered frame to an animated GIF
+    --settings <file>        keep the app's settings in this file, as the
+                             watch keeps them in flash
+    --render-cost            print how long drawing would take on the watch's
+                             display, once a second";
+
+pub struct Options {
+    pub time: StartTime,
+    pub battery_voltage: f32,
+    pub charging: bool,
+    pub battery_speed: f64,
+    pub playlist: Vec<Track>,
+    pub speed: u32,
+    pub scale: u32,
+    pub window: ActiveWindow,
+    pub record_to: Option<PathBuf>,
+    pub replay_from: Option<PathBuf>,
+    pub script: Option<PathBuf>,
+    pub screenshot_dir: Option<PathBuf>,
+    pub gif: Option<PathBuf>,
+    pub settings: Option<PathBuf>,
+    pub render_cost: bool,
+}
+
+// Headless runs take the time from their script instead.
+#[cfg_attr(not(feature = "window"), allow(dead_code))]
+pub enum StartTime {
+    Fixed(TimeOfDay),
+    /// The local time on the host when the sim starts.
+    Host,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            time: StartTime::Fixed(TimeOfDay {
+                hours: 23,
+                minutes: 59,
+                seconds: 58,
+            }),
+            battery_voltage: 4.1,
+            charging: true,
+            battery_speed: 1.,
+            playlist: phone::demo_playlist(),
+            speed: 1,
+            scale: 1,
+            window: ActiveWindow::Main,
+            record_to: None,
+            replay_from: None,
+            script: None,
+            screenshot_dir: None,
+            gif: None,
+            settings: None,
+            render_cost: false,
+        }
+    }
+}
+
+impl Options {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{arg} requires a value"));
+            match arg.as_str() {
+                "--time" => {
+                    options.time = match value()?.as_str() {
+                        "host" => StartTime::Host,
+                        time => StartTime::Fixed(parse_time(time)?),
+                    }
+                }
+                "--battery" => {
+                    let voltage = value()?;
+                    options.battery_voltage = voltage
+                        .parse()
+                        .map_err(|_| format!("invalid battery voltage {voltage:?}"))?;
+                }
+                "--discharging" => options.charging = false,
+                "--battery-speed" => {
+                    let speed = value()?;
+                    options.battery_speed = match speed.parse() {
+                        Ok(speed) if speed > 0. => speed,
+                        _ => return Err(format!("invalid battery speed {speed:?}")),
+                    };
+                }
+                "--playlist" => {
+                    let path = value()?;
+                    let playlist = std::fs::read_to_string(&path)
+                        .map_err(|e| format!("failed to read {path}: {e}"))?;
+                    options.playlist =
+                        phone::parse_playlist(&playlist).map_err(|e| format!("{path}: {e}"))?;
+                }
+                "--speed" => {
+                    let speed = value()?;
+                    options.speed = match speed.parse() {
+                        Ok(speed) if (1..=clock::MAX_SPEED).contains(&speed) => speed,
+                        _ => return Err(format!("invalid speed {speed:?}")),
+                    };
+                }
+                "--scale" => {
+                    let scale = value()?;
+                    options.scale = match scale.parse() {
+                        Ok(scale) if scale > 0 => scale,
+                        _ => return Err(format!("invalid scale {scale:?}")),
+                    };
+                }
+                "--window" => {
+                    options.window = match value()?.as_str() {
+                        "main" => ActiveWindow::Main,
+                        "battery-history" => ActiveWindow::BatteryHistory,
+                        "debug" => ActiveWindow::Debug,
+                        "settings" => ActiveWindow::Settings,
+                        other => return Err(format!("unknown window {other:?}")),
+                    }
+                }
+                "--record" => options.record_to = Some(value()?.into()),
+                "--replay" => options.replay_from = Some(value()?.into()),
+                "--script" => options.script = Some(value()?.into()),
+                "--screenshots" => options.screenshot_dir = Some(value()?.into()),
+                "--gif" => options.gif = Some(value()?.into()),
+                "--settings" => options.settings = Some(value()?.into()),
+                "--render-cost" => options.render_cost = true,
+                other => return Err(format!("unknown option {other:?}")),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+/// Parses `hh:mm:ss`.
+pub fn parse_time(time: &str) -> Result<TimeOfDay, String> {
+    let invalid = || format!("expected hh:mm:ss, found {time:?}");
+    let parts = time
+        .split(':')
+        .map(|part| part.parse::<u8>().map_err(|_| invalid()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    match parts.as_slice() {
+        &[hours, minutes, seconds] if hours < 24 && minutes < 60 && seconds < 60 => Ok(TimeOfDay {
+            hours,
+            minutes,
+            seconds,
+        }),
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_initial_state() {
+        let options = parse(&[
+            "--time",
+            "07:05:00",
+            "--battery",
+            "3.6",
+            "--discharging",
+            "--battery-speed",
+            "60",
+            "--window",
+            "debug",
+            "--scale",
+            "2",
+            "--speed",
+            "16",
+        ])
+        .unwrap();
+
+        assert!(matches!(
+            options.time,
+            StartTime::Fixed(TimeOfDay {
+                hours: 7,
+                minutes: 5,
+                seconds: 0
+            })
+        ));
+        assert_eq!(3.6, options.battery_voltage);
+        assert!(!options.charging);
+        assert_eq!(60., options.battery_speed);
+        assert_eq!(ActiveWindow::Debug, options.window);
+        assert_eq!(2, options.scale);
+        assert_eq!(16, options.speed);
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!(parse(&["--time", "24:00:00"]).is_err());
+        assert!(parse(&["--scale", "0"]).is_err());
+        assert!(parse(&["--speed", "0"]).is_err());
+        assert!(parse(&["--battery-speed", "-1"]).is_err());
+        assert!(parse(&["--window"]).is_err());
+        assert!(parse(&["--unknown"]).is_err());
+    }
+}
@@ -0,0 +1,431 @@
+//! A fake iPhone, answering media controls the way the Apple Media Service
+//! (AMS) does, so media UI work can be done without a phone.
+//!
+//! Like AMS, the phone only notifies attributes the watch subscribed to, and
+//! only when their value changes. `AmsClient` turns those notifications into
+//! app inputs the same way the firmware does, so the simulator shows what
+//! the watch would.
+
+use mesozoic_app::interface::{AppleMediaServiceData, AppleMediaServiceString, MediaControl};
+
+/// Pressing previous this far into a track restarts it, rather than going
+/// to the previous track.
+const RESTART_TRACK_MS: u64 = 3_000;
+/// iOS changes the volume in 16 steps.
+const VOLUME_STEP: f32 = 1.0 / 16.0;
+
+// Not every attribute is subscribed to by the firmware yet, but they are all
+// modelled so the firmware can start using them.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Attribute {
+    PlayerPlaybackInfo,
+    PlayerVolume,
+    TrackArtist,
+    TrackAlbum,
+    TrackTitle,
+    TrackDuration,
+}
+
+/// The attributes the firmware subscribes to, see `task_gatt_client`.
+pub const WATCH_SUBSCRIPTION: [Attribute; 3] = [
+    Attribute::TrackArtist,
+    Attribute::TrackAlbum,
+    Attribute::TrackTitle,
+];
+
+/// An AMS entity update notification.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntityUpdate {
+    pub attribute: Attribute,
+    /// Formatted as AMS formats it, for example durations are seconds with
+    /// a fractional part.
+    pub value: String,
+}
+
+#[derive(Clone)]
+pub struct Track {
+    pub artist: String,
+    pub album: String,
+    pub title: String,
+    pub duration_ms: u64,
+}
+
+pub struct FakePhone {
+    playlist: Vec<Track>,
+    index: usize,
+    playing: bool,
+    /// From 0 to 1.
+    volume: f32,
+    /// Position in the track when playback last started or stopped.
+    elapsed_ms: u64,
+    /// When playback last started or stopped.
+    elapsed_at_ms_since_boot: u64,
+    subscription: Vec<Attribute>,
+    /// The values last notified, so only changes are notified.
+    notified: Vec<EntityUpdate>,
+}
+
+impl FakePhone {
+    pub fn new(playlist: Vec<Track>) -> Self {
+        assert!(!playlist.is_empty(), "the playlist can't be empty");
+
+        Self {
+            playlist,
+            index: 0,
+            playing: false,
+            volume: 0.5,
+            elapsed_ms: 0,
+            elapsed_at_ms_since_boot: 0,
+            subscription: Vec::new(),
+            notified: Vec::new(),
+        }
+    }
+
+    /// Replaces the subscription, as writing the entity update
+    /// characteristic does. AMS then notifies the current value of every
+    /// subscribed attribute.
+    pub fn subscribe(&mut self, attributes: &[Attribute]) -> Vec<EntityUpdate> {
+        self.subscription = attributes.to_vec();
+        self.notified.clear();
+
+        self.changes()
+    }
+
+    /// Handles a remote command, returning the resulting notifications.
+    pub fn handle_control(
+        &mut self,
+        control: &MediaControl,
+        ms_since_boot: u64,
+    ) -> Vec<EntityUpdate> {
+        let elapsed_ms = self.elapsed_ms(ms_since_boot);
+        match control {
+            MediaControl::TogglePlayPause => {
+                self.elapsed_ms = elapsed_ms;
+                self.elapsed_at_ms_since_boot = ms_since_boot;
+                self.playing = !self.playing;
+                self.playback_changed();
+            }
+            MediaControl::NextTrack => {
+                self.skip_to((self.index + 1) % self.playlist.len(), ms_since_boot)
+            }
+            MediaControl::PreviousTrack if elapsed_ms > RESTART_TRACK_MS => {
+                self.skip_to(self.index, ms_since_boot)
+            }
+            MediaControl::PreviousTrack => self.skip_to(
+                self.index.checked_sub(1).unwrap_or(self.playlist.len() - 1),
+                ms_since_boot,
+            ),
+            MediaControl::VolumeUp => self.volume = (self.volume + VOLUME_STEP).min(1.0),
+            MediaControl::VolumeDown => self.volume = (self.volume - VOLUME_STEP).max(0.0),
+        }
+
+        self.changes()
+    }
+
+    /// Advances to the next track when the current one finishes playing.
+    pub fn tick(&mut self, ms_since_boot: u64) -> Vec<EntityUpdate> {
+        if self.elapsed_ms(ms_since_boot) < self.playlist[self.index].duration_ms {
+            return Vec::new();
+        }
+
+        self.skip_to((self.index + 1) % self.playlist.len(), ms_since_boot);
+
+        self.changes()
+    }
+
+    /// A summary for the simulator to print, as the app doesn't show
+    /// everything the phone knows.
+    #[cfg(feature = "window")]
+    pub fn status(&self, ms_since_boot: u64) -> String {
+        let track = &self.playlist[self.index];
+        let elapsed_s = self.elapsed_ms(ms_since_boot) / 1000;
+        let duration_s = track.duration_ms / 1000;
+
+        format!(
+            "phone: {} {:?} {}:{:02} / {}:{:02}, volume {:.0}%",
+            if self.playing { "playing" } else { "paused" },
+            track.title,
+            elapsed_s / 60,
+            elapsed_s % 60,
+            duration_s / 60,
+            duration_s % 60,
+            self.volume * 100.0
+        )
+    }
+
+    fn elapsed_ms(&self, ms_since_boot: u64) -> u64 {
+        match self.playing {
+            true => self.elapsed_ms + ms_since_boot.saturating_sub(self.elapsed_at_ms_since_boot),
+            false => self.elapsed_ms,
+        }
+    }
+
+    fn skip_to(&mut self, index: usize, ms_since_boot: u64) {
+        self.index = index;
+        self.elapsed_ms = 0;
+        self.elapsed_at_ms_since_boot = ms_since_boot;
+        self.playback_changed();
+    }
+
+    /// AMS notifies playback info whenever playback starts, stops, or
+    /// seeks, even if the value happens to be the same.
+    fn playback_changed(&mut self) {
+        self.notified
+            .retain(|notified| notified.attribute != Attribute::PlayerPlaybackInfo);
+    }
+
+    fn value(&self, attribute: Attribute) -> String {
+        let track = &self.playlist[self.index];
+        match attribute {
+            // Playback state, rate, and elapsed seconds.
+            Attribute::PlayerPlaybackInfo => format!(
+                "{},{:.1},{:.3}",
+                u8::from(self.playing),
+                if self.playing { 1.0 } else { 0.0 },
+                self.elapsed_ms as f32 / 1000.0
+            ),
+            Attribute::PlayerVolume => format!("{:.4}", self.volume),
+            Attribute::TrackArtist => track.artist.clone(),
+            Attribute::TrackAlbum => track.album.clone(),
+            Attribute::TrackTitle => track.title.clone(),
+            Attribute::TrackDuration => format!("{:.3}", track.duration_ms as f32 / 1000.0),
+        }
+    }
+
+    /// Notifications for subscribed attributes whose value has changed, in
+    /// subscription order.
+    fn changes(&mut self) -> Vec<EntityUpdate> {
+        let mut changes = Vec::new();
+        for &attribute in &self.subscription {
+            let update = EntityUpdate {
+                attribute,
+                value: self.value(attribute),
+            };
+            match self.notified.iter_mut().find(|n| n.attribute == attribute) {
+                Some(notified) if *notified == update => {}
+                Some(notified) => {
+                    *notified = update.clone();
+                    changes.push(update);
+                }
+                None => {
+                    self.notified.push(update.clone());
+                    changes.push(update);
+                }
+            }
+        }
+
+        changes
+    }
+}
+
+/// Episodes from a couple of podcasts, so some tracks share an artist and
+/// album.
+pub fn demo_playlist() -> Vec<Track> {
+    let track = |artist: &str, album: &str, title: &str, duration_s: u64| Track {
+        artist: artist.to_string(),
+        album: album.to_string(),
+        title: title.to_string(),
+        duration_ms: duration_s * 1000,
+    };
+
+    vec![
+        track(
+            "Rustacean Station",
+            "April 28, 2023",
+            "Rust Embedded WG",
+            3_751,
+        ),
+        track(
+            "Chats with James",
+            "September 29, 2023",
+            "014 - Steve Klabnik",
+            4_217,
+        ),
+        track(
+            "Chats with James",
+            "September 29, 2023",
+            "015 - Short Bonus Episode",
+            95,
+        ),
+    ]
+}
+
+/// Parses a playlist with one track per line, as
+/// `artist | album | title | m:ss`. Blank lines and lines starting with `#`
+/// are ignored.
+pub fn parse_playlist(playlist: &str) -> Result<Vec<Track>, String> {
+    let mut tracks = Vec::new();
+    for (i, line) in playlist.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid = || format!("line {}: expected artist | album | title | m:ss", i + 1);
+        let fields = line.split('|').map(str::trim).collect::<Vec<_>>();
+        let [artist, album, title, duration] = fields.as_slice() else {
+            return Err(invalid());
+        };
+        let (minutes, seconds) = duration.split_once(':').ok_or_else(invalid)?;
+        let minutes = minutes.parse::<u64>().map_err(|_| invalid())?;
+        let seconds = seconds.parse::<u64>().map_err(|_| invalid())?;
+
+        tracks.push(Track {
+            artist: artist.to_string(),
+            album: album.to_string(),
+            title: title.to_string(),
+            duration_ms: (minutes * 60 + seconds) * 1000,
+        });
+    }
+
+    if tracks.is_empty() {
+        return Err("the playlist is empty".to_string());
+    }
+
+    Ok(tracks)
+}
+
+/// Turns AMS notifications into app inputs the way the firmware does.
+///
+/// Media data is only sent to the app when the title is notified, so
+/// skipping to a track with the same title doesn't update the app, on the
+/// watch or here.
+#[derive(Default)]
+pub struct AmsClient {
+    artist: AppleMediaServiceString,
+    album: AppleMediaServiceString,
+    title: AppleMediaServiceString,
+}
+
+impl AmsClient {
+    pub fn handle_update(&mut self, update: &EntityUpdate) -> Option<AppleMediaServiceData> {
+        // AMS truncates values to fit a notification, which the firmware
+        // then stores whole.
+        let value = |value: &str| {
+            let mut s = AppleMediaServiceString::new();
+            for c in value.chars() {
+                if s.try_push(c).is_err() {
+                    break;
+                }
+            }
+            s
+        };
+
+        match update.attribute {
+            Attribute::TrackArtist => self.artist = value(&update.value),
+            Attribute::TrackAlbum => self.album = value(&update.value),
+            Attribute::TrackTitle => {
+                self.title = value(&update.value);
+                return Some(AppleMediaServiceData {
+                    artist: self.artist,
+                    album: self.album,
+                    title: self.title,
+                });
+            }
+            _ => {}
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn phone() -> FakePhone {
+        let track = |artist: &str, title: &str| Track {
+            artist: artist.to_string(),
+            album: "album".to_string(),
+            title: title.to_string(),
+            duration_ms: 60_000,
+        };
+
+        FakePhone::new(vec![
+            track("Rustacean Station", "one"),
+            track("Rustacean Station", "two"),
+            track("Chats with James", "two"),
+        ])
+    }
+
+    fn attributes(updates: &[EntityUpdate]) -> Vec<Attribute> {
+        updates.iter().map(|update| update.attribute).collect()
+    }
+
+    #[test]
+    fn only_changed_attributes_are_notified() {
+        let mut phone = phone();
+        assert_eq!(
+            WATCH_SUBSCRIPTION.to_vec(),
+            attributes(&phone.subscribe(&WATCH_SUBSCRIPTION))
+        );
+
+        // Same artist and album.
+        let updates = phone.handle_control(&MediaControl::NextTrack, 0);
+        assert_eq!(vec![Attribute::TrackTitle], attributes(&updates));
+
+        // Same title, so the firmware doesn't update the app.
+        let updates = phone.handle_control(&MediaControl::NextTrack, 0);
+        assert_eq!(vec![Attribute::TrackArtist], attributes(&updates));
+        let mut client = AmsClient::default();
+        assert!(client.handle_update(&updates[0]).is_none());
+
+        // Unsubscribed player attributes aren't notified.
+        assert!(phone
+            .handle_control(&MediaControl::TogglePlayPause, 0)
+            .is_empty());
+        assert!(phone.handle_control(&MediaControl::VolumeUp, 0).is_empty());
+    }
+
+    #[test]
+    fn previous_restarts_track_after_a_few_seconds() {
+        let mut phone = phone();
+        phone.subscribe(&[Attribute::TrackTitle, Attribute::PlayerPlaybackInfo]);
+        phone.handle_control(&MediaControl::TogglePlayPause, 0);
+
+        let updates = phone.handle_control(&MediaControl::PreviousTrack, 10_000);
+        assert_eq!(vec![Attribute::PlayerPlaybackInfo], attributes(&updates));
+        assert_eq!("1,1.0,0.000", updates[0].value);
+
+        let updates = phone.handle_control(&MediaControl::PreviousTrack, 11_000);
+        assert_eq!(
+            vec![Attribute::TrackTitle, Attribute::PlayerPlaybackInfo],
+            attributes(&updates)
+        );
+        assert_eq!("two", updates[0].value);
+
+        // Playback continues to the next track.
+        let updates = phone.tick(11_000 + 60_000);
+        assert_eq!("one", updates[0].value);
+    }
+
+    #[test]
+    fn playlist_file() {
+        let tracks = parse_playlist(
+            "# artist | album | title | duration\n\
+             \n\
+             Someone | Something | A Song | 3:05\n",
+        )
+        .unwrap();
+        assert_eq!(1, tracks.len());
+        assert_eq!("A Song", tracks[0].title);
+        assert_eq!(185_000, tracks[0].duration_ms);
+
+        assert!(parse_playlist("Someone | A Song | 3:05").is_err());
+        assert!(parse_playlist("# nothing but comments").is_err());
+    }
+
+    #[test]
+    fn volume_is_clamped() {
+        let mut phone = phone();
+        phone.subscribe(&[Attribute::PlayerVolume]);
+        for _ in 0..20 {
+            phone.handle_control(&MediaControl::VolumeUp, 0);
+        }
+        assert!(phone.handle_control(&MediaControl::VolumeUp, 0).is_empty());
+
+        let updates = phone.handle_control(&MediaControl::VolumeDown, 0);
+        assert_eq!("0.9375", updates[0].value);
+    }
+}
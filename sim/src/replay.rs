@@ -0,0 +1,83 @@
+use std::{path::Path, thread, time::Duration};
+
+use embedded_graphics_simulator::{SimulatorEvent, Window};
+use mesozoic_app::{
+    recording::{Decoder, Entry},
+    App,
+};
+
+use crate::{clock::VirtualClock, Display};
+
+/// Plays back a recording made with `--record`, or dumped from the watch,
+/// at the speed it was recorded. The clock's keys fast-forward playback.
+///
+/// The app sees the recorded timestamps rather than the host clock, so the
+/// screen ends up exactly as it was when recording.
+pub fn run(window: &mut Window, display: &mut Display, mut clock: VirtualClock, path: &Path) {
+    let recording =
+        std::fs::read(path).unwrap_or_else(|e| panic!("failed to read {}: {e}", path.display()));
+
+    let mut first_ms_since_boot = None;
+    let mut app = None;
+
+    for entry in Decoder::new(&recording) {
+        let entry = entry.unwrap_or_else(|e| panic!("failed to decode recording: {e:?}"));
+        let ms_since_boot = match entry {
+            Entry::Init { ms_since_boot } | Entry::Input { ms_since_boot, .. } => ms_since_boot,
+        };
+
+        // Wait until the entry is due, relative to the start of the recording.
+        let first_ms_since_boot = *first_ms_since_boot.get_or_insert(ms_since_boot);
+        let due = ms_since_boot.saturating_sub(first_ms_since_boot);
+        while clock.ms_since_boot() < due {
+            if !handle_events(window, &mut clock) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(16));
+        }
+
+        match entry {
+            Entry::Init { ms_since_boot } => {
+                app = Some(App::init(display, ms_since_boot).unwrap());
+            }
+            Entry::Input {
+                ms_since_boot,
+                input,
+            } => {
+                let app = app.get_or_insert_with(|| App::init(display, ms_since_boot).unwrap());
+                // The recording already holds the inputs the platform sent in
+                // response to these outputs.
+                app.handle_event(display, ms_since_boot, input).unwrap();
+            }
+        }
+
+        window.update(display);
+        if !handle_events(window, &mut clock) {
+            return;
+        }
+    }
+
+    println!("replay finished");
+    loop {
+        window.update(display);
+        if !handle_events(window, &mut clock) {
+            return;
+        }
+        thread::sleep(Duration::from_millis(16));
+    }
+}
+
+/// Returns false if the window was closed.
+fn handle_events(window: &mut Window, clock: &mut VirtualClock) -> bool {
+    for event in window.events() {
+        match event {
+            SimulatorEvent::Quit => return false,
+            SimulatorEvent::KeyDown { keycode, .. } => {
+                clock.handle_key(keycode);
+            }
+            _ => {}
+        }
+    }
+
+    true
+}
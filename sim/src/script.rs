@@ -0,0 +1,353 @@
+//! Headless scripted runs of the app, for end-to-end tests on machines
+//! without a display.
+//!
+//! A script has one step per line. Blank lines and lines starting with `#`
+//! are ignored. Each step can start with a time, either milliseconds since
+//! boot or `+` and milliseconds since the previous step, followed by a
+//! command. Steps without a time happen at the same time as the previous
+//! step.
+//!
+//! * `time <hh>:<mm>:<ss>`
+//! * `battery <volts> charging|discharging`
+//! * `media <artist> | <album> | <title>`
+//! * `touch <x> <y> [<gesture> [<event type>]]`, defaulting to a
+//!   `single-click` `down`. Gestures and event types are written in kebab
+//!   case, for example `slide-left` and `contact`.
+//! * `button`
+//! * `tick`
+//! * `phone-connect` connects the fake phone, which sends the first track of
+//!   its demo playlist. Media controls from the app are answered by the fake
+//!   phone, see `phone::FakePhone`.
+//! * `screenshot <name>` saves the screen as `<name>.png`
+//! * `expect <output>` checks the previous input emitted `<output>`, written
+//!   as its `Debug` representation, for example
+//!   `expect MediaControl(TogglePlayPause)`
+//! * `expect-none` checks the previous input emitted no outputs
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use arrayvec::ArrayString;
+use embedded_graphics::geometry::Size;
+use embedded_graphics_simulator::{OutputSettings, SimulatorDisplay};
+use mesozoic_app::{
+    interface::{
+        AppInput, AppOutput, AppleMediaServiceData, BatteryData, Gesture, Touch, TouchType, LCD_H,
+        LCD_W,
+    },
+    App,
+};
+
+use crate::{
+    animation::GifRecorder,
+    options,
+    phone::{self, AmsClient, FakePhone},
+    Display,
+};
+
+pub struct Script {
+    steps: Vec<Step>,
+}
+
+struct Step {
+    line: usize,
+    ms_since_boot: u64,
+    command: Command,
+}
+
+enum Command {
+    Input(Box<AppInput>),
+    PhoneConnect,
+    Screenshot(String),
+    Expect(String),
+    ExpectNone,
+}
+
+#[derive(Debug)]
+pub struct Error {
+    /// One based, as shown by editors.
+    line: usize,
+    message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Script {
+    pub fn parse(source: &str) -> Result<Self, Error> {
+        let mut steps = Vec::new();
+        let mut ms_since_boot = 0;
+
+        for (i, line) in source.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let error = |message: String| Error {
+                line: line_number,
+                message,
+            };
+            let (time, command) = match line.split_once(char::is_whitespace) {
+                Some((time, command))
+                    if time.starts_with(|c: char| c == '+' || c.is_ascii_digit()) =>
+                {
+                    (Some(time), command)
+                }
+                _ => (None, line),
+            };
+            ms_since_boot = match time.map(|time| (time, time.strip_prefix('+'))) {
+                Some((_, Some(delta))) => ms_since_boot + parse::<u64>(delta).map_err(error)?,
+                Some((time, None)) => parse(time).map_err(error)?,
+                None => ms_since_boot,
+            };
+
+            steps.push(Step {
+                line: line_number,
+                ms_since_boot,
+                command: parse_command(command.trim()).map_err(error)?,
+            });
+        }
+
+        Ok(Self { steps })
+    }
+
+    /// Runs the script against a new `App`, saving screenshots in
+    /// `screenshot_dir` and every rendered frame to `gif` if given. Stops at
+    /// the first failed expectation.
+    pub fn run(self, screenshot_dir: &Path, mut gif: Option<GifRecorder>) -> Result<(), Error> {
+        let mut display: Display = SimulatorDisplay::new(Size::new(LCD_W as u32, LCD_H as u32));
+        let mut app = App::init(&mut display, 0).unwrap();
+        let mut phone = FakePhone::new(phone::demo_playlist());
+        let mut ams_client = AmsClient::default();
+        // The outputs of the most recent input, in their Debug representation.
+        let mut outputs = Vec::new();
+        // Where the recording ends.
+        let mut last_step = (0, 0);
+
+        for step in self.steps {
+            last_step = (step.line, step.ms_since_boot);
+            let error = |message: String| Error {
+                line: step.line,
+                message,
+            };
+
+            match step.command {
+                Command::Input(input) => {
+                    let mut phone_updates = phone.tick(step.ms_since_boot);
+                    let app_outputs = app
+                        .handle_event(&mut display, step.ms_since_boot, *input)
+                        .unwrap();
+                    outputs = app_outputs
+                        .iter()
+                        .map(|output| format!("{output:?}"))
+                        .collect();
+                    for output in app_outputs {
+                        if let AppOutput::MediaControl(control) = output {
+                            phone_updates
+                                .extend(phone.handle_control(&control, step.ms_since_boot));
+                        }
+                    }
+
+                    for update in phone_updates {
+                        if let Some(media) = ams_client.handle_update(&update) {
+                            app.handle_event(
+                                &mut display,
+                                step.ms_since_boot,
+                                AppInput::AppleMedia(media),
+                            )
+                            .unwrap();
+                        }
+                    }
+                }
+                Command::PhoneConnect => {
+                    outputs.clear();
+                    for update in phone.subscribe(&phone::WATCH_SUBSCRIPTION) {
+                        if let Some(media) = ams_client.handle_update(&update) {
+                            app.handle_event(
+                                &mut display,
+                                step.ms_since_boot,
+                                AppInput::AppleMedia(media),
+                            )
+                            .unwrap();
+                        }
+                    }
+                }
+                Command::Screenshot(name) => {
+                    let path = screenshot_dir.join(format!("{name}.png"));
+                    display
+                        .to_rgb_output_image(&OutputSettings::default())
+                        .save_png(&path)
+                        .map_err(|e| error(format!("failed to save {}: {e}", path.display())))?;
+                }
+                Command::Expect(expected) => {
+                    if !outputs.contains(&expected) {
+                        return Err(error(format!("expected {expected}, found {outputs:?}")));
+                    }
+                }
+                Command::ExpectNone => {
+                    if !outputs.is_empty() {
+                        return Err(error(format!("expected no outputs, found {outputs:?}")));
+                    }
+                }
+            }
+
+            if let Some(gif) = gif.as_mut() {
+                gif.frame(&display, step.ms_since_boot).map_err(error)?;
+            }
+        }
+
+        match gif {
+            Some(gif) => gif.finish(last_step.1).map_err(|message| Error {
+                line: last_step.0,
+                message,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reads and runs a script, saving screenshots next to it unless
+/// `screenshot_dir` is given.
+pub fn run_file(
+    path: &Path,
+    screenshot_dir: Option<PathBuf>,
+    gif: Option<GifRecorder>,
+) -> Result<(), String> {
+    let source = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+    let screenshot_dir =
+        screenshot_dir.unwrap_or_else(|| path.parent().unwrap_or(Path::new(".")).to_path_buf());
+
+    Script::parse(&source)
+        .and_then(|script| script.run(&screenshot_dir, gif))
+        .map_err(|e| format!("{}: {e}", path.display()))
+}
+
+fn parse_command(command: &str) -> Result<Command, String> {
+    let (name, args) = command
+        .split_once(char::is_whitespace)
+        .map(|(name, args)| (name, args.trim()))
+        .unwrap_or((command, ""));
+    let words = args.split_whitespace().collect::<Vec<_>>();
+
+    let input = match (name, words.as_slice()) {
+        ("time", [time]) => AppInput::Time(options::parse_time(time)?),
+        ("battery", [voltage, charging]) => AppInput::Battery(BatteryData {
+            voltage: parse(voltage)?,
+            charging: match *charging {
+                "charging" => true,
+                "discharging" => false,
+                other => return Err(format!("expected charging or discharging, found {other:?}")),
+            },
+        }),
+        ("media", _) => {
+            let fields = args.split('|').map(str::trim).collect::<Vec<_>>();
+            let [artist, album, title] = fields.as_slice() else {
+                return Err(format!("expected artist | album | title, found {args:?}"));
+            };
+            let string = |s: &str| {
+                ArrayString::from(s).map_err(|_| format!("{s:?} is too long for media data"))
+            };
+            AppInput::AppleMedia(AppleMediaServiceData {
+                artist: string(artist)?,
+                album: string(album)?,
+                title: string(title)?,
+            })
+        }
+        ("touch", [x, y, rest @ ..]) if rest.len() <= 2 => AppInput::Touch(Touch {
+            x: parse(x)?,
+            y: parse(y)?,
+            gesture: match rest.first() {
+                Some(gesture) => parse_gesture(gesture)?,
+                None => Gesture::SingleClick,
+            },
+            event_type: match rest.get(1) {
+                Some(event_type) => parse_touch_type(event_type)?,
+                None => TouchType::Down,
+            },
+        }),
+        ("button", []) => AppInput::ButtonPressed,
+        ("phone-connect", []) => return Ok(Command::PhoneConnect),
+        ("tick", []) => AppInput::Tick,
+        ("screenshot", [name]) => return Ok(Command::Screenshot(name.to_string())),
+        ("expect", [_, ..]) => return Ok(Command::Expect(args.to_string())),
+        ("expect-none", []) => return Ok(Command::ExpectNone),
+        _ => return Err(format!("unknown command {command:?}")),
+    };
+
+    Ok(Command::Input(Box::new(input)))
+}
+
+fn parse_gesture(s: &str) -> Result<Gesture, String> {
+    match s {
+        "slide-down" => Ok(Gesture::SlideDown),
+        "slide-up" => Ok(Gesture::SlideUp),
+        "slide-left" => Ok(Gesture::SlideLeft),
+        "slide-right" => Ok(Gesture::SlideRight),
+        "single-click" => Ok(Gesture::SingleClick),
+        "double-click" => Ok(Gesture::DoubleClick),
+        "long-press" => Ok(Gesture::LongPress),
+        "none" => Ok(Gesture::None),
+        other => Err(format!("unknown gesture {other:?}")),
+    }
+}
+
+fn parse_touch_type(s: &str) -> Result<TouchType, String> {
+    match s {
+        "down" => Ok(TouchType::Down),
+        "contact" => Ok(TouchType::Contact),
+        "up" => Ok(TouchType::Up),
+        other => Err(format!("unknown touch event type {other:?}")),
+    }
+}
+
+fn parse<T: FromStr>(s: &str) -> Result<T, String> {
+    s.parse()
+        .map_err(|_| format!("expected a {}, found {s:?}", std::any::type_name::<T>()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every script in `sim/scripts` is an end-to-end test.
+    #[test]
+    fn scripts() {
+        let screenshot_dir = std::env::temp_dir().join("mesozoic-script-screenshots");
+        std::fs::create_dir_all(&screenshot_dir).unwrap();
+
+        let scripts_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("scripts");
+        let mut ran = 0;
+        for entry in std::fs::read_dir(scripts_dir).unwrap() {
+            let path = entry.unwrap().path();
+            if let Err(e) = run_file(&path, Some(screenshot_dir.clone()), None) {
+                panic!("{e}");
+            }
+            ran += 1;
+        }
+        assert!(ran > 0, "no scripts found");
+    }
+
+    #[test]
+    fn failed_expectation_reports_line() {
+        let script = Script::parse(
+            "# comment\n\
+             0 tick\n\
+             +10 expect MediaControl(NextTrack)\n",
+        )
+        .unwrap();
+
+        let error = script.run(Path::new("."), None).unwrap_err();
+        assert_eq!(3, error.line);
+    }
+}
@@ -0,0 +1,136 @@
+use std::{fs::File, io::Write, path::Path};
+
+use mesozoic_app::{
+    interface::{AppInput, AppOutputs},
+    recording::{encode_init, encode_input},
+    render_cost::{CostCounter, RenderCost},
+    App,
+};
+
+use crate::{clock::VirtualClock, Display};
+
+/// Passes inputs to the app with the time from the virtual clock, recording
+/// them first if requested.
+pub struct Session {
+    app: App,
+    clock: VirtualClock,
+    /// The file isn't buffered, so the recording is complete even if the
+    /// app panics.
+    recording: Option<File>,
+    /// What the most recent input cost to draw on the watch.
+    render_cost: RenderCost,
+}
+
+impl Session {
+    pub fn start(display: &mut Display, clock: VirtualClock, record_to: Option<&Path>) -> Self {
+        let ms_since_boot = clock.ms_since_boot();
+
+        let mut recording = record_to.map(|path| {
+            File::create(path)
+                .unwrap_or_else(|e| panic!("failed to create {}: {e}", path.display()))
+        });
+        if let Some(recording) = recording.as_mut() {
+            recording.write_all(&encode_init(ms_since_boot)).unwrap();
+        }
+
+        let mut counter = CostCounter::new(display);
+        let app = App::init(&mut counter, ms_since_boot).unwrap();
+
+        Self {
+            app,
+            clock,
+            recording,
+            render_cost: counter.cost(),
+        }
+    }
+
+    pub fn app(&self) -> &App {
+        &self.app
+    }
+
+    pub fn clock(&self) -> &VirtualClock {
+        &self.clock
+    }
+
+    pub fn clock_mut(&mut self) -> &mut VirtualClock {
+        &mut self.clock
+    }
+
+    pub fn render_cost(&self) -> RenderCost {
+        self.render_cost
+    }
+
+    pub fn ms_since_boot(&self) -> u64 {
+        self.clock.ms_since_boot()
+    }
+
+    pub fn handle_event(&mut self, display: &mut Display, input: AppInput) -> AppOutputs {
+        let ms_since_boot = self.ms_since_boot();
+
+        if let Some(recording) = self.recording.as_mut() {
+            recording
+                .write_all(&encode_input(ms_since_boot, &input))
+                .unwrap();
+        }
+
+        let mut counter = CostCounter::new(display);
+        let outputs = self
+            .app
+            .handle_event(&mut counter, ms_since_boot, input)
+            .unwrap();
+        self.render_cost = counter.cost();
+        // The virtual clock doesn't move while drawing, so the frame takes as
+        // long as sending it to the watch's display would.
+        self.app
+            .frame_sent(ms_since_boot + u64::from(self.render_cost.transfer_us() / 1000));
+
+        outputs
+    }
+}
+
+/// Summarises render costs once a second, as printing every frame's would
+/// scroll by too fast to read.
+pub struct RenderCostReport {
+    started_ms: u64,
+    frames: u32,
+    total_transfer_us: u64,
+    worst: RenderCost,
+}
+
+impl RenderCostReport {
+    pub fn new(real_ms: u64) -> Self {
+        Self {
+            started_ms: real_ms,
+            frames: 0,
+            total_transfer_us: 0,
+            worst: RenderCost::default(),
+        }
+    }
+
+    /// Returns the summary when a second has passed since the last one.
+    pub fn record(&mut self, cost: RenderCost, real_ms: u64) -> Option<String> {
+        self.frames += 1;
+        self.total_transfer_us += u64::from(cost.transfer_us());
+        if cost.transfer_us() > self.worst.transfer_us() {
+            self.worst = cost;
+        }
+
+        if real_ms.saturating_sub(self.started_ms) < 1000 {
+            return None;
+        }
+
+        let summary = format!(
+            "render cost: {} frames, average {:.1}ms, worst {:.1}ms for {} pixels in {} address \
+             windows ({} bytes)",
+            self.frames,
+            self.total_transfer_us as f32 / self.frames as f32 / 1000.,
+            self.worst.transfer_us() as f32 / 1000.,
+            self.worst.pixels,
+            self.worst.address_windows,
+            self.worst.spi_bytes(),
+        );
+        *self = Self::new(real_ms);
+
+        Some(summary)
+    }
+}
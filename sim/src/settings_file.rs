@@ -0,0 +1,49 @@
+//! Settings kept in a file between runs, as the watch keeps them in flash.
+
+use std::{io::ErrorKind, path::Path};
+
+use mesozoic_app::settings::Settings;
+
+/// Reads settings saved by an earlier run. A missing file means nothing has
+/// been saved yet.
+pub fn load(path: &Path) -> Result<Option<Settings>, String> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("failed to read {}: {e}", path.display())),
+    };
+
+    Settings::decode(&bytes)
+        .map(Some)
+        .map_err(|e| format!("invalid settings in {}: {e:?}", path.display()))
+}
+
+pub fn save(path: &Path, settings: &Settings) -> Result<(), String> {
+    std::fs::write(path, settings.encode())
+        .map_err(|e| format!("failed to write {}: {e}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use mesozoic_app::settings::{Brightness, TimeFormat};
+
+    use super::*;
+
+    #[test]
+    fn saved_settings_load() {
+        let path = std::env::temp_dir().join("mesozoic-settings-test.bin");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(Ok(None), load(&path));
+
+        let settings = Settings {
+            brightness: Brightness::Low,
+            time_format: TimeFormat::TwelveHour,
+            screen_timeout_s: 60,
+        };
+        save(&path, &settings).unwrap();
+        assert_eq!(Ok(Some(settings)), load(&path));
+
+        std::fs::write(&path, [1, 2, 3]).unwrap();
+        assert!(load(&path).is_err());
+    }
+}
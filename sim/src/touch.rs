@@ -0,0 +1,87 @@
+use embedded_graphics::prelude::Point;
+use embedded_graphics_simulator::sdl2::Keycode;
+use mesozoic_app::{
+    gesture::{GestureConfig, GestureRecognizer, RecognizedGesture, SwipeDirection},
+    interface::{Touch, TouchType, LCD_H, LCD_W},
+};
+
+/// Emulates the touch screen with the mouse.
+///
+/// While a mouse button is held, the position is reported every frame, the
+/// way the touch controller reports a held contact. The reports go through
+/// the same gesture recognizer as on the watch, so drags become slides,
+/// holding still becomes a long press, and double clicks become double taps.
+pub struct MouseTouch {
+    recognizer: GestureRecognizer,
+    held_at: Option<(u8, u8)>,
+}
+
+impl MouseTouch {
+    pub fn new() -> Self {
+        Self {
+            recognizer: GestureRecognizer::new(GestureConfig::default()),
+            held_at: None,
+        }
+    }
+
+    pub fn button_down(&mut self, point: Point, ms_since_boot: u64) -> Option<Touch> {
+        let (x, y) = to_screen(point);
+        self.held_at = Some((x, y));
+
+        self.report(TouchType::Down, x, y, ms_since_boot)
+    }
+
+    pub fn moved(&mut self, point: Point, ms_since_boot: u64) -> Option<Touch> {
+        self.held_at?;
+        let (x, y) = to_screen(point);
+        self.held_at = Some((x, y));
+
+        self.report(TouchType::Contact, x, y, ms_since_boot)
+    }
+
+    pub fn button_up(&mut self, point: Point, ms_since_boot: u64) -> Option<Touch> {
+        self.held_at.take()?;
+        let (x, y) = to_screen(point);
+
+        self.report(TouchType::Up, x, y, ms_since_boot)
+    }
+
+    /// Should be called every frame, to report a held button and recognize
+    /// gestures which depend on the passage of time.
+    pub fn poll(&mut self, ms_since_boot: u64) -> Option<Touch> {
+        match self.held_at {
+            Some((x, y)) => self.report(TouchType::Contact, x, y, ms_since_boot),
+            None => self.recognizer.poll(ms_since_boot).map(Touch::from),
+        }
+    }
+
+    fn report(&mut self, event_type: TouchType, x: u8, y: u8, ms: u64) -> Option<Touch> {
+        self.recognizer
+            .handle_touch(event_type, x, y, ms)
+            .map(Touch::from)
+    }
+}
+
+/// Arrow keys are shortcuts for swipes across the middle of the screen.
+pub fn swipe_for_key(keycode: Keycode) -> Option<Touch> {
+    let direction = match keycode {
+        Keycode::Up => SwipeDirection::Up,
+        Keycode::Down => SwipeDirection::Down,
+        Keycode::Left => SwipeDirection::Left,
+        Keycode::Right => SwipeDirection::Right,
+        _ => return None,
+    };
+
+    Some(Touch::from(RecognizedGesture::Swipe {
+        direction,
+        start_x: (LCD_W / 2) as u8,
+        start_y: (LCD_H / 2) as u8,
+    }))
+}
+
+fn to_screen(point: Point) -> (u8, u8) {
+    (
+        point.x.clamp(0, LCD_W as i32 - 1) as u8,
+        point.y.clamp(0, LCD_H as i32 - 1) as u8,
+    )
+}